@@ -0,0 +1,76 @@
+use crate::decompress;
+use crate::errors::*;
+use crate::limits::Limits;
+use std::path::Path;
+use tar::{Archive, EntryType};
+
+/// Read a single regular file from a (compressed) tar archive, like a package
+pub fn extract_file(bytes: &[u8], path: &str, limits: &Limits) -> Result<Vec<u8>> {
+    let compression = decompress::detect_compression(bytes);
+    let tar =
+        decompress::stream(compression, bytes).context("Failed to open compressed archive")?;
+
+    let mut archive = Archive::new(limits.decompressed(tar));
+
+    for (i, entry) in archive.entries()?.enumerate() {
+        limits.check_tar_entries(i + 1)?;
+        let entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        match entry.header().path() {
+            Ok(p) if p == Path::new(path) => (),
+            _ => continue,
+        }
+
+        let size = entry.header().size()?;
+        return limits
+            .read_entry(size, entry)
+            .with_context(|| anyhow!("Failed to read {} from archive", path));
+    }
+
+    bail!("Archive does not contain {}", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_missing_file() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let r = extract_file(bytes, ".INSTALL", &Limits::default());
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn extract_decompressed_size_limit() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let limits = Limits {
+            max_decompressed_size: 512,
+            ..Default::default()
+        };
+        assert!(extract_file(bytes, ".PKGINFO", &limits).is_err());
+    }
+
+    #[test]
+    fn extract_entry_size_limit() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let limits = Limits {
+            max_entry_size: 16,
+            ..Default::default()
+        };
+        assert!(extract_file(bytes, ".PKGINFO", &limits).is_err());
+    }
+
+    #[test]
+    fn extract_tar_entries_limit() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let limits = Limits {
+            max_tar_entries: 2,
+            ..Default::default()
+        };
+        assert!(extract_file(bytes, ".PKGINFO", &limits).is_err());
+    }
+}
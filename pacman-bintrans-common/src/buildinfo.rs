@@ -0,0 +1,183 @@
+use crate::archive;
+use crate::errors::*;
+use crate::keyvalue::{self, set_number_once, set_once};
+use crate::limits::Limits;
+use crate::pkginfo::PkgInfo;
+use serde::Serialize;
+
+/// The parsed content of a `.BUILDINFO` file, see BUILDINFO(5)
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub format: u64,
+    pub pkgname: String,
+    pub pkgbase: String,
+    pub pkgver: String,
+    pub pkgarch: String,
+    pub pkgbuild_sha256sum: String,
+    pub packager: Option<String>,
+    pub builddate: Option<u64>,
+    pub builddir: Option<String>,
+    pub startdir: Option<String>,
+    pub buildtool: Option<String>,
+    pub buildtoolver: Option<String>,
+    pub buildenv: Vec<String>,
+    pub options: Vec<String>,
+    pub installed: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn parse(content: &str) -> Result<BuildInfo> {
+        let mut format = None;
+        let mut pkgname = None;
+        let mut pkgbase = None;
+        let mut pkgver = None;
+        let mut pkgarch = None;
+        let mut pkgbuild_sha256sum = None;
+
+        let mut info = BuildInfo::default();
+
+        for line in keyvalue::lines(content) {
+            let (num, key, value) = line?;
+
+            match key {
+                "format" => set_number_once(&mut format, key, value, num)?,
+                "pkgname" => set_once(&mut pkgname, key, value, num)?,
+                "pkgbase" => set_once(&mut pkgbase, key, value, num)?,
+                "pkgver" => set_once(&mut pkgver, key, value, num)?,
+                "pkgarch" => set_once(&mut pkgarch, key, value, num)?,
+                "pkgbuild_sha256sum" => set_once(&mut pkgbuild_sha256sum, key, value, num)?,
+                "packager" => set_once(&mut info.packager, key, value, num)?,
+                "builddate" => set_number_once(&mut info.builddate, key, value, num)?,
+                "builddir" => set_once(&mut info.builddir, key, value, num)?,
+                "startdir" => set_once(&mut info.startdir, key, value, num)?,
+                "buildtool" => set_once(&mut info.buildtool, key, value, num)?,
+                "buildtoolver" => set_once(&mut info.buildtoolver, key, value, num)?,
+                "buildenv" => keyvalue::push(&mut info.buildenv, value),
+                "options" => keyvalue::push(&mut info.options, value),
+                "installed" => keyvalue::push(&mut info.installed, value),
+                _ => debug!(
+                    "Line {}: ignoring unknown key in .BUILDINFO: {:?}",
+                    num, key
+                ),
+            }
+        }
+
+        info.format = format.context("Missing format field in .BUILDINFO")?;
+        info.pkgname = pkgname.context("Missing pkgname field in .BUILDINFO")?;
+        info.pkgbase = pkgbase.context("Missing pkgbase field in .BUILDINFO")?;
+        info.pkgver = pkgver.context("Missing pkgver field in .BUILDINFO")?;
+        info.pkgarch = pkgarch.context("Missing pkgarch field in .BUILDINFO")?;
+        info.pkgbuild_sha256sum =
+            pkgbuild_sha256sum.context("Missing pkgbuild_sha256sum field in .BUILDINFO")?;
+
+        Ok(info)
+    }
+
+    /// Extract the raw `.BUILDINFO` of a (compressed) package
+    pub fn extract_from_pkg(pkg: &[u8], limits: &Limits) -> Result<Vec<u8>> {
+        info!("Extracting .BUILDINFO from package...");
+        archive::extract_file(pkg, ".BUILDINFO", limits)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BuildInfo> {
+        let content = std::str::from_utf8(bytes).context(".BUILDINFO is not valid utf-8")?;
+        let buildinfo = BuildInfo::parse(content)?;
+        debug!("Parsed buildinfo: {:?}", buildinfo);
+        Ok(buildinfo)
+    }
+
+    /// Ensure the .BUILDINFO describes the same package as the .PKGINFO next to it
+    pub fn ensure_matches(&self, pkginfo: &PkgInfo) -> Result<()> {
+        if self.pkgname != pkginfo.pkgname {
+            bail!(
+                "Package name in .BUILDINFO doesn't match .PKGINFO: {:?} != {:?}",
+                self.pkgname,
+                pkginfo.pkgname
+            );
+        }
+        if self.pkgbase != pkginfo.pkgbase() {
+            bail!(
+                "Package base in .BUILDINFO doesn't match .PKGINFO: {:?} != {:?}",
+                self.pkgbase,
+                pkginfo.pkgbase()
+            );
+        }
+        if self.pkgver != pkginfo.pkgver {
+            bail!(
+                "Package version in .BUILDINFO doesn't match .PKGINFO: {:?} != {:?}",
+                self.pkgver,
+                pkginfo.pkgver
+            );
+        }
+        if self.pkgarch != pkginfo.arch {
+            bail!(
+                "Package architecture in .BUILDINFO doesn't match .PKGINFO: {:?} != {:?}",
+                self.pkgarch,
+                pkginfo.arch
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_buildinfo_from_pkg() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let buildinfo = BuildInfo::extract_from_pkg(bytes, &Limits::default()).unwrap();
+        let buildinfo = BuildInfo::from_bytes(&buildinfo).unwrap();
+
+        assert_eq!(buildinfo.format, 2);
+        assert_eq!(buildinfo.pkgname, "rebuilderd");
+        assert_eq!(buildinfo.pkgbase, "rebuilderd");
+        assert_eq!(buildinfo.pkgver, "0.18.1-1");
+        assert_eq!(buildinfo.pkgarch, "x86_64");
+        assert_eq!(
+            buildinfo.pkgbuild_sha256sum,
+            "60d713a6f7495cbda71eff4be3c47ca2a28c4654645e3b400f73a5fd8949e0b8"
+        );
+        assert_eq!(
+            buildinfo.packager.as_deref(),
+            Some("kpcyrd <kpcyrd@archlinux.org>")
+        );
+        assert_eq!(buildinfo.builddate, Some(1639741192));
+        assert_eq!(buildinfo.builddir.as_deref(), Some("/build"));
+        assert_eq!(buildinfo.startdir.as_deref(), Some("/startdir"));
+        assert_eq!(buildinfo.buildtool.as_deref(), Some("makepkg"));
+        assert_eq!(buildinfo.buildtoolver.as_deref(), Some("6.0.1"));
+        assert_eq!(
+            buildinfo.buildenv,
+            &["!distcc", "color", "!ccache", "check", "!sign"]
+        );
+        assert_eq!(buildinfo.options.len(), 8);
+        assert_eq!(buildinfo.installed[0], "acl-2.3.1-1-x86_64");
+
+        let pkginfo = PkgInfo::from_pkg(bytes, &Limits::default()).unwrap();
+        buildinfo.ensure_matches(&pkginfo).unwrap();
+    }
+
+    #[test]
+    fn buildinfo_doesnt_match_pkginfo() {
+        let buildinfo = BuildInfo::parse(
+            "format = 2
+pkgname = foo
+pkgbase = foo
+pkgver = 1.0-1
+pkgarch = x86_64
+pkgbuild_sha256sum = 60d713a6f7495cbda71eff4be3c47ca2a28c4654645e3b400f73a5fd8949e0b8
+",
+        )
+        .unwrap();
+        let pkginfo = PkgInfo::parse("pkgname = foo\npkgver = 1.0-2\narch = x86_64\n").unwrap();
+        assert!(buildinfo.ensure_matches(&pkginfo).is_err());
+    }
+
+    #[test]
+    fn parse_buildinfo_error_line_number() {
+        let err = BuildInfo::parse("format = 2\nformat = 2\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"), "{:#}", err);
+    }
+}
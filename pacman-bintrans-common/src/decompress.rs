@@ -1,10 +1,17 @@
 use crate::errors::*;
 use bzip2::read::BzDecoder;
 use flate2::read::GzDecoder;
-use std::io::Read;
+use serde::Serialize;
+use std::io::{self, Cursor, Read};
 use xz::read::XzDecoder;
 
-#[derive(Debug, PartialEq, Eq)]
+mod lrzip;
+mod lzip;
+mod lzop;
+mod lzw;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CompressedWith {
     // .gz
     Gzip,
@@ -14,21 +21,106 @@ pub enum CompressedWith {
     Xz,
     // .zstd
     Zstd,
+    // .lz4
+    Lz4,
+    // .lz
+    Lzip,
+    // .lrz
+    Lrzip,
+    // .lzo
+    Lzo,
+    // .Z
+    Compress,
+    // .tar
+    Uncompressed,
     Unknown,
 }
 
+/// Number of bytes that are needed to detect every supported format, the
+/// magic of an uncompressed tar file is located at offset 257
+pub const MAGIC_LEN: usize = 262;
+
+const MAGIC_NUMBERS: &[(&[u8], CompressedWith)] = &[
+    (b"\x1f\x8b", CompressedWith::Gzip),
+    (b"BZh", CompressedWith::Bzip2),
+    (b"\xfd7zXZ\x00", CompressedWith::Xz),
+    (b"\x28\xb5\x2f\xfd", CompressedWith::Zstd),
+    (b"\x04\x22\x4d\x18", CompressedWith::Lz4),
+    (b"LZIP", CompressedWith::Lzip),
+    (b"LRZI", CompressedWith::Lrzip),
+    (b"\x89LZO\x00\x0d\x0a\x1a\x0a", CompressedWith::Lzo),
+    (b"\x1f\x9d", CompressedWith::Compress),
+];
+
+const EXTENSIONS: &[(&str, CompressedWith)] = &[
+    (".gz", CompressedWith::Gzip),
+    (".bz2", CompressedWith::Bzip2),
+    (".xz", CompressedWith::Xz),
+    (".zst", CompressedWith::Zstd),
+    (".lz4", CompressedWith::Lz4),
+    (".lz", CompressedWith::Lzip),
+    (".lrz", CompressedWith::Lrzip),
+    (".lzo", CompressedWith::Lzo),
+    (".Z", CompressedWith::Compress),
+    (".tar", CompressedWith::Uncompressed),
+];
+
+/// Detect the compression format from the first bytes of the data
 pub fn detect_compression(bytes: &[u8]) -> CompressedWith {
-    let mime = tree_magic_mini::from_u8(bytes);
-    debug!("Detected mimetype for possibly compressed data: {:?}", mime);
+    for (magic, comp) in MAGIC_NUMBERS {
+        if bytes.starts_with(magic) {
+            debug!("Detected compression from magic bytes: {:?}", comp);
+            return *comp;
+        }
+    }
 
-    match mime {
-        "application/gzip" => CompressedWith::Gzip,
-        "application/x-bzip" => CompressedWith::Bzip2,
-        "application/x-bzip2" => CompressedWith::Bzip2,
-        "application/x-xz" => CompressedWith::Xz,
-        "application/zstd" => CompressedWith::Zstd,
-        _ => CompressedWith::Unknown,
+    // both posix ("ustar\0") and gnu ("ustar ") tar files
+    if bytes.get(257..262) == Some(b"ustar") {
+        debug!("Detected uncompressed tar archive");
+        return CompressedWith::Uncompressed;
     }
+
+    CompressedWith::Unknown
+}
+
+/// Detect the compression format from the file extension, eg. `core.db.tar.gz`
+pub fn detect_compression_from_filename(filename: &str) -> CompressedWith {
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| filename.ends_with(ext))
+        .map(|(_, comp)| *comp)
+        .unwrap_or(CompressedWith::Unknown)
+}
+
+/// Detect the compression format from the magic bytes, falling back to the filename
+pub fn detect_compression_with_filename(bytes: &[u8], filename: &str) -> CompressedWith {
+    match detect_compression(bytes) {
+        CompressedWith::Unknown => {
+            let comp = detect_compression_from_filename(filename);
+            debug!(
+                "Failed to detect compression from magic bytes, using file extension: {:?}",
+                comp
+            );
+            comp
+        }
+        comp => comp,
+    }
+}
+
+/// A reader that replays the bytes consumed for detection before the rest of the stream
+pub type PeekedReader<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+/// Read the first bytes from a reader to detect the compression format, the
+/// returned reader yields the full stream, including the bytes used for detection
+pub fn detect_compression_from_reader<R: Read>(
+    mut reader: R,
+) -> io::Result<(CompressedWith, PeekedReader<R>)> {
+    let mut head = Vec::with_capacity(MAGIC_LEN);
+    (&mut reader)
+        .take(MAGIC_LEN as u64)
+        .read_to_end(&mut head)?;
+    let comp = detect_compression(&head);
+    Ok((comp, Cursor::new(head).chain(reader)))
 }
 
 pub fn stream<'a>(comp: CompressedWith, bytes: &'a [u8]) -> Result<Box<dyn Read + 'a>> {
@@ -37,7 +129,13 @@ pub fn stream<'a>(comp: CompressedWith, bytes: &'a [u8]) -> Result<Box<dyn Read
         CompressedWith::Bzip2 => Ok(Box::new(BzDecoder::new(bytes))),
         CompressedWith::Xz => Ok(Box::new(XzDecoder::new(bytes))),
         CompressedWith::Zstd => Ok(Box::new(zstd::Decoder::new(bytes)?)),
-        CompressedWith::Unknown => Ok(Box::new(bytes)),
+        CompressedWith::Lz4 => Ok(Box::new(lz4_flex::frame::FrameDecoder::new(bytes))),
+        CompressedWith::Lzip => Ok(Box::new(lzip::LzipDecoder::new(bytes)?)),
+        CompressedWith::Lrzip => Ok(Box::new(lrzip::LrzipDecoder::new(bytes)?)),
+        CompressedWith::Lzo => Ok(Box::new(lzop::LzopDecoder::new(bytes)?)),
+        CompressedWith::Compress => Ok(Box::new(lzw::LzwDecoder::new(bytes)?)),
+        CompressedWith::Uncompressed => Ok(Box::new(bytes)),
+        CompressedWith::Unknown => bail!("Failed to detect compression format"),
     }
 }
 
@@ -53,12 +151,32 @@ mod tests {
     }
 
     #[test]
-    fn decompress_no_compression() {
+    fn decompress_unknown_compression() {
         let bytes = b"ohai";
         let comp = detect_compression(bytes);
+        assert!(stream(comp, bytes).is_err());
+    }
+
+    fn tar_bytes() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, ".PKGINFO", &b"ohai\n"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn detect_tar_no_compression() {
+        let bytes = tar_bytes();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Uncompressed);
+
         let mut buf = Vec::new();
-        stream(comp, bytes).unwrap().read_to_end(&mut buf).unwrap();
-        assert_eq!(bytes, b"ohai");
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, bytes);
     }
 
     #[test]
@@ -140,4 +258,195 @@ mod tests {
         stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
         assert_eq!(buf, b"ohai\n");
     }
+
+    #[test]
+    fn detect_lz4_compression() {
+        let bytes = BASE64.decode(b"BCJNGGRApwUAAIBvaGFpCgAAAADXYgCl").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lz4);
+    }
+
+    #[test]
+    fn decompress_lz4_compression() {
+        let bytes = BASE64.decode(b"BCJNGGRApwUAAIBvaGFpCgAAAADXYgCl").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lz4);
+
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohai\n");
+    }
+
+    #[test]
+    fn detect_lzip_compression() {
+        let bytes = BASE64
+            .decode(b"TFpJUAEQADeaCEeA55kA///9KbAAilAtPgUAAAAAAAAAKQAAAAAAAAA=")
+            .unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lzip);
+    }
+
+    #[test]
+    fn decompress_lzip_compression() {
+        let bytes = BASE64
+            .decode(b"TFpJUAEQADeaCEeA55kA///9KbAAilAtPgUAAAAAAAAAKQAAAAAAAAA=")
+            .unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lzip);
+
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohai\n");
+    }
+
+    #[test]
+    fn decompress_lzip_multiple_members() {
+        let bytes = BASE64.decode(b"TFpJUAEQADeaCEeA55kA///9KbAAilAtPgUAAAAAAAAAKQAAAAAAAABMWklQARAANZ0JNVwrMXJqG+17//81vAAALqvR7wgAAAAAAAAALQAAAAAAAAA=").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lzip);
+
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohai\nkthxbye\n");
+    }
+
+    #[test]
+    fn decompress_lzip_bad_crc() {
+        let mut bytes = BASE64
+            .decode(b"TFpJUAEQADeaCEeA55kA///9KbAAilAtPgUAAAAAAAAAKQAAAAAAAAA=")
+            .unwrap();
+        let idx = bytes.len() - 20;
+        bytes[idx] ^= 0xff;
+
+        let mut buf = Vec::new();
+        let r = stream(CompressedWith::Lzip, &bytes)
+            .unwrap()
+            .read_to_end(&mut buf);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn detect_lrzip_compression() {
+        let bytes = BASE64.decode(b"TFJaSQAGDQAAAAAAAAAAAAAAAAAAAAAAAgEAAAMAAAAADgADAAAAACcAAxIAEgAAAAAEAAEIAAQAAAEAAAAA8DiUwQMFAAUAAABvaGFpCg==").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lrzip);
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohaiohaiohai\n");
+    }
+
+    #[test]
+    fn detect_lzo_compression() {
+        let bytes = BASE64.decode(b"iUxaTwANChoKEEAgoAlAAQUDAAADAACBpAAAAAAAAAAAACrDAosAAAANAAAACyTjBO4UwAK/FW9oYWntAAoRAAAAAAAA").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lzo);
+    }
+
+    #[test]
+    fn decompress_lzo_compression() {
+        let bytes = BASE64.decode(b"iUxaTwANChoKEEAgoAlAAQUDAAADAACBpAAAAAAAAAAAACrDAosAAAANAAAACyTjBO4UwAK/FW9oYWntAAoRAAAAAAAA").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Lzo);
+
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohaiohaiohai\n");
+    }
+
+    #[test]
+    fn detect_compress_compression() {
+        let bytes = BASE64.decode(b"H52Qb9CESaMA").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Compress);
+    }
+
+    #[test]
+    fn decompress_compress_compression() {
+        let bytes = BASE64.decode(b"H52Qb9CESaMA").unwrap();
+        let comp = detect_compression(&bytes);
+        assert_eq!(comp, CompressedWith::Compress);
+
+        let mut buf = Vec::new();
+        stream(comp, &bytes).unwrap().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"ohai\n");
+    }
+
+    #[test]
+    fn decompress_compress_table_resets() {
+        // 9 bit codes only, so the table is reset multiple times
+        let bytes = include_bytes!("../../test_data/lcg-2000.Z");
+        let comp = detect_compression(bytes);
+        assert_eq!(comp, CompressedWith::Compress);
+
+        let mut buf = Vec::new();
+        stream(comp, bytes).unwrap().read_to_end(&mut buf).unwrap();
+
+        let mut x = 1u32;
+        let expected = (0..2000)
+            .map(|_| {
+                x = x.wrapping_mul(1103515245).wrapping_add(12345);
+                b'a' + ((x >> 16) % 8) as u8
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn detect_from_first_chunk() {
+        let bytes = BASE64.decode(b"KLUv/QRYKQAAb2hhaQpnBE++").unwrap();
+        let comp = detect_compression(&bytes[..4]);
+        assert_eq!(comp, CompressedWith::Zstd);
+    }
+
+    #[test]
+    fn detect_from_reader() {
+        let bytes = BASE64
+            .decode(b"H4sIAAAAAAAAA8vPSMzkAgCKUC0+BQAAAA==")
+            .unwrap();
+        let (comp, mut reader) = detect_compression_from_reader(&bytes[..]).unwrap();
+        assert_eq!(comp, CompressedWith::Gzip);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, bytes);
+    }
+
+    #[test]
+    fn detect_tar_from_reader() {
+        let bytes = tar_bytes();
+        let (comp, _) = detect_compression_from_reader(&bytes[..]).unwrap();
+        assert_eq!(comp, CompressedWith::Uncompressed);
+    }
+
+    #[test]
+    fn detect_from_filename() {
+        for (filename, expected) in [
+            ("core.db", CompressedWith::Unknown),
+            ("core.db.tar.gz", CompressedWith::Gzip),
+            (
+                "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst",
+                CompressedWith::Zstd,
+            ),
+            ("foo-1.0-1-any.pkg.tar.xz", CompressedWith::Xz),
+            ("foo-1.0-1-any.pkg.tar.bz2", CompressedWith::Bzip2),
+            ("foo-1.0-1-any.pkg.tar.lz4", CompressedWith::Lz4),
+            ("foo-1.0-1-any.pkg.tar.lz", CompressedWith::Lzip),
+            ("foo-1.0-1-any.pkg.tar.lrz", CompressedWith::Lrzip),
+            ("foo-1.0-1-any.pkg.tar.lzo", CompressedWith::Lzo),
+            ("foo-1.0-1-any.pkg.tar.Z", CompressedWith::Compress),
+            ("foo-1.0-1-any.pkg.tar", CompressedWith::Uncompressed),
+        ] {
+            assert_eq!(detect_compression_from_filename(filename), expected);
+        }
+    }
+
+    #[test]
+    fn detect_magic_before_filename() {
+        let bytes = BASE64.decode(b"KLUv/QRYKQAAb2hhaQpnBE++").unwrap();
+        let comp = detect_compression_with_filename(&bytes, "foo.pkg.tar.xz");
+        assert_eq!(comp, CompressedWith::Zstd);
+
+        let comp = detect_compression_with_filename(b"ohai", "foo.pkg.tar.xz");
+        assert_eq!(comp, CompressedWith::Xz);
+    }
 }
@@ -0,0 +1,622 @@
+use super::lzop::lzo1x_decompress;
+use bzip2::read::BzDecoder;
+use flate2::read::ZlibDecoder;
+use lzma_rs::decompress::{Options, UnpackedSize};
+use std::io::{self, Read};
+
+const MAGIC: &[u8] = b"LRZI";
+const MAGIC_LEN: usize = 24;
+// lrzip splits its streams into blocks that fit comfortably into memory,
+// there's no reason to allocate more than this for a single one
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024 * 1024;
+
+const CTYPE_NONE: u8 = 3;
+const CTYPE_BZIP2: u8 = 4;
+const CTYPE_LZO: u8 = 5;
+const CTYPE_LZMA: u8 = 6;
+const CTYPE_GZIP: u8 = 7;
+const CTYPE_ZPAQ: u8 = 8;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid lrzip data: {}", msg),
+    )
+}
+
+fn le_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, b| n << 8 | *b as u64)
+}
+
+fn slice(bytes: &[u8], pos: u64, len: u64) -> io::Result<&[u8]> {
+    pos.checked_add(len)
+        .filter(|end| *end <= bytes.len() as u64)
+        .map(|end| &bytes[pos as usize..end as usize])
+        .ok_or_else(|| invalid("unexpected end of input"))
+}
+
+struct BlockHeader {
+    c_type: u8,
+    c_len: u64,
+    u_len: u64,
+    next_head: u64,
+}
+
+impl BlockHeader {
+    fn len(chunk_bytes: usize) -> u64 {
+        1 + 3 * chunk_bytes as u64
+    }
+
+    fn read(bytes: &[u8], pos: u64, chunk_bytes: usize) -> io::Result<BlockHeader> {
+        let header = slice(bytes, pos, BlockHeader::len(chunk_bytes))?;
+        let (c_type, rest) = header.split_at(1);
+        let mut fields = rest.chunks(chunk_bytes).map(le_uint);
+        Ok(BlockHeader {
+            c_type: c_type[0],
+            c_len: fields.next().unwrap(),
+            u_len: fields.next().unwrap(),
+            next_head: fields.next().unwrap(),
+        })
+    }
+}
+
+fn decompress_block(header: &BlockHeader, data: &[u8], props: &[u8; 5]) -> io::Result<Vec<u8>> {
+    if header.u_len > MAX_BLOCK_SIZE {
+        return Err(invalid("block size is too large"));
+    }
+
+    let mut out = Vec::new();
+    match header.c_type {
+        CTYPE_NONE => out.extend_from_slice(data),
+        CTYPE_BZIP2 => {
+            BzDecoder::new(data)
+                .take(header.u_len + 1)
+                .read_to_end(&mut out)?;
+        }
+        // despite the name these blocks are written with zlib's compress2
+        CTYPE_GZIP => {
+            ZlibDecoder::new(data)
+                .take(header.u_len + 1)
+                .read_to_end(&mut out)?;
+        }
+        CTYPE_LZO => out = lzo1x_decompress(data, header.u_len as usize)?,
+        CTYPE_LZMA => {
+            // raw lzma without a header, the properties are stored once in the magic
+            let options = Options {
+                unpacked_size: UnpackedSize::UseProvided(Some(header.u_len)),
+                ..Default::default()
+            };
+            let mut input = (&props[..]).chain(data);
+            lzma_rs::lzma_decompress_with_options(&mut input, &mut out, &options)?;
+        }
+        CTYPE_ZPAQ => return Err(invalid("zpaq compressed blocks are not supported")),
+        _ => return Err(invalid("unknown block compression")),
+    }
+
+    if out.len() as u64 != header.u_len {
+        return Err(invalid("block size mismatch"));
+    }
+    Ok(out)
+}
+
+struct Stream {
+    // offset of the current block header, relative to the start of the chunk
+    head: u64,
+    next_head: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+struct Chunk {
+    base: u64,
+    chunk_bytes: usize,
+    // bytes of headers and blocks that belong to this chunk
+    read: u64,
+    streams: [Stream; 2],
+    crc: crc32fast::Hasher,
+}
+
+impl Chunk {
+    fn open(bytes: &[u8], pos: u64) -> io::Result<(Chunk, bool)> {
+        let head = slice(bytes, pos, 2)?;
+        let chunk_bytes = head[0] as usize;
+        if !(1..=8).contains(&chunk_bytes) {
+            return Err(invalid("chunk byte width is out of range"));
+        }
+        let eof = head[1] != 0;
+        // the size of the chunk is only a hint, the output is checked against the magic
+        let _chunk_size = slice(bytes, pos + 2, chunk_bytes as u64)?;
+        let base = pos + 2 + chunk_bytes as u64;
+
+        let header_len = BlockHeader::len(chunk_bytes);
+        let open_stream = |head: u64| -> io::Result<Stream> {
+            let header = BlockHeader::read(bytes, base + head, chunk_bytes)?;
+            if header.c_type != CTYPE_NONE || header.c_len != 0 || header.u_len != 0 {
+                return Err(invalid("unexpected initial stream header"));
+            }
+            Ok(Stream {
+                head,
+                next_head: header.next_head,
+                buf: Vec::new(),
+                pos: 0,
+            })
+        };
+        let streams = [open_stream(0)?, open_stream(header_len)?];
+
+        let chunk = Chunk {
+            base,
+            chunk_bytes,
+            read: 2 * header_len,
+            streams,
+            // lrzip checksums with a crc32 that skips the initial and final inversion
+            crc: crc32fast::Hasher::new_with_initial(!0),
+        };
+        Ok((chunk, eof))
+    }
+
+    fn next_block(&mut self, bytes: &[u8], props: &[u8; 5], idx: usize) -> io::Result<()> {
+        let stream = &self.streams[idx];
+        if stream.next_head == 0 {
+            return Err(invalid("unexpected end of stream"));
+        }
+        // only ever move forward so a crafted file can't send us in circles
+        if stream.next_head <= stream.head {
+            return Err(invalid("block headers are out of order"));
+        }
+
+        let head = stream.next_head;
+        let pos = self
+            .base
+            .checked_add(head)
+            .ok_or_else(|| invalid("block offset is out of range"))?;
+        let header = BlockHeader::read(bytes, pos, self.chunk_bytes)?;
+        let header_len = BlockHeader::len(self.chunk_bytes);
+        let data = slice(bytes, pos + header_len, header.c_len)?;
+        let buf = decompress_block(&header, data, props)?;
+        self.read += header_len + header.c_len;
+
+        self.streams[idx] = Stream {
+            head,
+            next_head: header.next_head,
+            buf,
+            pos: 0,
+        };
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        bytes: &[u8],
+        props: &[u8; 5],
+        idx: usize,
+        mut n: usize,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        while n > 0 {
+            let stream = &mut self.streams[idx];
+            if stream.pos >= stream.buf.len() {
+                self.next_block(bytes, props, idx)?;
+                continue;
+            }
+            let len = n.min(stream.buf.len() - stream.pos);
+            out.extend_from_slice(&stream.buf[stream.pos..stream.pos + len]);
+            stream.pos += len;
+            n -= len;
+        }
+        Ok(())
+    }
+
+    fn read_uint(
+        &mut self,
+        bytes: &[u8],
+        props: &[u8; 5],
+        idx: usize,
+        width: usize,
+    ) -> io::Result<u64> {
+        let mut buf = Vec::with_capacity(width);
+        self.read(bytes, props, idx, width, &mut buf)?;
+        Ok(le_uint(&buf))
+    }
+}
+
+/// Decoder for lrzip 0.6 files, as written by `lrzip` from the official
+/// repositories. Every chunk is an rzip stream of literals and long distance
+/// matches that's split into two compressed streams.
+pub struct LrzipDecoder<'a> {
+    bytes: &'a [u8],
+    expected_size: u64,
+    props: [u8; 5],
+    has_md5: bool,
+    next_chunk: u64,
+    eof: bool,
+    total: u64,
+    chunk: Option<Chunk>,
+    // output of the current chunk, matches may refer to anything in here
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> LrzipDecoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> io::Result<LrzipDecoder<'a>> {
+        let magic = slice(bytes, 0, MAGIC_LEN as u64)?;
+        if &magic[..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if magic[4] != 0 || magic[5] != 6 {
+            return Err(invalid("unsupported version"));
+        }
+        if magic[22] != 0 {
+            return Err(invalid("encrypted files are not supported"));
+        }
+
+        let mut props = [0; 5];
+        props.copy_from_slice(&magic[16..21]);
+
+        Ok(LrzipDecoder {
+            bytes,
+            expected_size: le_uint(&magic[6..14]),
+            props,
+            // if the file ends with a md5 there's no crc for each chunk. The md5
+            // isn't verified, the checksum of the package is checked afterwards
+            has_md5: magic[21] == 1,
+            next_chunk: MAGIC_LEN as u64,
+            eof: false,
+            total: 0,
+            chunk: None,
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Decode the next literal or match into the output buffer, returns false
+    /// after the last chunk
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            let chunk = match &mut self.chunk {
+                Some(chunk) => chunk,
+                None => {
+                    // the size is unknown if lrzip was writing to a pipe
+                    let more = if self.expected_size > 0 {
+                        self.total < self.expected_size
+                    } else {
+                        !self.eof
+                    };
+                    if !more {
+                        return Ok(false);
+                    }
+                    let (chunk, eof) = Chunk::open(self.bytes, self.next_chunk)?;
+                    self.eof = eof;
+                    self.chunk.insert(chunk)
+                }
+            };
+
+            let bytes = self.bytes;
+            let props = &self.props;
+            let head = chunk.read_uint(bytes, props, 0, 1)?;
+            let len = chunk.read_uint(bytes, props, 0, 2)? as usize;
+            let start = self.out.len();
+
+            if head != 0 {
+                let dist = chunk.read_uint(bytes, props, 0, chunk.chunk_bytes)?;
+                if dist == 0 || dist > start as u64 {
+                    return Err(invalid("match distance is out of range"));
+                }
+                // byte-by-byte since the source may overlap with the bytes we're writing
+                let dist = dist as usize;
+                for _ in 0..len {
+                    let b = self.out[self.out.len() - dist];
+                    self.out.push(b);
+                }
+            } else if len > 0 {
+                chunk.read(bytes, props, 1, len, &mut self.out)?;
+            } else {
+                if !self.has_md5 {
+                    let expected = chunk.read_uint(bytes, props, 0, 4)? as u32;
+                    if !chunk.crc.clone().finalize() != expected {
+                        return Err(invalid("crc32 mismatch"));
+                    }
+                }
+
+                self.next_chunk = chunk
+                    .base
+                    .checked_add(chunk.read)
+                    .ok_or_else(|| invalid("chunk offset is out of range"))?;
+                self.total += start as u64;
+                if self.expected_size > 0 && self.total > self.expected_size {
+                    return Err(invalid("data size mismatch"));
+                }
+                self.chunk = None;
+                self.out.clear();
+                self.pos = 0;
+                continue;
+            }
+
+            chunk.crc.update(&self.out[start..]);
+            if self.out.len() > start {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl<'a> Read for LrzipDecoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.out.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const CHUNK_BYTES: usize = 2;
+    const DATA: &[u8] = b"ohaiohaiohai\n";
+
+    // crc32 without the initial and final inversion, written out to not
+    // depend on the trick used by the decoder
+    fn raw_crc32(bytes: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for b in bytes {
+            crc ^= *b as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        crc
+    }
+
+    fn uint(n: u64, width: usize) -> Vec<u8> {
+        n.to_le_bytes()[..width].to_vec()
+    }
+
+    fn header(c_type: u8, c_len: u64, u_len: u64, next_head: u64) -> Vec<u8> {
+        let mut buf = vec![c_type];
+        buf.extend(uint(c_len, CHUNK_BYTES));
+        buf.extend(uint(u_len, CHUNK_BYTES));
+        buf.extend(uint(next_head, CHUNK_BYTES));
+        buf
+    }
+
+    struct Block {
+        stream: usize,
+        c_type: u8,
+        data: Vec<u8>,
+        u_len: u64,
+    }
+
+    impl Block {
+        fn raw(stream: usize, data: &[u8]) -> Block {
+            Block {
+                stream,
+                c_type: CTYPE_NONE,
+                data: data.to_vec(),
+                u_len: data.len() as u64,
+            }
+        }
+    }
+
+    fn chunk(eof: bool, blocks: &[Block]) -> Vec<u8> {
+        let header_len = BlockHeader::len(CHUNK_BYTES);
+        let mut offsets = Vec::new();
+        let mut pos = 2 * header_len;
+        for block in blocks {
+            offsets.push(pos);
+            pos += header_len + block.data.len() as u64;
+        }
+        let next_head = |stream: usize, after: usize| {
+            (after..blocks.len())
+                .find(|i| blocks[*i].stream == stream)
+                .map(|i| offsets[i])
+                .unwrap_or(0)
+        };
+
+        let mut buf = vec![CHUNK_BYTES as u8, eof as u8];
+        buf.extend(uint(0, CHUNK_BYTES));
+        buf.extend(header(CTYPE_NONE, 0, 0, next_head(0, 0)));
+        buf.extend(header(CTYPE_NONE, 0, 0, next_head(1, 0)));
+        for (i, block) in blocks.iter().enumerate() {
+            let next = next_head(block.stream, i + 1);
+            buf.extend(header(
+                block.c_type,
+                block.data.len() as u64,
+                block.u_len,
+                next,
+            ));
+            buf.extend(&block.data);
+        }
+        buf
+    }
+
+    fn magic(expected_size: u64, props: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; MAGIC_LEN];
+        buf[..4].copy_from_slice(MAGIC);
+        buf[5] = 6;
+        buf[6..14].copy_from_slice(&expected_size.to_le_bytes());
+        buf[16..16 + props.len()].copy_from_slice(props);
+        buf
+    }
+
+    // a literal of 4 bytes, a match repeating them twice and a literal newline
+    fn ops(output: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut ops = vec![0, 4, 0];
+        ops.extend([1, 8, 0]);
+        ops.extend(uint(4, CHUNK_BYTES));
+        ops.extend([0, 1, 0]);
+        ops.extend([0, 0, 0]);
+        ops.extend(raw_crc32(output).to_le_bytes());
+        (ops, b"ohai\n".to_vec())
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        LrzipDecoder::new(bytes)?.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn raw_crc32_matches_decoder() {
+        let mut crc = crc32fast::Hasher::new_with_initial(!0);
+        crc.update(DATA);
+        assert_eq!(!crc.finalize(), raw_crc32(DATA));
+    }
+
+    #[test]
+    fn decode_literals_and_match() {
+        let (ops, literals) = ops(DATA);
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes.extend(chunk(
+            true,
+            &[Block::raw(0, &ops), Block::raw(1, &literals)],
+        ));
+        assert_eq!(decode(&bytes).unwrap(), DATA);
+    }
+
+    #[test]
+    fn decode_stream_split_into_blocks() {
+        let (ops, literals) = ops(DATA);
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes.extend(chunk(
+            true,
+            &[
+                Block::raw(0, &ops[..5]),
+                Block::raw(1, &literals[..2]),
+                Block::raw(0, &ops[5..]),
+                Block::raw(1, &literals[2..]),
+            ],
+        ));
+        assert_eq!(decode(&bytes).unwrap(), DATA);
+    }
+
+    #[test]
+    fn decode_multiple_chunks_with_unknown_size() {
+        let (ops, literals) = ops(DATA);
+        let blocks = [Block::raw(0, &ops), Block::raw(1, &literals)];
+        let mut bytes = magic(0, &[]);
+        bytes.extend(chunk(false, &blocks));
+        bytes.extend(chunk(true, &blocks));
+        assert_eq!(decode(&bytes).unwrap(), DATA.repeat(2));
+    }
+
+    #[test]
+    fn decode_compressed_blocks() {
+        let mut lzma = Vec::new();
+        let options = lzma_rs::compress::Options {
+            unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        };
+        lzma_rs::lzma_compress_with_options(&mut &DATA[..], &mut lzma, &options).unwrap();
+        let props = lzma[..5].to_vec();
+        let lzma = lzma[5..].to_vec();
+
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+        bzip2.write_all(DATA).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+
+        let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+        zlib.write_all(DATA).unwrap();
+        let zlib = zlib.finish().unwrap();
+
+        let lzo = b"\x15ohai\xed\x00\n\x11\x00\x00".to_vec();
+
+        let mut ops = vec![0];
+        ops.extend(uint(DATA.len() as u64, 2));
+        ops.extend([0, 0, 0]);
+        ops.extend(raw_crc32(DATA).to_le_bytes());
+
+        for (c_type, data) in [
+            (CTYPE_LZMA, lzma),
+            (CTYPE_BZIP2, bzip2),
+            (CTYPE_GZIP, zlib),
+            (CTYPE_LZO, lzo),
+        ] {
+            let literals = Block {
+                stream: 1,
+                c_type,
+                data,
+                u_len: DATA.len() as u64,
+            };
+            let mut bytes = magic(DATA.len() as u64, &props);
+            bytes.extend(chunk(true, &[Block::raw(0, &ops), literals]));
+            assert_eq!(decode(&bytes).unwrap(), DATA, "c_type={}", c_type);
+        }
+    }
+
+    #[test]
+    fn decode_without_crc_if_md5_is_present() {
+        let (mut ops, literals) = ops(DATA);
+        ops.truncate(ops.len() - 4);
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes[21] = 1;
+        bytes.extend(chunk(
+            true,
+            &[Block::raw(0, &ops), Block::raw(1, &literals)],
+        ));
+        bytes.extend([0; 16]);
+        assert_eq!(decode(&bytes).unwrap(), DATA);
+    }
+
+    #[test]
+    fn reject_crc_mismatch() {
+        let (ops, literals) = ops(b"something else");
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes.extend(chunk(
+            true,
+            &[Block::raw(0, &ops), Block::raw(1, &literals)],
+        ));
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("crc32 mismatch"));
+    }
+
+    #[test]
+    fn reject_out_of_bounds_match() {
+        let (mut ops, literals) = ops(DATA);
+        ops[6] = 5;
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes.extend(chunk(
+            true,
+            &[Block::raw(0, &ops), Block::raw(1, &literals)],
+        ));
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("match distance"));
+    }
+
+    #[test]
+    fn reject_zpaq_blocks() {
+        let (ops, literals) = ops(DATA);
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes.extend(chunk(
+            true,
+            &[
+                Block::raw(0, &ops),
+                Block {
+                    stream: 1,
+                    c_type: CTYPE_ZPAQ,
+                    data: literals,
+                    u_len: 5,
+                },
+            ],
+        ));
+        let err = decode(&bytes).unwrap_err();
+        assert!(err.to_string().contains("zpaq"));
+    }
+
+    #[test]
+    fn reject_encrypted() {
+        let mut bytes = magic(DATA.len() as u64, &[]);
+        bytes[22] = 1;
+        assert!(LrzipDecoder::new(&bytes).is_err());
+    }
+}
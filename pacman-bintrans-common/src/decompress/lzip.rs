@@ -0,0 +1,180 @@
+use lzma_rs::decompress::Stream;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8] = b"LZIP";
+const HEADER_SIZE: usize = 6;
+const TRAILER_SIZE: usize = 20;
+// feed the lzma decoder in small steps so a single call can't expand into a huge buffer
+const INPUT_CHUNK_SIZE: usize = 4096;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid lzip data: {}", msg),
+    )
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Split a (possibly multi-member) lzip file into its members, using the
+/// member size that is recorded in each trailer
+fn split_members(mut bytes: &[u8]) -> io::Result<VecDeque<&[u8]>> {
+    let mut members = VecDeque::new();
+    while !bytes.is_empty() {
+        if bytes.len() < HEADER_SIZE + TRAILER_SIZE {
+            return Err(invalid("truncated member"));
+        }
+        let member_size = le_u64(&bytes[bytes.len() - 8..]);
+        if member_size < (HEADER_SIZE + TRAILER_SIZE) as u64 || member_size > bytes.len() as u64 {
+            return Err(invalid("member size in trailer is out of range"));
+        }
+        let (rest, member) = bytes.split_at(bytes.len() - member_size as usize);
+        members.push_front(member);
+        bytes = rest;
+    }
+    Ok(members)
+}
+
+struct Member<'a> {
+    stream: Stream<Vec<u8>>,
+    input: &'a [u8],
+    trailer: &'a [u8],
+    crc: crc32fast::Hasher,
+    size: u64,
+}
+
+impl<'a> Member<'a> {
+    fn new(member: &'a [u8]) -> io::Result<Member<'a>> {
+        let (header, rest) = member.split_at(HEADER_SIZE);
+        let (input, trailer) = rest.split_at(rest.len() - TRAILER_SIZE);
+
+        if &header[..4] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if header[4] != 1 {
+            return Err(invalid("unsupported version"));
+        }
+
+        let exp = (header[5] & 0x1f) as u32;
+        if !(12..=29).contains(&exp) {
+            return Err(invalid("dictionary size is out of range"));
+        }
+        let base = 1u32 << exp;
+        let dict_size = base - (base / 16) * (header[5] >> 5) as u32;
+
+        // lzip uses a fixed set of lzma properties (lc=3, lp=0, pb=2) and
+        // always terminates with an end-of-stream marker
+        let mut lzma_header = [0xff; 13];
+        lzma_header[0] = 0x5d;
+        lzma_header[1..5].copy_from_slice(&dict_size.to_le_bytes());
+
+        let mut stream = Stream::new(Vec::new());
+        stream.write_all(&lzma_header)?;
+
+        Ok(Member {
+            stream,
+            input,
+            trailer,
+            crc: crc32fast::Hasher::new(),
+            size: 0,
+        })
+    }
+
+    fn take_output(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let output = self
+            .stream
+            .get_output_mut()
+            .ok_or_else(|| invalid("decoder is in a failed state"))?;
+        self.crc.update(output);
+        self.size += output.len() as u64;
+        out.append(output);
+        Ok(())
+    }
+
+    fn finish(self, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut crc = self.crc;
+        let output = self.stream.finish().map_err(io::Error::from)?;
+        crc.update(&output);
+        let size = self.size + output.len() as u64;
+        out.extend(output);
+
+        if crc.finalize() != le_u32(&self.trailer[0..4]) {
+            return Err(invalid("crc32 mismatch"));
+        }
+        if size != le_u64(&self.trailer[4..12]) {
+            return Err(invalid("data size mismatch"));
+        }
+        Ok(())
+    }
+}
+
+pub struct LzipDecoder<'a> {
+    members: VecDeque<&'a [u8]>,
+    current: Option<Member<'a>>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> LzipDecoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> io::Result<LzipDecoder<'a>> {
+        let members = split_members(bytes)?;
+        Ok(LzipDecoder {
+            members,
+            current: None,
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Decode the next chunk into the output buffer, returns false at the end of the last member
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut member = match self.current.take() {
+            Some(member) => member,
+            None => match self.members.pop_front() {
+                Some(member) => Member::new(member)?,
+                None => return Ok(false),
+            },
+        };
+
+        if member.input.is_empty() {
+            member.finish(&mut self.out)?;
+        } else {
+            let n = member.input.len().min(INPUT_CHUNK_SIZE);
+            let (chunk, rest) = member.input.split_at(n);
+            member.stream.write_all(chunk)?;
+            member.input = rest;
+            member.take_output(&mut self.out)?;
+            self.current = Some(member);
+        }
+
+        Ok(true)
+    }
+}
+
+impl<'a> Read for LzipDecoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,377 @@
+use std::io::{self, Read};
+
+const MAGIC: &[u8] = b"\x89LZO\x00\x0d\x0a\x1a\x0a";
+// lzop refuses to write larger blocks, so there's no reason to accept them
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+const F_ADLER32_D: u32 = 0x0000_0001;
+const F_ADLER32_C: u32 = 0x0000_0002;
+const F_H_EXTRA_FIELD: u32 = 0x0000_0040;
+const F_CRC32_D: u32 = 0x0000_0100;
+const F_CRC32_C: u32 = 0x0000_0200;
+const F_MULTIPART: u32 = 0x0000_0400;
+const F_H_FILTER: u32 = 0x0000_0800;
+const F_H_CRC32: u32 = 0x0000_1000;
+
+const M_LZO1X_1: u8 = 1;
+const M_LZO1X_1_15: u8 = 2;
+const M_LZO1X_999: u8 = 3;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid lzo data: {}", msg),
+    )
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let mut a = 1u32;
+    let mut b = 0u32;
+    // 5552 is the largest n such that 255n(n+1)/2 + (n+1)(MOD-1) fits in a u32
+    for chunk in bytes.chunks(5552) {
+        for x in chunk {
+            a += *x as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+struct Input<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Input<'a> {
+    fn new(buf: &'a [u8]) -> Input<'a> {
+        Input { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("unexpected end of input"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn be_u16(&mut self) -> io::Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn be_u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn le_u16(&mut self) -> io::Result<usize> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]) as usize)
+    }
+
+    /// Lengths that don't fit into an instruction are encoded as a run of
+    /// zero bytes (255 each) followed by a non-zero byte
+    fn zero_run(&mut self, base: usize) -> io::Result<usize> {
+        let mut n = base;
+        loop {
+            match self.u8()? {
+                0 => n += 255,
+                x => return Ok(n + x as usize),
+            }
+        }
+    }
+}
+
+fn copy_literals(input: &mut Input, out: &mut Vec<u8>, len: usize, max: usize) -> io::Result<()> {
+    if out.len() + len > max {
+        return Err(invalid("output exceeds block size"));
+    }
+    out.extend_from_slice(input.take(len)?);
+    Ok(())
+}
+
+fn copy_match(out: &mut Vec<u8>, dist: usize, len: usize, max: usize) -> io::Result<()> {
+    if dist == 0 || dist > out.len() {
+        return Err(invalid("match distance is out of range"));
+    }
+    if out.len() + len > max {
+        return Err(invalid("output exceeds block size"));
+    }
+    // byte-by-byte since the source may overlap with the bytes we're writing
+    for _ in 0..len {
+        let b = out[out.len() - dist];
+        out.push(b);
+    }
+    Ok(())
+}
+
+/// Decompress a single lzo1x block, this is a port of lzo1x_decompress_safe
+pub fn lzo1x_decompress(src: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    let mut input = Input::new(src);
+    let mut out = Vec::with_capacity(max);
+
+    // 0: expecting a literal run, 1-3: a match was followed by this many
+    // literals, 4: a literal run was just copied
+    let mut state = 0;
+
+    if let Some(first) = src.first() {
+        if *first > 17 {
+            input.u8()?;
+            let len = (*first - 17) as usize;
+            copy_literals(&mut input, &mut out, len, max)?;
+            state = if len < 4 { len } else { 4 };
+        }
+    }
+
+    loop {
+        let t = input.u8()? as usize;
+
+        let (dist, len, next) = if t < 16 {
+            match state {
+                0 => {
+                    let len = if t == 0 { input.zero_run(15)? } else { t };
+                    copy_literals(&mut input, &mut out, len + 3, max)?;
+                    state = 4;
+                    continue;
+                }
+                4 => {
+                    let dist = 1 + 0x800 + (t >> 2) + ((input.u8()? as usize) << 2);
+                    (dist, 3, t & 3)
+                }
+                _ => {
+                    let dist = 1 + (t >> 2) + ((input.u8()? as usize) << 2);
+                    (dist, 2, t & 3)
+                }
+            }
+        } else if t >= 64 {
+            let dist = 1 + ((t >> 2) & 7) + ((input.u8()? as usize) << 3);
+            (dist, (t >> 5) + 1, t & 3)
+        } else if t >= 32 {
+            let len = match t & 31 {
+                0 => input.zero_run(31)?,
+                x => x,
+            };
+            let v = input.le_u16()?;
+            (1 + (v >> 2), len + 2, v & 3)
+        } else {
+            let len = match t & 7 {
+                0 => input.zero_run(7)?,
+                x => x,
+            };
+            let v = input.le_u16()?;
+            let dist = ((t & 8) << 11) + (v >> 2);
+            if dist == 0 {
+                // end of stream marker
+                break;
+            }
+            (dist + 0x4000, len + 2, v & 3)
+        };
+
+        copy_match(&mut out, dist, len, max)?;
+        copy_literals(&mut input, &mut out, next, max)?;
+        state = next;
+    }
+
+    if !input.is_empty() {
+        return Err(invalid("trailing data after end of stream"));
+    }
+
+    Ok(out)
+}
+
+pub struct LzopDecoder<'a> {
+    input: Input<'a>,
+    flags: u32,
+    done: bool,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> LzopDecoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> io::Result<LzopDecoder<'a>> {
+        let mut input = Input::new(bytes);
+
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let header_start = input.pos;
+        let version = input.be_u16()?;
+        if version < 0x0900 {
+            return Err(invalid("unsupported version"));
+        }
+        let _lib_version = input.be_u16()?;
+        if version >= 0x0940 {
+            let version_needed = input.be_u16()?;
+            if version_needed > 0x1040 {
+                return Err(invalid("unsupported version"));
+            }
+        }
+        let method = input.u8()?;
+        if ![M_LZO1X_1, M_LZO1X_1_15, M_LZO1X_999].contains(&method) {
+            return Err(invalid("unsupported compression method"));
+        }
+        if version >= 0x0940 {
+            let _level = input.u8()?;
+        }
+        let flags = input.be_u32()?;
+        if flags & (F_H_FILTER | F_MULTIPART) != 0 {
+            return Err(invalid("filters and multipart archives are not supported"));
+        }
+        let _mode = input.be_u32()?;
+        let _mtime_low = input.be_u32()?;
+        if version >= 0x0940 {
+            let _mtime_high = input.be_u32()?;
+        }
+        let name_len = input.u8()? as usize;
+        let _name = input.take(name_len)?;
+
+        let header = &bytes[header_start..input.pos];
+        let checksum = if flags & F_H_CRC32 != 0 {
+            crc32fast::hash(header)
+        } else {
+            adler32(header)
+        };
+        if input.be_u32()? != checksum {
+            return Err(invalid("header checksum mismatch"));
+        }
+
+        if flags & F_H_EXTRA_FIELD != 0 {
+            let len = input.be_u32()? as usize;
+            input.take(len)?;
+            let _checksum = input.be_u32()?;
+        }
+
+        Ok(LzopDecoder {
+            input,
+            flags,
+            done: false,
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn verify(&self, bytes: &[u8], adler: Option<u32>, crc: Option<u32>) -> io::Result<()> {
+        if let Some(expected) = adler {
+            if adler32(bytes) != expected {
+                return Err(invalid("adler32 mismatch"));
+            }
+        }
+        if let Some(expected) = crc {
+            if crc32fast::hash(bytes) != expected {
+                return Err(invalid("crc32 mismatch"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the next block into the output buffer, returns false after the last block
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+
+        let dst_len = self.input.be_u32()? as usize;
+        if dst_len == 0 {
+            self.done = true;
+            return Ok(false);
+        }
+        if dst_len > MAX_BLOCK_SIZE {
+            return Err(invalid("block size is too large"));
+        }
+        let src_len = self.input.be_u32()? as usize;
+        if src_len == 0 || src_len > dst_len {
+            return Err(invalid("compressed block size is out of range"));
+        }
+
+        let flags = self.flags;
+        let mut read_if = |flag: u32| -> io::Result<Option<u32>> {
+            if flags & flag != 0 {
+                Ok(Some(self.input.be_u32()?))
+            } else {
+                Ok(None)
+            }
+        };
+        let d_adler = read_if(F_ADLER32_D)?;
+        let d_crc = read_if(F_CRC32_D)?;
+        let (c_adler, c_crc) = if src_len < dst_len {
+            (read_if(F_ADLER32_C)?, read_if(F_CRC32_C)?)
+        } else {
+            (None, None)
+        };
+
+        let data = self.input.take(src_len)?;
+        self.out = if src_len < dst_len {
+            self.verify(data, c_adler, c_crc)?;
+            let out = lzo1x_decompress(data, dst_len)?;
+            if out.len() != dst_len {
+                return Err(invalid("block size mismatch"));
+            }
+            out
+        } else {
+            data.to_vec()
+        };
+        self.verify(&self.out, d_adler, d_crc)?;
+
+        Ok(true)
+    }
+}
+
+impl<'a> Read for LzopDecoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adler32_known_value() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn lzo1x_literals_and_match() {
+        let src = b"\x15ohai\xed\x00\n\x11\x00\x00";
+        let out = lzo1x_decompress(src, 13).unwrap();
+        assert_eq!(out, b"ohaiohaiohai\n");
+    }
+
+    #[test]
+    fn lzo1x_reject_out_of_bounds_match() {
+        let src = b"\x15ohai\xed\x01\n\x11\x00\x00";
+        assert!(lzo1x_decompress(src, 64).is_err());
+    }
+
+    #[test]
+    fn lzo1x_reject_oversized_output() {
+        let src = b"\x15ohai\xed\x00\n\x11\x00\x00";
+        assert!(lzo1x_decompress(src, 12).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::{self, Read};
+
+const MAGIC: &[u8] = b"\x1f\x9d";
+const BLOCK_MODE: u8 = 0x80;
+const BITS_MASK: u8 = 0x1f;
+const INIT_BITS: u32 = 9;
+const MAX_BITS: u32 = 16;
+const CLEAR: usize = 256;
+// decode until at least this many bytes are buffered
+const OUTPUT_CHUNK_SIZE: usize = 8192;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid .Z data: {}", msg),
+    )
+}
+
+/// Decoder for the unix compress(1) format, this follows the behavior of ncompress
+pub struct LzwDecoder<'a> {
+    input: &'a [u8],
+    // bit position of the next code
+    bitpos: usize,
+    // codes are written in groups of 8, a group is discarded when the code size changes
+    group_start: usize,
+    block_mode: bool,
+    max_bits: u32,
+    n_bits: u32,
+    maxcode: usize,
+    maxmaxcode: usize,
+    free_ent: usize,
+    oldcode: Option<usize>,
+    finchar: u8,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    stack: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> LzwDecoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> io::Result<LzwDecoder<'a>> {
+        if bytes.len() < 3 || &bytes[..2] != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+
+        let flags = bytes[2];
+        let max_bits = (flags & BITS_MASK) as u32;
+        if !(INIT_BITS..=MAX_BITS).contains(&max_bits) {
+            return Err(invalid("unsupported maximum code size"));
+        }
+        let block_mode = flags & BLOCK_MODE != 0;
+        let maxmaxcode = 1 << max_bits;
+
+        let mut suffix = vec![0; maxmaxcode];
+        for (i, x) in suffix.iter_mut().enumerate().take(256) {
+            *x = i as u8;
+        }
+
+        Ok(LzwDecoder {
+            input: &bytes[3..],
+            bitpos: 0,
+            group_start: 0,
+            block_mode,
+            max_bits,
+            n_bits: INIT_BITS,
+            maxcode: (1 << INIT_BITS) - 1,
+            maxmaxcode,
+            free_ent: if block_mode { CLEAR + 1 } else { CLEAR },
+            oldcode: None,
+            finchar: 0,
+            prefix: vec![0; maxmaxcode],
+            suffix,
+            stack: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Skip the rest of the current group of codes
+    fn align(&mut self) {
+        let group_bits = self.n_bits as usize * 8;
+        let offset = self.bitpos - self.group_start;
+        self.bitpos = self.group_start + offset.div_ceil(group_bits) * group_bits;
+        self.group_start = self.bitpos;
+    }
+
+    fn next_code(&mut self) -> Option<usize> {
+        if self.free_ent > self.maxcode {
+            self.align();
+            self.n_bits += 1;
+            self.maxcode = if self.n_bits == self.max_bits {
+                self.maxmaxcode
+            } else {
+                (1 << self.n_bits) - 1
+            };
+        }
+
+        if self.bitpos + self.n_bits as usize > self.input.len() * 8 {
+            return None;
+        }
+
+        let mut code = 0;
+        for i in 0..self.n_bits as usize {
+            let bit = self.bitpos + i;
+            if self.input[bit / 8] & (1 << (bit % 8)) != 0 {
+                code |= 1 << i;
+            }
+        }
+        self.bitpos += self.n_bits as usize;
+
+        Some(code)
+    }
+
+    /// Decode a single code into the output buffer, returns false at the end of the input
+    fn decode_next(&mut self) -> io::Result<bool> {
+        let mut code = match self.next_code() {
+            Some(code) => code,
+            None => return Ok(false),
+        };
+
+        let oldcode = match self.oldcode {
+            Some(oldcode) => oldcode,
+            None => {
+                if code >= CLEAR {
+                    return Err(invalid("first code must be a literal"));
+                }
+                self.finchar = code as u8;
+                self.oldcode = Some(code);
+                self.out.push(self.finchar);
+                return Ok(true);
+            }
+        };
+
+        if code == CLEAR && self.block_mode {
+            // the entry that is added by the next code is never referenced
+            self.free_ent = CLEAR;
+            self.align();
+            self.n_bits = INIT_BITS;
+            self.maxcode = (1 << INIT_BITS) - 1;
+            return Ok(true);
+        }
+
+        let incode = code;
+        self.stack.clear();
+
+        if code >= self.free_ent {
+            if code > self.free_ent {
+                return Err(invalid("code is not in the table"));
+            }
+            self.stack.push(self.finchar);
+            code = oldcode;
+        }
+
+        while code >= CLEAR {
+            if self.stack.len() >= self.maxmaxcode {
+                return Err(invalid("corrupt code table"));
+            }
+            self.stack.push(self.suffix[code]);
+            code = self.prefix[code] as usize;
+        }
+        self.finchar = code as u8;
+        self.stack.push(self.finchar);
+        self.out.extend(self.stack.iter().rev());
+
+        if self.free_ent < self.maxmaxcode {
+            self.prefix[self.free_ent] = oldcode as u16;
+            self.suffix[self.free_ent] = self.finchar;
+            self.free_ent += 1;
+        }
+        self.oldcode = Some(incode);
+
+        Ok(true)
+    }
+}
+
+impl<'a> Read for LzwDecoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.out.len() {
+            self.out.clear();
+            self.pos = 0;
+            while self.out.len() < OUTPUT_CHUNK_SIZE {
+                if !self.decode_next()? {
+                    break;
+                }
+            }
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,57 @@
+//! Helpers for the `key = value` format that is used by .PKGINFO and .BUILDINFO
+
+use crate::errors::*;
+
+/// Iterate over all `key = value` lines, skipping empty lines and comments.
+/// Every item contains the 1-based line number for error reporting.
+pub(crate) fn lines(content: &str) -> impl Iterator<Item = Result<(usize, &str, &str)>> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| {
+            let num = idx + 1;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Line {}: expected `key = value`: {:?}", num, line))?;
+            let key = key.trim_end();
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            Ok((num, key, value))
+        })
+}
+
+pub(crate) fn set_once(
+    field: &mut Option<String>,
+    key: &str,
+    value: &str,
+    num: usize,
+) -> Result<()> {
+    if field.is_some() {
+        bail!("Line {}: duplicate key {:?}", num, key);
+    }
+    *field = Some(value.to_string());
+    Ok(())
+}
+
+pub(crate) fn set_number_once(
+    field: &mut Option<u64>,
+    key: &str,
+    value: &str,
+    num: usize,
+) -> Result<()> {
+    if field.is_some() {
+        bail!("Line {}: duplicate key {:?}", num, key);
+    }
+    let n = value
+        .parse()
+        .with_context(|| anyhow!("Line {}: invalid number for {:?}: {:?}", num, key, value))?;
+    *field = Some(n);
+    Ok(())
+}
+
+/// Add a value to a multi-value field, empty values are skipped
+pub(crate) fn push(list: &mut Vec<String>, value: &str) {
+    if !value.is_empty() {
+        list.push(value.to_string());
+    }
+}
@@ -1,4 +1,16 @@
+pub mod archive;
+pub mod buildinfo;
 pub mod decompress;
 pub mod errors;
 pub mod http;
+mod keyvalue;
+pub mod limits;
+pub mod merkle;
+pub mod mtree;
+pub mod pkginfo;
+pub mod proofarchive;
+pub mod snapshot;
+pub mod syncdb;
+pub mod tls;
 pub mod ui;
+pub mod version;
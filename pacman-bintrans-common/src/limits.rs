@@ -0,0 +1,168 @@
+use crate::errors::*;
+use std::io::{self, Read};
+
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 4 * 1024 * 1024 * 1024; // 4G
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024 * 1024; // 16G
+pub const DEFAULT_MAX_TAR_ENTRIES: usize = 1_000_000;
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 8 * 1024 * 1024; // 8M
+
+/// Hard limits that are enforced while processing untrusted data from a mirror
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of bytes we're going to download into memory
+    pub max_download_size: u64,
+    /// Maximum number of bytes we're going to read from a decompressor
+    pub max_decompressed_size: u64,
+    /// Maximum number of tar entries we're going to look at
+    pub max_tar_entries: usize,
+    /// Maximum size of a single metadata file we're going to read into memory, eg. `.PKGINFO`
+    pub max_entry_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_download_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_tar_entries: DEFAULT_MAX_TAR_ENTRIES,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    /// Wrap a decompressor so reading past `max_decompressed_size` fails
+    pub fn decompressed<R: Read>(&self, reader: R) -> LimitedReader<R> {
+        LimitedReader::new(reader, self.max_decompressed_size)
+    }
+
+    /// Call this with the 1-based index of every tar entry that is processed
+    pub fn check_tar_entries(&self, n: usize) -> Result<()> {
+        if n > self.max_tar_entries {
+            bail!(
+                "Exceeded limit for number of tar entries ({})",
+                self.max_tar_entries
+            );
+        }
+        Ok(())
+    }
+
+    /// Read a tar entry into memory, `size` is the size declared in its header
+    pub fn read_entry<R: Read>(&self, size: u64, reader: R) -> Result<Vec<u8>> {
+        if size > self.max_entry_size {
+            bail!(
+                "Exceeded size limit for archive entry ({} > {} bytes)",
+                size,
+                self.max_entry_size
+            );
+        }
+
+        let mut buf = Vec::new();
+        reader.take(self.max_entry_size + 1).read_to_end(&mut buf)?;
+        if buf.len() as u64 > self.max_entry_size {
+            bail!(
+                "Exceeded size limit for archive entry ({} bytes)",
+                self.max_entry_size
+            );
+        }
+        Ok(buf)
+    }
+
+    pub fn check_download_size(&self, n: u64) -> Result<()> {
+        if n > self.max_download_size {
+            bail!(
+                "Exceeded size limit for download ({} > {} bytes)",
+                n,
+                self.max_download_size
+            );
+        }
+        Ok(())
+    }
+}
+
+pub struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> LimitedReader<R> {
+        LimitedReader {
+            inner,
+            limit,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // allow reading one byte past the limit so we can tell the difference
+        // between "exactly at the limit" and "would have been more"
+        let max = if self.remaining < buf.len() as u64 {
+            self.remaining as usize + 1
+        } else {
+            buf.len()
+        };
+        let n = self.inner.read(&mut buf[..max])?;
+
+        if n as u64 > self.remaining {
+            return Err(io::Error::other(format!(
+                "Exceeded size limit for decompressed data ({} bytes)",
+                self.limit
+            )));
+        }
+
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_reader_within_limit() {
+        let mut buf = Vec::new();
+        LimitedReader::new(&b"ohai"[..], 4)
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"ohai");
+    }
+
+    #[test]
+    fn limited_reader_exceeds_limit() {
+        let mut buf = Vec::new();
+        let r = LimitedReader::new(&b"ohai"[..], 3).read_to_end(&mut buf);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn tar_entries_limit() {
+        let limits = Limits {
+            max_tar_entries: 2,
+            ..Default::default()
+        };
+        assert!(limits.check_tar_entries(2).is_ok());
+        assert!(limits.check_tar_entries(3).is_err());
+    }
+
+    #[test]
+    fn entry_size_limit() {
+        let limits = Limits {
+            max_entry_size: 4,
+            ..Default::default()
+        };
+        assert_eq!(limits.read_entry(4, &b"ohai"[..]).unwrap(), b"ohai");
+        // the size in the header is checked before reading
+        assert!(limits.read_entry(5, &b"ohai"[..]).is_err());
+        // and the header isn't trusted while reading
+        assert!(limits.read_entry(4, &b"ohai!"[..]).is_err());
+    }
+}
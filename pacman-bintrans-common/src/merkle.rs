@@ -0,0 +1,290 @@
+//! Verification of RFC 9162 merkle tree proofs, as used by transparency logs
+use crate::errors::*;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+
+pub type Hash = [u8; 32];
+
+/// The hash of a leaf with the given content
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Parse a hex encoded hash
+pub fn parse_hash(s: &str) -> Result<Hash> {
+    let bytes = hex::decode(s).with_context(|| anyhow!("Invalid hex in hash: {:?}", s))?;
+    Hash::try_from(bytes.as_slice()).map_err(|_| anyhow!("Hash has an invalid length: {:?}", s))
+}
+
+/// Compute the root hash of a tree of `size` leaves from an inclusion proof of leaf `index`
+pub fn root_from_inclusion_proof(
+    index: u64,
+    size: u64,
+    leaf: &Hash,
+    proof: &[Hash],
+) -> Result<Hash> {
+    if index >= size {
+        bail!("Leaf index {} is outside of a tree of size {}", index, size);
+    }
+
+    let mut fnode = index;
+    let mut snode = size - 1;
+    let mut r = *leaf;
+    for p in proof {
+        if snode == 0 {
+            bail!("Inclusion proof is too long");
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            r = node_hash(p, &r);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    if snode != 0 {
+        bail!("Inclusion proof is too short");
+    }
+    Ok(r)
+}
+
+/// Verify that leaf `index` is part of the tree with the given root hash
+pub fn verify_inclusion(
+    index: u64,
+    size: u64,
+    leaf: &Hash,
+    proof: &[Hash],
+    root: &Hash,
+) -> Result<()> {
+    let computed = root_from_inclusion_proof(index, size, leaf, proof)?;
+    if computed != *root {
+        bail!(
+            "Inclusion proof leads to root hash {}, expected {}",
+            hex::encode(computed),
+            hex::encode(root)
+        );
+    }
+    Ok(())
+}
+
+/// Verify that the tree of size `second` is an append-only extension of the tree of size `first`
+pub fn verify_consistency(
+    first: u64,
+    second: u64,
+    first_root: &Hash,
+    second_root: &Hash,
+    proof: &[Hash],
+) -> Result<()> {
+    if first > second {
+        bail!(
+            "Tree of size {} can't extend a tree of size {}",
+            second,
+            first
+        );
+    }
+    if first == second {
+        if !proof.is_empty() {
+            bail!("Consistency proof for trees of the same size needs to be empty");
+        }
+        if first_root != second_root {
+            bail!("Trees have the same size, but different root hashes");
+        }
+        return Ok(());
+    }
+    if first == 0 {
+        bail!("Consistency proofs from an empty tree are not supported");
+    }
+
+    let mut path = Vec::with_capacity(proof.len() + 1);
+    if first.is_power_of_two() {
+        path.push(*first_root);
+    }
+    path.extend_from_slice(proof);
+    let (start, rest) = path
+        .split_first()
+        .ok_or_else(|| anyhow!("Consistency proof is empty"))?;
+
+    let mut fnode = first - 1;
+    let mut snode = second - 1;
+    while fnode & 1 == 1 {
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    let mut fr = *start;
+    let mut sr = *start;
+    for c in rest {
+        if snode == 0 {
+            bail!("Consistency proof is too long");
+        }
+        if fnode & 1 == 1 || fnode == snode {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while fnode & 1 == 0 && fnode != 0 {
+                fnode >>= 1;
+                snode >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fnode >>= 1;
+        snode >>= 1;
+    }
+
+    if snode != 0 {
+        bail!("Consistency proof is too short");
+    }
+    if fr != *first_root || sr != *second_root {
+        bail!("Consistency proof doesn't match the root hashes");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u64) -> Vec<Hash> {
+        (0..n)
+            .map(|i| leaf_hash(format!("leaf {}", i).as_bytes()))
+            .collect()
+    }
+
+    /// The largest power of two smaller than n
+    fn split(n: usize) -> usize {
+        let mut k = 1;
+        while k * 2 < n {
+            k *= 2;
+        }
+        k
+    }
+
+    fn root(leaves: &[Hash]) -> Hash {
+        if leaves.len() == 1 {
+            return leaves[0];
+        }
+        let k = split(leaves.len());
+        node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+    }
+
+    fn inclusion_path(m: usize, leaves: &[Hash]) -> Vec<Hash> {
+        if leaves.len() == 1 {
+            return Vec::new();
+        }
+        let k = split(leaves.len());
+        if m < k {
+            let mut path = inclusion_path(m, &leaves[..k]);
+            path.push(root(&leaves[k..]));
+            path
+        } else {
+            let mut path = inclusion_path(m - k, &leaves[k..]);
+            path.push(root(&leaves[..k]));
+            path
+        }
+    }
+
+    fn subproof(m: usize, leaves: &[Hash], complete: bool) -> Vec<Hash> {
+        let n = leaves.len();
+        if m == n {
+            return if complete {
+                Vec::new()
+            } else {
+                vec![root(leaves)]
+            };
+        }
+        let k = split(n);
+        if m <= k {
+            let mut proof = subproof(m, &leaves[..k], complete);
+            proof.push(root(&leaves[k..]));
+            proof
+        } else {
+            let mut proof = subproof(m - k, &leaves[k..], false);
+            proof.push(root(&leaves[..k]));
+            proof
+        }
+    }
+
+    #[test]
+    fn test_inclusion() {
+        for n in 1..=20 {
+            let leaves = leaves(n);
+            let root = root(&leaves);
+            for m in 0..n {
+                let proof = inclusion_path(m as usize, &leaves);
+                verify_inclusion(m, n, &leaves[m as usize], &proof, &root).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_inclusion_invalid() {
+        let leaves = leaves(7);
+        let root = root(&leaves);
+        let proof = inclusion_path(3, &leaves);
+        assert!(verify_inclusion(3, 7, &leaves[4], &proof, &root).is_err());
+        assert!(verify_inclusion(4, 7, &leaves[3], &proof, &root).is_err());
+        assert!(verify_inclusion(3, 16, &leaves[3], &proof, &root).is_err());
+        assert!(verify_inclusion(3, 7, &leaves[3], &proof[1..], &root).is_err());
+        let mut long = proof.clone();
+        long.push(leaves[0]);
+        assert!(verify_inclusion(3, 7, &leaves[3], &long, &root).is_err());
+        assert!(verify_inclusion(7, 7, &leaves[3], &proof, &root).is_err());
+    }
+
+    #[test]
+    fn test_consistency() {
+        let leaves = leaves(20);
+        for n in 1..=20 {
+            let second = root(&leaves[..n]);
+            for m in 1..=n {
+                let first = root(&leaves[..m]);
+                let proof = subproof(m, &leaves[..n], true);
+                verify_consistency(m as u64, n as u64, &first, &second, &proof).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistency_invalid() {
+        let leaves = leaves(11);
+        let first = root(&leaves[..6]);
+        let second = root(&leaves);
+        let proof = subproof(6, &leaves, true);
+        verify_consistency(6, 11, &first, &second, &proof).unwrap();
+
+        // a forked tree with the same size
+        let mut forked = leaves.clone();
+        forked[2] = leaf_hash(b"evil");
+        assert!(verify_consistency(6, 11, &root(&forked[..6]), &second, &proof).is_err());
+        assert!(verify_consistency(6, 11, &first, &root(&forked), &proof).is_err());
+        assert!(verify_consistency(6, 32, &first, &second, &proof).is_err());
+        assert!(verify_consistency(6, 11, &first, &second, &proof[1..]).is_err());
+        assert!(verify_consistency(11, 6, &second, &first, &proof).is_err());
+        assert!(verify_consistency(11, 11, &second, &first, &[]).is_err());
+        verify_consistency(11, 11, &second, &second, &[]).unwrap();
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        let hash = parse_hash(&"ab".repeat(32)).unwrap();
+        assert_eq!(hash, [0xab; 32]);
+        assert!(parse_hash("abcd").is_err());
+        assert!(parse_hash(&"zz".repeat(32)).is_err());
+    }
+}
@@ -0,0 +1,657 @@
+use crate::archive;
+use crate::decompress;
+use crate::errors::*;
+use crate::limits::Limits;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+use tar::{Archive, EntryType};
+
+const MTREE_PATH: &str = ".MTREE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Link,
+    Other,
+}
+
+impl FileType {
+    fn parse(value: &str) -> FileType {
+        match value {
+            "file" => FileType::File,
+            "dir" => FileType::Dir,
+            "link" => FileType::Link,
+            _ => FileType::Other,
+        }
+    }
+
+    fn from_tar(entry_type: EntryType) -> FileType {
+        match entry_type {
+            // hardlinks are listed as regular files in the manifest
+            EntryType::Regular | EntryType::Continuous | EntryType::Link => FileType::File,
+            EntryType::Directory => FileType::Dir,
+            EntryType::Symlink => FileType::Link,
+            _ => FileType::Other,
+        }
+    }
+}
+
+/// A single file in the `.MTREE` of a package, after applying all `/set` defaults
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub path: String,
+    pub file_type: FileType,
+    pub mode: Option<u32>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub link: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Keywords<'a> {
+    file_type: Option<&'a str>,
+    mode: Option<&'a str>,
+    size: Option<&'a str>,
+    sha256: Option<&'a str>,
+    link: Option<&'a str>,
+}
+
+impl<'a> Keywords<'a> {
+    fn set(&mut self, key: &str, value: &'a str) {
+        match key {
+            "type" => self.file_type = Some(value),
+            "mode" => self.mode = Some(value),
+            "size" => self.size = Some(value),
+            "sha256digest" => self.sha256 = Some(value),
+            "link" => self.link = Some(value),
+            _ => (),
+        }
+    }
+
+    fn unset(&mut self, key: &str) {
+        match key {
+            "type" => self.file_type = None,
+            "mode" => self.mode = None,
+            "size" => self.size = None,
+            "sha256digest" => self.sha256 = None,
+            "link" => self.link = None,
+            _ => (),
+        }
+    }
+}
+
+/// Decode the octal escapes (`\040`) that are used for special characters in paths
+fn unescape(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| bytes[i] == b'\\' && digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        if let Some(digits) = octal {
+            let n = digits.iter().fold(0u32, |n, d| n * 8 + (*d - b'0') as u32);
+            out.push(u8::try_from(n).context("Octal escape is out of range")?);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).context("Path is not valid utf-8")
+}
+
+/// Strip the `./` prefix of the manifest and the trailing `/` of tar directories
+fn normalize_path(path: &str) -> &str {
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.strip_suffix('/').unwrap_or(path)
+}
+
+pub fn parse(content: &str) -> Result<Vec<Entry>> {
+    let mut defaults = Keywords::default();
+    let mut entries = Vec::new();
+
+    for (idx, line) in content.lines().enumerate() {
+        let num = idx + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap_or_default();
+
+        match first {
+            "/set" => {
+                for word in words {
+                    let (key, value) = word
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("Line {}: expected key=value: {:?}", num, word))?;
+                    defaults.set(key, value);
+                }
+            }
+            "/unset" => {
+                for key in words {
+                    defaults.unset(key);
+                }
+            }
+            _ if first.starts_with('/') => {
+                bail!("Line {}: unsupported special command: {:?}", num, first)
+            }
+            path => {
+                let mut keywords = defaults.clone();
+                for word in words {
+                    let (key, value) = word
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("Line {}: expected key=value: {:?}", num, word))?;
+                    keywords.set(key, value);
+                }
+
+                let path = unescape(path).with_context(|| anyhow!("Line {}", num))?;
+                let mode = keywords
+                    .mode
+                    .map(|mode| u32::from_str_radix(mode, 8))
+                    .transpose()
+                    .with_context(|| anyhow!("Line {}: invalid mode", num))?;
+                let size = keywords
+                    .size
+                    .map(|size| size.parse())
+                    .transpose()
+                    .with_context(|| anyhow!("Line {}: invalid size", num))?;
+                let link = keywords
+                    .link
+                    .map(unescape)
+                    .transpose()
+                    .with_context(|| anyhow!("Line {}: invalid link", num))?;
+
+                entries.push(Entry {
+                    path: normalize_path(&path).to_string(),
+                    file_type: FileType::parse(keywords.file_type.unwrap_or("file")),
+                    mode,
+                    size,
+                    sha256: keywords.sha256.map(|s| s.to_lowercase()),
+                    link,
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// A difference between the content of a package and its `.MTREE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing {
+        path: String,
+    },
+    Extra {
+        path: String,
+    },
+    FileType {
+        path: String,
+        expected: FileType,
+        actual: FileType,
+    },
+    Size {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    Mode {
+        path: String,
+        expected: u32,
+        actual: u32,
+    },
+    Sha256 {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    LinkTarget {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    UnresolvedHardLink {
+        path: String,
+        target: String,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Missing { path } => write!(w, "{:?}: missing from package", path),
+            Mismatch::Extra { path } => write!(w, "{:?}: not listed in .MTREE", path),
+            Mismatch::FileType {
+                path,
+                expected,
+                actual,
+            } => write!(w, "{:?}: expected {:?}, got {:?}", path, expected, actual),
+            Mismatch::Size {
+                path,
+                expected,
+                actual,
+            } => write!(w, "{:?}: expected size {}, got {}", path, expected, actual),
+            Mismatch::Mode {
+                path,
+                expected,
+                actual,
+            } => write!(
+                w,
+                "{:?}: expected mode {:o}, got {:o}",
+                path, expected, actual
+            ),
+            Mismatch::Sha256 {
+                path,
+                expected,
+                actual,
+            } => write!(
+                w,
+                "{:?}: expected sha256 {}, got {}",
+                path, expected, actual
+            ),
+            Mismatch::LinkTarget {
+                path,
+                expected,
+                actual,
+            } => write!(
+                w,
+                "{:?}: expected link to {:?}, got {:?}",
+                path, expected, actual
+            ),
+            Mismatch::UnresolvedHardLink { path, target } => {
+                write!(w, "{:?}: hardlink to {:?} can't be verified", path, target)
+            }
+        }
+    }
+}
+
+/// Extract, decompress and parse the `.MTREE` of a package
+pub fn from_pkg(pkg: &[u8], limits: &Limits) -> Result<Vec<Entry>> {
+    info!("Extracting .MTREE from package...");
+    let bytes = archive::extract_file(pkg, MTREE_PATH, limits)?;
+    let compression = decompress::detect_compression(&bytes);
+    let reader = decompress::stream(compression, &bytes).context("Failed to decompress .MTREE")?;
+
+    let mut content = String::new();
+    limits
+        .decompressed(reader)
+        .read_to_string(&mut content)
+        .context("Failed to read .MTREE")?;
+
+    parse(&content)
+}
+
+/// Compare the size and sha256 of some content with its `.MTREE` entry
+fn check_content(
+    mismatches: &mut Vec<Mismatch>,
+    path: &str,
+    mtree: &Entry,
+    size: u64,
+    sha256: &str,
+) {
+    if let Some(expected) = mtree.size {
+        if size != expected {
+            mismatches.push(Mismatch::Size {
+                path: path.to_string(),
+                expected,
+                actual: size,
+            });
+        }
+    }
+
+    if let Some(expected) = &mtree.sha256 {
+        if sha256 != expected {
+            mismatches.push(Mismatch::Sha256 {
+                path: path.to_string(),
+                expected: expected.clone(),
+                actual: sha256.to_string(),
+            });
+        }
+    }
+}
+
+/// Walk every entry of the package and compare it with the `.MTREE`
+pub fn verify_pkg(pkg: &[u8], limits: &Limits) -> Result<Vec<Mismatch>> {
+    let mut expected = BTreeMap::new();
+    for entry in from_pkg(pkg, limits)? {
+        if let Some(dupe) = expected.insert(entry.path.clone(), entry) {
+            bail!(".MTREE contains duplicate entry: {:?}", dupe.path);
+        }
+    }
+
+    let compression = decompress::detect_compression(pkg);
+    let tar = decompress::stream(compression, pkg).context("Failed to open compressed archive")?;
+    let mut archive = Archive::new(limits.decompressed(tar));
+
+    let mut mismatches = Vec::new();
+    // size and sha256 of every regular file so far, to resolve hardlinks
+    let mut contents = BTreeMap::<String, (u64, String)>::new();
+    for (i, entry) in archive.entries()?.enumerate() {
+        limits.check_tar_entries(i + 1)?;
+        let mut entry = entry?;
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let path = normalize_path(&path).to_string();
+        if path == MTREE_PATH {
+            continue;
+        }
+
+        let mtree = if let Some(mtree) = expected.remove(&path) {
+            mtree
+        } else {
+            mismatches.push(Mismatch::Extra { path });
+            continue;
+        };
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let file_type = FileType::from_tar(entry_type);
+        if file_type != mtree.file_type {
+            mismatches.push(Mismatch::FileType {
+                path,
+                expected: mtree.file_type,
+                actual: file_type,
+            });
+            continue;
+        }
+
+        let mode = header.mode()? & 0o7777;
+        if let Some(expected) = mtree.mode {
+            // the permissions of symlinks are meaningless
+            if file_type != FileType::Link && mode != expected {
+                mismatches.push(Mismatch::Mode {
+                    path: path.clone(),
+                    expected,
+                    actual: mode,
+                });
+            }
+        }
+
+        if let (FileType::Link, Some(expected)) = (file_type, &mtree.link) {
+            let actual = entry
+                .link_name()?
+                .map(|link| link.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if *expected != actual {
+                mismatches.push(Mismatch::LinkTarget {
+                    path: path.clone(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+
+        if file_type != FileType::File {
+            continue;
+        }
+
+        if entry_type == EntryType::Link {
+            // hardlinks don't have content of their own, use the content of the target
+            let target = entry
+                .link_name()?
+                .map(|link| normalize_path(&link.to_string_lossy()).to_string())
+                .unwrap_or_default();
+            if let Some((size, sha256)) = contents.get(&target) {
+                check_content(&mut mismatches, &path, &mtree, *size, sha256);
+            } else {
+                mismatches.push(Mismatch::UnresolvedHardLink { path, target });
+            }
+        } else {
+            let mut sha256 = Sha256::new();
+            let size = io::copy(&mut entry, &mut sha256)
+                .with_context(|| anyhow!("Failed to read {:?} from archive", path))?;
+            let sha256 = hex::encode(sha256.finalize());
+
+            check_content(&mut mismatches, &path, &mtree, size, &sha256);
+            contents.insert(path, (size, sha256));
+        }
+    }
+
+    mismatches.extend(expected.into_keys().map(|path| Mismatch::Missing { path }));
+
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        w.write_all(data).unwrap();
+        w.finish().unwrap()
+    }
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, mode: u32, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(mode);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn append_symlink(builder: &mut tar::Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_cksum();
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn append_hardlink(builder: &mut tar::Builder<Vec<u8>>, path: &str, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn build_pkg(mtree: &str, files: &[(&str, u32, &[u8])], symlinks: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, ".MTREE", 0o644, &gzip(mtree.as_bytes()));
+        for (path, mode, data) in files {
+            append(&mut builder, path, *mode, data);
+        }
+        for (path, target) in symlinks {
+            append_symlink(&mut builder, path, target);
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn parse_mtree_with_defaults() {
+        let entries = parse(
+            "#mtree
+/set type=file uid=0 gid=0 mode=644
+./.PKGINFO time=1639741192.0 size=573 sha256digest=7765b3877b5334e9acfd0af4d8519ac219b990874f07ddadd7706e77fa1a00b4
+/set mode=755
+./usr time=1639741192.0 type=dir
+./usr/bin/foo\\040bar time=1639741192.0 size=3
+./usr/lib/libfoo.so time=1639741192.0 mode=777 type=link link=libfoo.so.1
+/unset mode
+./usr/share/foo time=1639741192.0 size=0
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    path: ".PKGINFO".to_string(),
+                    file_type: FileType::File,
+                    mode: Some(0o644),
+                    size: Some(573),
+                    sha256: Some(
+                        "7765b3877b5334e9acfd0af4d8519ac219b990874f07ddadd7706e77fa1a00b4"
+                            .to_string()
+                    ),
+                    link: None,
+                },
+                Entry {
+                    path: "usr".to_string(),
+                    file_type: FileType::Dir,
+                    mode: Some(0o755),
+                    size: None,
+                    sha256: None,
+                    link: None,
+                },
+                Entry {
+                    path: "usr/bin/foo bar".to_string(),
+                    file_type: FileType::File,
+                    mode: Some(0o755),
+                    size: Some(3),
+                    sha256: None,
+                    link: None,
+                },
+                Entry {
+                    path: "usr/lib/libfoo.so".to_string(),
+                    file_type: FileType::Link,
+                    mode: Some(0o777),
+                    size: None,
+                    sha256: None,
+                    link: Some("libfoo.so.1".to_string()),
+                },
+                Entry {
+                    path: "usr/share/foo".to_string(),
+                    file_type: FileType::File,
+                    mode: None,
+                    size: Some(0),
+                    sha256: None,
+                    link: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_mtree_invalid_mode() {
+        let err = parse("#mtree\n./foo mode=999\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"), "{:#}", err);
+    }
+
+    #[test]
+    fn verify_pkg_from_test_data() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let mismatches = verify_pkg(bytes, &Limits::default()).unwrap();
+        assert_eq!(mismatches, vec![]);
+    }
+
+    #[test]
+    fn verify_pkg_detects_mismatches() {
+        let sha256 = hex::encode(Sha256::digest(b"ohai\n"));
+        let mtree = format!(
+            "#mtree
+/set type=file mode=644
+./good size=5 sha256digest={sha256}
+./tampered size=5 sha256digest={sha256}
+./resized size=6
+./chmod mode=755 size=5
+./link type=link link=good
+./missing size=0
+",
+            sha256 = sha256
+        );
+        let pkg = build_pkg(
+            &mtree,
+            &[
+                ("good", 0o644, b"ohai\n"),
+                ("tampered", 0o644, b"0wn3d"),
+                ("resized", 0o644, b"ohai\n"),
+                ("chmod", 0o4755, b"ohai\n"),
+                ("extra", 0o644, b""),
+            ],
+            &[("link", "tampered")],
+        );
+
+        let mismatches = verify_pkg(&pkg, &Limits::default()).unwrap();
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch::Sha256 {
+                    path: "tampered".to_string(),
+                    expected: sha256,
+                    actual: hex::encode(Sha256::digest(b"0wn3d")),
+                },
+                Mismatch::Size {
+                    path: "resized".to_string(),
+                    expected: 6,
+                    actual: 5,
+                },
+                Mismatch::Mode {
+                    path: "chmod".to_string(),
+                    expected: 0o755,
+                    actual: 0o4755,
+                },
+                Mismatch::Extra {
+                    path: "extra".to_string(),
+                },
+                Mismatch::LinkTarget {
+                    path: "link".to_string(),
+                    expected: "good".to_string(),
+                    actual: "tampered".to_string(),
+                },
+                Mismatch::Missing {
+                    path: "missing".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_pkg_resolves_hardlinks() {
+        let sha256 = hex::encode(Sha256::digest(b"ohai\n"));
+        let mtree = format!(
+            "#mtree
+/set type=file mode=644
+./good size=5 sha256digest={sha256}
+./tampered size=5 sha256digest={sha256}
+./hardlink size=5 sha256digest={sha256}
+./tampered-hardlink size=5 sha256digest={sha256}
+./dangling-hardlink size=5 sha256digest={sha256}
+",
+            sha256 = sha256
+        );
+
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, ".MTREE", 0o644, &gzip(mtree.as_bytes()));
+        append(&mut builder, "good", 0o644, b"ohai\n");
+        append(&mut builder, "tampered", 0o644, b"0wn3d");
+        append_hardlink(&mut builder, "hardlink", "good");
+        append_hardlink(&mut builder, "tampered-hardlink", "tampered");
+        append_hardlink(&mut builder, "dangling-hardlink", "nope");
+        let pkg = builder.into_inner().unwrap();
+
+        let mismatches = verify_pkg(&pkg, &Limits::default()).unwrap();
+        let tampered = hex::encode(Sha256::digest(b"0wn3d"));
+        assert_eq!(
+            mismatches,
+            vec![
+                Mismatch::Sha256 {
+                    path: "tampered".to_string(),
+                    expected: sha256.clone(),
+                    actual: tampered.clone(),
+                },
+                Mismatch::Sha256 {
+                    path: "tampered-hardlink".to_string(),
+                    expected: sha256,
+                    actual: tampered,
+                },
+                Mismatch::UnresolvedHardLink {
+                    path: "dangling-hardlink".to_string(),
+                    target: "nope".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use crate::archive;
+use crate::errors::*;
+use crate::keyvalue::{self, set_number_once, set_once};
+use crate::limits::Limits;
+use serde::Serialize;
+
+/// The parsed content of a `.PKGINFO` file, as written by makepkg
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PkgInfo {
+    pub pkgname: String,
+    pub pkgbase: Option<String>,
+    pub pkgver: String,
+    pub pkgdesc: Option<String>,
+    pub url: Option<String>,
+    pub builddate: Option<u64>,
+    pub packager: Option<String>,
+    pub size: Option<u64>,
+    pub arch: String,
+    pub license: Vec<String>,
+    pub replaces: Vec<String>,
+    pub groups: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub provides: Vec<String>,
+    pub backup: Vec<String>,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub checkdepends: Vec<String>,
+    pub xdata: Vec<(String, String)>,
+    pub makepkgopt: Vec<String>,
+}
+
+impl PkgInfo {
+    pub fn parse(content: &str) -> Result<PkgInfo> {
+        let mut pkgname = None;
+        let mut pkgver = None;
+        let mut arch = None;
+
+        let mut info = PkgInfo::default();
+
+        for line in keyvalue::lines(content) {
+            let (num, key, value) = line?;
+
+            let list = match key {
+                "pkgname" => {
+                    set_once(&mut pkgname, key, value, num)?;
+                    continue;
+                }
+                "pkgbase" => {
+                    set_once(&mut info.pkgbase, key, value, num)?;
+                    continue;
+                }
+                "pkgver" => {
+                    set_once(&mut pkgver, key, value, num)?;
+                    continue;
+                }
+                "pkgdesc" => {
+                    set_once(&mut info.pkgdesc, key, value, num)?;
+                    continue;
+                }
+                "url" => {
+                    set_once(&mut info.url, key, value, num)?;
+                    continue;
+                }
+                "builddate" => {
+                    set_number_once(&mut info.builddate, key, value, num)?;
+                    continue;
+                }
+                "packager" => {
+                    set_once(&mut info.packager, key, value, num)?;
+                    continue;
+                }
+                "size" => {
+                    set_number_once(&mut info.size, key, value, num)?;
+                    continue;
+                }
+                "arch" => {
+                    set_once(&mut arch, key, value, num)?;
+                    continue;
+                }
+                "xdata" => {
+                    let (k, v) = value.split_once('=').ok_or_else(|| {
+                        anyhow!("Line {}: expected `xdata = key=value`: {:?}", num, value)
+                    })?;
+                    info.xdata.push((k.to_string(), v.to_string()));
+                    continue;
+                }
+                "license" => &mut info.license,
+                "replaces" => &mut info.replaces,
+                "group" => &mut info.groups,
+                "conflict" => &mut info.conflicts,
+                "provides" => &mut info.provides,
+                "backup" => &mut info.backup,
+                "depend" => &mut info.depends,
+                "optdepend" => &mut info.optdepends,
+                "makedepend" => &mut info.makedepends,
+                "checkdepend" => &mut info.checkdepends,
+                "makepkgopt" => &mut info.makepkgopt,
+                _ => {
+                    debug!("Line {}: ignoring unknown key in .PKGINFO: {:?}", num, key);
+                    continue;
+                }
+            };
+
+            keyvalue::push(list, value);
+        }
+
+        info.pkgname = pkgname.context("Missing pkgname field in .PKGINFO")?;
+        info.pkgver = pkgver.context("Missing pkgver field in .PKGINFO")?;
+        info.arch = arch.context("Missing arch field in .PKGINFO")?;
+
+        Ok(info)
+    }
+
+    /// Extract and parse the `.PKGINFO` of a (compressed) package
+    pub fn from_pkg(pkg: &[u8], limits: &Limits) -> Result<PkgInfo> {
+        info!("Extracting .PKGINFO from package...");
+        let content = archive::extract_file(pkg, ".PKGINFO", limits)?;
+        let content = String::from_utf8(content).context(".PKGINFO is not valid utf-8")?;
+        let pkginfo = PkgInfo::parse(&content)?;
+        debug!("Parsed pkginfo: {:?}", pkginfo);
+        Ok(pkginfo)
+    }
+
+    /// The pkgbase, if it's missing the package is its own pkgbase
+    pub fn pkgbase(&self) -> &str {
+        self.pkgbase.as_deref().unwrap_or(&self.pkgname)
+    }
+
+    /// Lookup a value from the `xdata` fields, eg. `pkgtype`
+    pub fn xdata(&self, key: &str) -> Option<&str> {
+        self.xdata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pkginfo_from_pkg() {
+        let bytes = include_bytes!("../../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+        let pkginfo = PkgInfo::from_pkg(bytes, &Limits::default()).unwrap();
+        assert_eq!(
+            pkginfo,
+            PkgInfo {
+                pkgname: "rebuilderd".to_string(),
+                pkgbase: Some("rebuilderd".to_string()),
+                pkgver: "0.18.1-1".to_string(),
+                pkgdesc: Some(
+                    "Independent verification system of binary packages (server package)"
+                        .to_string()
+                ),
+                url: Some("https://github.com/kpcyrd/rebuilderd".to_string()),
+                builddate: Some(1639741192),
+                packager: Some("kpcyrd <kpcyrd@archlinux.org>".to_string()),
+                size: Some(13573669),
+                arch: "x86_64".to_string(),
+                license: vec!["GPL3".to_string()],
+                backup: vec![
+                    "etc/rebuilderd.conf".to_string(),
+                    "etc/rebuilderd-sync.conf".to_string(),
+                    "etc/rebuilderd-worker.conf".to_string(),
+                ],
+                depends: vec![
+                    "rebuilderd-tools".to_string(),
+                    "sqlite".to_string(),
+                    "archlinux-repro".to_string(),
+                ],
+                makedepends: vec![
+                    "cargo".to_string(),
+                    "sqlite".to_string(),
+                    "scdoc".to_string(),
+                ],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_pkginfo_all_fields() {
+        let pkginfo = PkgInfo::parse(
+            "# Generated by makepkg 6.0.2
+# using fakeroot version 1.31
+pkgname = python-foo
+pkgbase = foo
+xdata = pkgtype=split
+pkgver = 1:2.0.1-3
+pkgdesc = A = sign in the description
+url = https://example.com
+builddate = 1680000000
+packager = Unknown Packager
+size = 1234
+arch = any
+license = MIT
+license = custom:BSD
+replaces = python-oldfoo
+group = foo-group
+conflict = python-oldfoo
+provides = python-bar=2.0
+backup = etc/foo.conf
+depend = python>=3.11
+optdepend = python-baz: for baz support
+makedepend = python-build
+checkdepend = python-pytest
+makepkgopt = strip
+makepkgopt = !debug
+",
+        )
+        .unwrap();
+
+        assert_eq!(pkginfo.pkgname, "python-foo");
+        assert_eq!(pkginfo.pkgbase(), "foo");
+        assert_eq!(pkginfo.pkgver, "1:2.0.1-3");
+        assert_eq!(
+            pkginfo.pkgdesc.as_deref(),
+            Some("A = sign in the description")
+        );
+        assert_eq!(pkginfo.builddate, Some(1680000000));
+        assert_eq!(pkginfo.size, Some(1234));
+        assert_eq!(pkginfo.arch, "any");
+        assert_eq!(pkginfo.license, &["MIT", "custom:BSD"]);
+        assert_eq!(pkginfo.replaces, &["python-oldfoo"]);
+        assert_eq!(pkginfo.groups, &["foo-group"]);
+        assert_eq!(pkginfo.conflicts, &["python-oldfoo"]);
+        assert_eq!(pkginfo.provides, &["python-bar=2.0"]);
+        assert_eq!(pkginfo.backup, &["etc/foo.conf"]);
+        assert_eq!(pkginfo.depends, &["python>=3.11"]);
+        assert_eq!(pkginfo.optdepends, &["python-baz: for baz support"]);
+        assert_eq!(pkginfo.makedepends, &["python-build"]);
+        assert_eq!(pkginfo.checkdepends, &["python-pytest"]);
+        assert_eq!(pkginfo.makepkgopt, &["strip", "!debug"]);
+        assert_eq!(pkginfo.xdata("pkgtype"), Some("split"));
+    }
+
+    #[test]
+    fn parse_pkginfo_error_line_number() {
+        let err = PkgInfo::parse("pkgname = foo\npkgver = 1.0-1\nthis is garbage\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 3:"), "{:#}", err);
+    }
+
+    #[test]
+    fn parse_pkginfo_duplicate_key() {
+        let err = PkgInfo::parse("pkgname = foo\npkgname = bar\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"), "{:#}", err);
+    }
+
+    #[test]
+    fn parse_pkginfo_invalid_number() {
+        let err = PkgInfo::parse("pkgname = foo\nsize = 12x\n").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"), "{:#}", err);
+    }
+
+    #[test]
+    fn parse_pkginfo_missing_arch() {
+        let err = PkgInfo::parse("pkgname = foo\npkgver = 1.0-1\n");
+        assert!(err.is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::errors::*;
+use crate::snapshot::DbSnapshot;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version of the archive format, bumped on incompatible changes
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// The transparency signature of a single package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub sha256: String,
+    /// The content of the `<filename>.t` file
+    pub signature: String,
+}
+
+/// The transparency signatures of all packages in a database snapshot
+///
+/// Clients download this once per database instead of requesting a proof for every
+/// package they install, so the server doesn't learn which packages they use.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProofArchive {
+    pub version: u32,
+    /// The database filename, eg. `core.db`
+    pub db: String,
+    pub db_sha256: String,
+    /// Index of the signatures, by package filename
+    pub entries: BTreeMap<String, ArchiveEntry>,
+}
+
+impl ProofArchive {
+    pub fn new(db: &str, db_sha256: &str) -> ProofArchive {
+        ProofArchive {
+            version: ARCHIVE_VERSION,
+            db: db.to_string(),
+            db_sha256: db_sha256.to_string(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// The name the archive of a snapshot is published under, this is also the trusted comment of its signature
+    pub fn name(db: &str, db_sha256: &str) -> String {
+        format!("{}.proofs", DbSnapshot::proof_name(db, db_sha256))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<ProofArchive> {
+        let archive = serde_json::from_slice::<ProofArchive>(bytes)
+            .context("Failed to parse proof archive")?;
+        if archive.version != ARCHIVE_VERSION {
+            bail!("Unsupported proof archive version: {}", archive.version);
+        }
+        if archive.db.is_empty() || archive.db.contains('/') || archive.db.starts_with('.') {
+            bail!(
+                "Invalid database filename in proof archive: {:?}",
+                archive.db
+            );
+        }
+        Ok(archive)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>> {
+        let mut bytes = serde_json::to_vec(self)?;
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+
+    pub fn insert(&mut self, filename: &str, sha256: &str, signature: &str) {
+        self.entries.insert(
+            filename.to_string(),
+            ArchiveEntry {
+                sha256: sha256.to_string(),
+                signature: signature.to_string(),
+            },
+        );
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&ArchiveEntry> {
+        self.entries.get(filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let mut archive = ProofArchive::new("core.db", &"ab".repeat(32));
+        archive.insert(
+            "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst",
+            &"cd".repeat(32),
+            "untrusted comment: signature from minisign secret key\n...",
+        );
+        let bytes = archive.to_vec().unwrap();
+        let parsed = ProofArchive::parse(&bytes).unwrap();
+        assert_eq!(parsed, archive);
+        assert_eq!(
+            parsed
+                .get("rebuilderd-0.18.1-1-x86_64.pkg.tar.zst")
+                .unwrap()
+                .sha256,
+            "cd".repeat(32)
+        );
+        assert_eq!(parsed.get("rebuilderd-0.18.0-1-x86_64.pkg.tar.zst"), None);
+    }
+
+    #[test]
+    fn test_archive_name() {
+        assert_eq!(
+            ProofArchive::name("core.db", &"ab".repeat(32)),
+            format!("core.db.{}.proofs", "ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_archive_invalid() {
+        assert!(ProofArchive::parse(b"").is_err());
+        assert!(ProofArchive::parse(
+            br#"{"version":2,"db":"core.db","db_sha256":"ab","entries":{}}"#
+        )
+        .is_err());
+        assert!(ProofArchive::parse(
+            br#"{"version":1,"db":"../core.db","db_sha256":"ab","entries":{}}"#
+        )
+        .is_err());
+        assert!(ProofArchive::parse(
+            br#"{"version":1,"db":"core.db","db_sha256":"ab","entries":{}}"#
+        )
+        .is_ok());
+    }
+}
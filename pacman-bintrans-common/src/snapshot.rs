@@ -0,0 +1,62 @@
+use crate::errors::*;
+use std::fmt;
+
+/// A repository database snapshot, this is stored in the trusted comment of its signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbSnapshot {
+    pub filename: String,
+    /// The time the snapshot was last signed, as unix timestamp
+    pub timestamp: u64,
+}
+
+impl DbSnapshot {
+    pub fn parse(comment: &str) -> Result<DbSnapshot> {
+        let (filename, timestamp) = comment
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Expected `<filename> <timestamp>`: {:?}", comment))?;
+        if filename.is_empty() || filename.contains('/') {
+            bail!("Invalid filename in snapshot: {:?}", filename);
+        }
+        let timestamp = timestamp
+            .parse()
+            .with_context(|| anyhow!("Invalid timestamp in snapshot: {:?}", timestamp))?;
+        Ok(DbSnapshot {
+            filename: filename.to_string(),
+            timestamp,
+        })
+    }
+
+    /// The name the signature of a snapshot is published under, without the `.t` suffix
+    pub fn proof_name(filename: &str, sha256: &str) -> String {
+        format!("{}.{}", filename, sha256)
+    }
+}
+
+impl fmt::Display for DbSnapshot {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(w, "{} {}", self.filename, self.timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let snapshot = DbSnapshot {
+            filename: "core.db".to_string(),
+            timestamp: 1639741192,
+        };
+        let comment = snapshot.to_string();
+        assert_eq!(comment, "core.db 1639741192");
+        assert_eq!(DbSnapshot::parse(&comment).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn snapshot_invalid() {
+        assert!(DbSnapshot::parse("core.db").is_err());
+        assert!(DbSnapshot::parse("core.db yesterday").is_err());
+        assert!(DbSnapshot::parse("../core.db 1639741192").is_err());
+    }
+}
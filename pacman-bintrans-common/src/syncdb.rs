@@ -0,0 +1,536 @@
+use crate::decompress;
+use crate::errors::*;
+use crate::limits::Limits;
+use std::collections::HashMap;
+use tar::{Archive, EntryType};
+
+/// A package entry of a pacman sync database (`.db` or `.files`), see repo-add(8)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Pkg {
+    pub filename: String,
+    pub name: String,
+    pub base: Option<String>,
+    pub version: String,
+    pub desc: Option<String>,
+    pub groups: Vec<String>,
+    pub csize: Option<u64>,
+    pub isize: Option<u64>,
+    pub md5sum: Option<String>,
+    pub sha256sum: String,
+    pub pgpsig: Option<String>,
+    pub url: Option<String>,
+    pub license: Vec<String>,
+    pub arch: String,
+    pub builddate: Option<u64>,
+    pub packager: Option<String>,
+    pub replaces: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub provides: Vec<String>,
+    pub depends: Vec<String>,
+    pub optdepends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub checkdepends: Vec<String>,
+    /// Only available in `.files` databases
+    pub files: Vec<String>,
+}
+
+impl Pkg {
+    /// The pkgbase, if it's missing the package is its own pkgbase
+    pub fn base(&self) -> &str {
+        self.base.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// An entry of the database that couldn't be parsed
+#[derive(Debug)]
+pub struct EntryError {
+    pub entry: String,
+    pub error: Error,
+}
+
+#[derive(Debug, Default)]
+pub struct SyncDb {
+    pub pkgs: Vec<Pkg>,
+    pub errors: Vec<EntryError>,
+}
+
+impl SyncDb {
+    pub fn find_by_filename(&self, filename: &str) -> Option<&Pkg> {
+        self.pkgs.iter().find(|pkg| pkg.filename == filename)
+    }
+}
+
+/// A package entry of the local database of installed packages, `local/<name>-<version>/desc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalPkg {
+    pub name: String,
+    pub version: String,
+}
+
+/// Parse the desc file of an installed package
+pub fn parse_local(content: &str) -> Result<LocalPkg> {
+    let mut sections = Sections::default();
+    sections.parse(content)?;
+    Ok(LocalPkg {
+        name: sections.required("NAME")?,
+        version: sections.required("VERSION")?,
+    })
+}
+
+/// The sections that were collected from all files of a database entry
+#[derive(Debug, Default)]
+struct Sections {
+    values: HashMap<String, Vec<String>>,
+}
+
+impl Sections {
+    fn parse(&mut self, content: &str) -> Result<()> {
+        let mut lines = content.lines().enumerate();
+        while let Some((idx, line)) = lines.next() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let key = line
+                .strip_prefix('%')
+                .and_then(|key| key.strip_suffix('%'))
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| anyhow!("Line {}: expected %SECTION%: {:?}", idx + 1, line))?;
+
+            let mut values = Vec::new();
+            for (_, value) in &mut lines {
+                if value.is_empty() {
+                    break;
+                }
+                values.push(value.to_string());
+            }
+
+            if self.values.insert(key.to_string(), values).is_some() {
+                bail!("Line {}: duplicate section %{}%", idx + 1, key);
+            }
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, key: &str) -> Vec<String> {
+        self.values.remove(key).unwrap_or_default()
+    }
+
+    fn single(&mut self, key: &str) -> Result<Option<String>> {
+        let mut values = self.list(key);
+        if values.len() > 1 {
+            bail!("Section %{}% has more than one value", key);
+        }
+        Ok(values.pop())
+    }
+
+    fn required(&mut self, key: &str) -> Result<String> {
+        self.single(key)?
+            .ok_or_else(|| anyhow!("Missing %{}% section", key))
+    }
+
+    fn number(&mut self, key: &str) -> Result<Option<u64>> {
+        self.single(key)?
+            .map(|value| {
+                value
+                    .parse()
+                    .with_context(|| anyhow!("Invalid number in %{}%: {:?}", key, value))
+            })
+            .transpose()
+    }
+
+    fn into_pkg(mut self) -> Result<Pkg> {
+        let pkg = Pkg {
+            filename: self.required("FILENAME")?,
+            name: self.required("NAME")?,
+            base: self.single("BASE")?,
+            version: self.required("VERSION")?,
+            desc: self.single("DESC")?,
+            groups: self.list("GROUPS"),
+            csize: self.number("CSIZE")?,
+            isize: self.number("ISIZE")?,
+            md5sum: self.single("MD5SUM")?,
+            sha256sum: self.required("SHA256SUM")?,
+            pgpsig: self.single("PGPSIG")?,
+            url: self.single("URL")?,
+            license: self.list("LICENSE"),
+            arch: self.required("ARCH")?,
+            builddate: self.number("BUILDDATE")?,
+            packager: self.single("PACKAGER")?,
+            replaces: self.list("REPLACES"),
+            conflicts: self.list("CONFLICTS"),
+            provides: self.list("PROVIDES"),
+            depends: self.list("DEPENDS"),
+            optdepends: self.list("OPTDEPENDS"),
+            makedepends: self.list("MAKEDEPENDS"),
+            checkdepends: self.list("CHECKDEPENDS"),
+            files: self.list("FILES"),
+        };
+
+        if pkg.filename.contains('/') {
+            bail!("Filename contains invalid characters: {:?}", pkg.filename);
+        }
+
+        for key in self.values.keys() {
+            debug!("Ignoring unknown section in database entry: %{}%", key);
+        }
+
+        Ok(pkg)
+    }
+}
+
+/// Parse a (compressed) sync database, entries that fail to parse are
+/// collected in `errors` instead of failing the whole database
+pub fn parse(bytes: &[u8], limits: &Limits) -> Result<SyncDb> {
+    let comp = decompress::detect_compression(bytes);
+    let tar = decompress::stream(comp, bytes).context("Failed to open compressed database")?;
+    let mut archive = Archive::new(limits.decompressed(tar));
+
+    let mut entries = Vec::<(String, Result<Sections>)>::new();
+    let mut index = HashMap::new();
+
+    for (i, entry) in archive.entries()?.enumerate() {
+        limits.check_tar_entries(i + 1)?;
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let (dir, file) = match path.rsplit_once('/') {
+            Some((dir, file @ ("desc" | "depends" | "files"))) => (dir.to_string(), file),
+            _ => {
+                debug!("Ignoring unexpected file in database: {:?}", path);
+                continue;
+            }
+        };
+
+        let read = entry
+            .header()
+            .size()
+            .map_err(Error::from)
+            .and_then(|size| limits.read_entry(size, &mut entry))
+            .and_then(|bytes| String::from_utf8(bytes).map_err(Error::from))
+            .with_context(|| anyhow!("Failed to read {:?} from database", path));
+
+        let idx = *index.entry(dir.clone()).or_insert_with(|| {
+            entries.push((dir, Ok(Sections::default())));
+            entries.len() - 1
+        });
+        if let Ok(sections) = &mut entries[idx].1 {
+            if let Err(err) = read.and_then(|content| {
+                sections
+                    .parse(&content)
+                    .with_context(|| anyhow!("Failed to parse {:?}", file))
+            }) {
+                entries[idx].1 = Err(err);
+            }
+        }
+    }
+
+    let mut db = SyncDb::default();
+    for (entry, sections) in entries {
+        match sections.and_then(Sections::into_pkg) {
+            Ok(pkg) => db.pkgs.push(pkg),
+            Err(error) => db.errors.push(EntryError { entry, error }),
+        }
+    }
+
+    Ok(db)
+}
+
+/// Search a (compressed) sync database for the sha256 of the package with this filename
+///
+/// Unlike `parse` this only looks at `desc` files and stops at the first entry
+/// with a matching filename, entries that fail to parse are skipped.
+pub fn find_sha256(bytes: &[u8], filename: &str, limits: &Limits) -> Result<Option<String>> {
+    let comp = decompress::detect_compression(bytes);
+    let tar = decompress::stream(comp, bytes).context("Failed to open compressed database")?;
+    let mut archive = Archive::new(limits.decompressed(tar));
+
+    for (i, entry) in archive.entries()?.enumerate() {
+        limits.check_tar_entries(i + 1)?;
+        let entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+        if !path.ends_with("/desc") {
+            continue;
+        }
+
+        let size = entry.header().size()?;
+        let content = limits
+            .read_entry(size, entry)
+            .and_then(|bytes| String::from_utf8(bytes).map_err(Error::from))
+            .with_context(|| anyhow!("Failed to read {:?} from database", path))?;
+        // cheap check before parsing the sections of every entry
+        if !content.contains(filename) {
+            continue;
+        }
+
+        let mut sections = Sections::default();
+        let sha256 = sections.parse(&content).and_then(|_| {
+            if sections.required("FILENAME")? == filename {
+                sections.required("SHA256SUM").map(Some)
+            } else {
+                Ok(None)
+            }
+        });
+        match sha256 {
+            Ok(Some(sha256)) => return Ok(Some(sha256)),
+            Ok(None) => (),
+            Err(err) => debug!(
+                "Skipping invalid entry {:?} in sync database: {:#}",
+                path, err
+            ),
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const DESC: &str = "%FILENAME%
+rebuilderd-0.18.1-1-x86_64.pkg.tar.zst
+
+%NAME%
+rebuilderd
+
+%BASE%
+rebuilderd
+
+%VERSION%
+0.18.1-1
+
+%DESC%
+Independent verification system of binary packages (server package)
+
+%CSIZE%
+4043745
+
+%ISIZE%
+13573669
+
+%MD5SUM%
+7e4ab4f2b6c3b3b0ac0a8b1b4c2ed1b6
+
+%SHA256SUM%
+4ff5de01f36d8d5c9b8b8d5c1e2f4a5c8b9e2f5a1c4d7e0a3b6c9d2e5f8a1b4c
+
+%PGPSIG%
+iQIzBAABCAAdFiEE
+
+%URL%
+https://github.com/kpcyrd/rebuilderd
+
+%LICENSE%
+GPL3
+
+%ARCH%
+x86_64
+
+%BUILDDATE%
+1639741192
+
+%PACKAGER%
+kpcyrd <kpcyrd@archlinux.org>
+
+%DEPENDS%
+rebuilderd-tools
+sqlite
+archlinux-repro
+
+%MAKEDEPENDS%
+cargo
+sqlite
+scdoc
+
+";
+
+    const FILES: &str = "%FILES%
+usr/
+usr/bin/
+usr/bin/rebuilderd
+
+";
+
+    fn build_db(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        w.write_all(&tar).unwrap();
+        w.finish().unwrap()
+    }
+
+    #[test]
+    fn parse_files_db() {
+        let db = build_db(&[
+            ("rebuilderd-0.18.1-1/desc", DESC),
+            ("rebuilderd-0.18.1-1/files", FILES),
+        ]);
+        let db = parse(&db, &Limits::default()).unwrap();
+        assert!(db.errors.is_empty(), "{:?}", db.errors);
+        assert_eq!(
+            db.pkgs,
+            vec![Pkg {
+                filename: "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst".to_string(),
+                name: "rebuilderd".to_string(),
+                base: Some("rebuilderd".to_string()),
+                version: "0.18.1-1".to_string(),
+                desc: Some(
+                    "Independent verification system of binary packages (server package)"
+                        .to_string()
+                ),
+                groups: vec![],
+                csize: Some(4043745),
+                isize: Some(13573669),
+                md5sum: Some("7e4ab4f2b6c3b3b0ac0a8b1b4c2ed1b6".to_string()),
+                sha256sum: "4ff5de01f36d8d5c9b8b8d5c1e2f4a5c8b9e2f5a1c4d7e0a3b6c9d2e5f8a1b4c"
+                    .to_string(),
+                pgpsig: Some("iQIzBAABCAAdFiEE".to_string()),
+                url: Some("https://github.com/kpcyrd/rebuilderd".to_string()),
+                license: vec!["GPL3".to_string()],
+                arch: "x86_64".to_string(),
+                builddate: Some(1639741192),
+                packager: Some("kpcyrd <kpcyrd@archlinux.org>".to_string()),
+                depends: vec![
+                    "rebuilderd-tools".to_string(),
+                    "sqlite".to_string(),
+                    "archlinux-repro".to_string(),
+                ],
+                makedepends: vec![
+                    "cargo".to_string(),
+                    "sqlite".to_string(),
+                    "scdoc".to_string(),
+                ],
+                files: vec![
+                    "usr/".to_string(),
+                    "usr/bin/".to_string(),
+                    "usr/bin/rebuilderd".to_string(),
+                ],
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            db.find_by_filename("rebuilderd-0.18.1-1-x86_64.pkg.tar.zst")
+                .map(|pkg| pkg.base()),
+            Some("rebuilderd")
+        );
+    }
+
+    #[test]
+    fn parse_db_without_packager() {
+        let desc = DESC.replace("%PACKAGER%\nkpcyrd <kpcyrd@archlinux.org>\n\n", "");
+        let db = build_db(&[("rebuilderd-0.18.1-1/desc", &desc)]);
+        let db = parse(&db, &Limits::default()).unwrap();
+        assert!(db.errors.is_empty(), "{:?}", db.errors);
+        assert_eq!(db.pkgs.len(), 1);
+        assert_eq!(db.pkgs[0].packager, None);
+    }
+
+    #[test]
+    fn parse_db_reports_entry_errors() {
+        let broken = DESC
+            .replace("rebuilderd", "broken")
+            .replace("%SHA256SUM%", "%SHA256SUM%\nabcd");
+        let incomplete = DESC.replace("%NAME%\nrebuilderd\n\n", "");
+        let db = build_db(&[
+            ("broken-0.18.1-1/desc", &broken),
+            ("rebuilderd-0.18.1-1/desc", DESC),
+            ("incomplete-0.18.1-1/desc", &incomplete),
+        ]);
+        let db = parse(&db, &Limits::default()).unwrap();
+        assert_eq!(db.pkgs.len(), 1);
+        assert_eq!(db.pkgs[0].name, "rebuilderd");
+        let errors = db
+            .errors
+            .iter()
+            .map(|e| e.entry.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(errors, &["broken-0.18.1-1", "incomplete-0.18.1-1"]);
+    }
+
+    #[test]
+    fn parse_db_tar_entries_limit() {
+        let db = build_db(&[
+            ("rebuilderd-0.18.1-1/desc", DESC),
+            ("rebuilderd-0.18.1-1/files", FILES),
+        ]);
+        let limits = Limits {
+            max_tar_entries: 1,
+            ..Default::default()
+        };
+        assert!(parse(&db, &limits).is_err());
+    }
+
+    #[test]
+    fn find_sha256_by_filename() {
+        let other = DESC.replace("rebuilderd", "other");
+        let broken = DESC
+            .replace("rebuilderd", "broken")
+            .replace("%SHA256SUM%", "%SHA256SUM%\nabcd");
+        let db = build_db(&[
+            ("other-0.18.1-1/desc", &other),
+            ("broken-0.18.1-1/desc", &broken),
+            ("rebuilderd-0.18.1-1/desc", DESC),
+            ("rebuilderd-0.18.1-1/files", FILES),
+        ]);
+        let limits = Limits::default();
+
+        let sha256 = find_sha256(&db, "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst", &limits).unwrap();
+        assert_eq!(
+            sha256.as_deref(),
+            Some("4ff5de01f36d8d5c9b8b8d5c1e2f4a5c8b9e2f5a1c4d7e0a3b6c9d2e5f8a1b4c")
+        );
+        let sha256 = find_sha256(&db, "broken-0.18.1-1-x86_64.pkg.tar.zst", &limits).unwrap();
+        assert_eq!(sha256, None);
+        let sha256 = find_sha256(&db, "rebuilderd-0.18.1-1-x86_64.pkg.tar", &limits).unwrap();
+        assert_eq!(sha256, None);
+    }
+
+    #[test]
+    fn find_sha256_stops_at_first_match() {
+        let db = build_db(&[
+            ("rebuilderd-0.18.1-1/desc", DESC),
+            ("rebuilderd-0.18.1-1/files", FILES),
+        ]);
+        let limits = Limits {
+            max_tar_entries: 1,
+            ..Default::default()
+        };
+        let sha256 = find_sha256(&db, "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst", &limits).unwrap();
+        assert!(sha256.is_some());
+    }
+
+    #[test]
+    fn test_parse_local() {
+        let pkg = parse_local(
+            "%NAME%\nrebuilderd\n\n%VERSION%\n0.18.1-1\n\n%INSTALLDATE%\n1639741192\n\n%REASON%\n1\n\n",
+        )
+        .unwrap();
+        assert_eq!(
+            pkg,
+            LocalPkg {
+                name: "rebuilderd".to_string(),
+                version: "0.18.1-1".to_string(),
+            }
+        );
+        assert!(parse_local("%NAME%\nrebuilderd\n\n").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::errors::*;
+use base64::prelude::*;
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::tls::{Certificate, Identity, TlsInfo};
+use reqwest::{ClientBuilder, Response};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+/// The number of redirects reqwest follows by default
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl From<TlsVersion> for reqwest::tls::Version {
+    fn from(version: TlsVersion) -> reqwest::tls::Version {
+        match version {
+            TlsVersion::Tls10 => reqwest::tls::Version::TLS_1_0,
+            TlsVersion::Tls11 => reqwest::tls::Version::TLS_1_1,
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        }
+    }
+}
+
+/// TLS settings for a single host
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM bundle with certificate authorities to trust in addition to the system ones
+    pub ca_bundle: Option<PathBuf>,
+    /// sha256 hashes of the public key of the server certificate, `sha256/<base64>`
+    #[serde(default)]
+    pub pins: Vec<String>,
+    pub min_tls_version: Option<TlsVersion>,
+    /// PEM encoded client certificate, requires `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM encoded PKCS#8 private key of the client certificate
+    pub client_key: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    pub fn apply(&self, mut b: ClientBuilder) -> Result<ClientBuilder> {
+        if let Some(path) = &self.ca_bundle {
+            let pem = fs::read(path)
+                .with_context(|| anyhow!("Failed to read ca bundle from {:?}", path))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| anyhow!("Failed to parse ca bundle from {:?}", path))?;
+            for cert in certs {
+                b = b.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(version) = self.min_tls_version {
+            b = b.min_tls_version(version.into());
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert = fs::read(cert).with_context(|| {
+                    anyhow!("Failed to read client certificate from {:?}", cert)
+                })?;
+                let key = fs::read(key)
+                    .with_context(|| anyhow!("Failed to read client key from {:?}", key))?;
+                let identity = Identity::from_pkcs8_pem(&cert, &key)
+                    .context("Failed to load client certificate")?;
+                b = b.identity(identity);
+            }
+            (None, None) => (),
+            _ => bail!("Both client_cert and client_key need to be configured"),
+        }
+
+        if !self.pins.is_empty() {
+            for pin in &self.pins {
+                validate_pin(pin)?;
+            }
+            b = b.tls_info(true);
+        }
+
+        Ok(b)
+    }
+
+    /// Ensure the server certificate matches one of the pins, before the response is used
+    ///
+    /// The certificate is only known after the handshake, so the request (and a
+    /// client certificate) was already sent to the server at this point.
+    pub fn check_pins(&self, resp: &Response) -> Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        if resp.status().is_redirection() {
+            bail!(
+                "Not following redirect from a host with certificate pins: {}",
+                resp.status()
+            );
+        }
+
+        let cert = resp
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .ok_or_else(|| {
+                anyhow!("Certificate pins are configured, but there's no server certificate")
+            })?;
+        let pin = spki_pin(cert)?;
+
+        if !self.pins.contains(&pin) {
+            bail!(
+                "Server certificate doesn't match any pin: got {}, expected one of {:?}",
+                pin,
+                self.pins
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Don't follow redirects to or from hosts with pins, their certificate couldn't be checked
+pub fn redirect_policy(pinned: BTreeSet<String>) -> Policy {
+    Policy::custom(move |attempt: Attempt| {
+        let is_pinned = |url: &reqwest::Url| {
+            url.host_str()
+                .is_some_and(|host| pinned.contains(&host.to_lowercase()))
+        };
+        if is_pinned(attempt.url()) || attempt.previous().iter().any(is_pinned) {
+            attempt.stop()
+        } else if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error("Too many redirects")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+fn validate_pin(pin: &str) -> Result<()> {
+    let hash = pin
+        .strip_prefix("sha256/")
+        .ok_or_else(|| anyhow!("Pin needs to start with `sha256/`: {:?}", pin))?;
+    let hash = BASE64_STANDARD
+        .decode(hash)
+        .with_context(|| anyhow!("Pin is not valid base64: {:?}", pin))?;
+    if hash.len() != 32 {
+        bail!("Pin has an invalid length: {:?}", pin);
+    }
+    Ok(())
+}
+
+/// A single DER element
+struct Der<'a> {
+    tag: u8,
+    /// The whole element, including tag and length
+    raw: &'a [u8],
+    content: &'a [u8],
+    /// The input following the element
+    rest: &'a [u8],
+}
+
+/// Read a single DER element from the start of the input
+fn der_element(input: &[u8]) -> Result<Der<'_>> {
+    let (tag, first) = match input {
+        [tag, first, ..] => (*tag, *first),
+        _ => bail!("Unexpected end of DER input"),
+    };
+
+    let (header, len) = if first & 0x80 == 0 {
+        (2, first as usize)
+    } else {
+        let num = (first & 0x7f) as usize;
+        if num == 0 || num > 4 || input.len() < 2 + num {
+            bail!("Invalid DER length");
+        }
+        let len = input[2..2 + num]
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (2 + num, len)
+    };
+
+    let end = header + len;
+    if input.len() < end {
+        bail!("DER element is longer than the input");
+    }
+    Ok(Der {
+        tag,
+        raw: &input[..end],
+        content: &input[header..end],
+        rest: &input[end..],
+    })
+}
+
+/// The pin of a DER encoded certificate, the base64 encoded sha256 of its SubjectPublicKeyInfo
+pub fn spki_pin(cert: &[u8]) -> Result<String> {
+    let cert = der_element(cert).context("Failed to parse certificate")?;
+    let tbs = der_element(cert.content).context("Failed to parse tbsCertificate")?;
+
+    // skip the optional version
+    let mut rest = tbs.content;
+    let version = der_element(rest)?;
+    if version.tag == 0xa0 {
+        rest = version.rest;
+    }
+    // skip serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+
+    let spki = der_element(rest)?;
+    if spki.tag != 0x30 {
+        bail!("Expected SubjectPublicKeyInfo, found tag {:#x}", spki.tag);
+    }
+    Ok(format!(
+        "sha256/{}",
+        BASE64_STANDARD.encode(Sha256::digest(spki.raw))
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spki_pin() {
+        let cert = include_bytes!("../../test_data/tls-cert.der");
+        assert_eq!(
+            spki_pin(cert).unwrap(),
+            "sha256/1zwWNzmuBauRhVc/jmLVSQoLnRNTiJReCe/u/fegOJs="
+        );
+    }
+
+    #[test]
+    fn test_spki_pin_invalid() {
+        let cert = include_bytes!("../../test_data/tls-cert.der");
+        assert!(spki_pin(&cert[..100]).is_err());
+        assert!(spki_pin(b"").is_err());
+    }
+
+    #[test]
+    fn test_validate_pin() {
+        assert!(validate_pin("sha256/1zwWNzmuBauRhVc/jmLVSQoLnRNTiJReCe/u/fegOJs=").is_ok());
+        assert!(validate_pin("1zwWNzmuBauRhVc/jmLVSQoLnRNTiJReCe/u/fegOJs=").is_err());
+        assert!(validate_pin("sha256/1zwWNzmuBauRhVc").is_err());
+        assert!(validate_pin("sha256/not base64").is_err());
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let config = TlsConfig {
+            client_cert: Some("/etc/pacman-bintrans/client.pem".into()),
+            ..Default::default()
+        };
+        assert!(config.apply(reqwest::Client::builder()).is_err());
+    }
+}
@@ -1,3 +1,293 @@
-pub trait StatusIndicator {
-    fn set_progress(&self);
+use crate::errors::*;
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::str::FromStr;
+
+/// The result of a step that is reported to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Success => "success",
+            Status::Failure => "failure",
+        }
+    }
+}
+
+/// Reports the progress of a verification to the user
+pub trait StatusIndicator: Send {
+    /// A new step has started, eg. `Checking transparency log...`
+    fn progress(&mut self, msg: &str);
+
+    /// The current step (if any) has finished with a message that should stay visible
+    fn finish(&mut self, status: Status, msg: &str, label: Option<&str>);
+
+    /// The current step (if any) has finished and there's nothing to report
+    fn clear(&mut self);
+
+    fn success(&mut self, msg: &str) {
+        self.finish(Status::Success, msg, None);
+    }
+
+    fn failure(&mut self, msg: &str) {
+        self.finish(Status::Failure, msg, None);
+    }
+}
+
+/// How status output should be rendered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Use `fancy` on a terminal and `plain` otherwise
+    Auto,
+    Plain,
+    Fancy,
+    Json,
+    None,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Mode::Auto),
+            "plain" => Ok(Mode::Plain),
+            "fancy" => Ok(Mode::Fancy),
+            "json" => Ok(Mode::Json),
+            "none" => Ok(Mode::None),
+            _ => bail!(
+                "Unknown ui mode {:?}, expected auto, plain, fancy, json or none",
+                s
+            ),
+        }
+    }
+}
+
+/// Select a renderer writing to stderr
+///
+/// With `Mode::Auto` nothing is rendered if log output is enabled, so the two don't get mixed up.
+pub fn new(mode: Mode, logging: bool) -> Box<dyn StatusIndicator> {
+    let stderr = io::stderr();
+    let color = env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    match mode {
+        Mode::Auto if logging => Box::new(Quiet),
+        Mode::Auto if stderr.is_terminal() => Box::new(Fancy::new(stderr, color)),
+        Mode::Auto | Mode::Plain => Box::new(Plain::new(stderr)),
+        Mode::Fancy => Box::new(Fancy::new(stderr, color)),
+        Mode::Json => Box::new(Json::new(stderr)),
+        Mode::None => Box::new(Quiet),
+    }
+}
+
+/// Discards all status output
+pub struct Quiet;
+
+impl StatusIndicator for Quiet {
+    fn progress(&mut self, _msg: &str) {}
+
+    fn finish(&mut self, _status: Status, _msg: &str, _label: Option<&str>) {}
+
+    fn clear(&mut self) {}
+}
+
+/// One line per event without any escape sequences, for logs and CI output
+pub struct Plain<W> {
+    w: W,
+}
+
+impl<W: Write> Plain<W> {
+    pub fn new(w: W) -> Plain<W> {
+        Plain { w }
+    }
+}
+
+impl<W: Write + Send> StatusIndicator for Plain<W> {
+    fn progress(&mut self, msg: &str) {
+        writeln!(self.w, "[%] {}", msg).ok();
+    }
+
+    fn finish(&mut self, status: Status, msg: &str, label: Option<&str>) {
+        let symbol = match status {
+            Status::Success => '+',
+            Status::Failure => '-',
+        };
+        if let Some(label) = label {
+            writeln!(self.w, "[{}] {} {}", symbol, msg, label).ok();
+        } else {
+            writeln!(self.w, "[{}] {}", symbol, msg).ok();
+        }
+    }
+
+    fn clear(&mut self) {}
+}
+
+/// Replaces progress messages in place once the step finished
+pub struct Fancy<W> {
+    w: W,
+    color: bool,
+    pending: bool,
+}
+
+impl<W: Write> Fancy<W> {
+    pub fn new(w: W, color: bool) -> Fancy<W> {
+        Fancy {
+            w,
+            color,
+            pending: false,
+        }
+    }
+
+    fn take_pending(&mut self) {
+        if self.pending {
+            write!(self.w, "\x1b[1A\x1b[2K\r").ok();
+            self.pending = false;
+        }
+    }
+
+    fn symbol(&self, symbol: char, color: &str) -> String {
+        if self.color {
+            format!("\x1b[1m[\x1b[{}m{}\x1b[0;1m]\x1b[0m", color, symbol)
+        } else {
+            format!("[{}]", symbol)
+        }
+    }
+}
+
+impl<W: Write + Send> StatusIndicator for Fancy<W> {
+    fn progress(&mut self, msg: &str) {
+        self.take_pending();
+        let symbol = self.symbol('%', "34");
+        writeln!(self.w, "{} {}", symbol, msg).ok();
+        self.pending = true;
+    }
+
+    fn finish(&mut self, status: Status, msg: &str, label: Option<&str>) {
+        self.take_pending();
+        let (symbol, color) = match status {
+            Status::Success => ('+', "32"),
+            Status::Failure => ('-', "31"),
+        };
+        let symbol = self.symbol(symbol, color);
+        match label {
+            Some(label) if self.color => writeln!(
+                self.w,
+                "{} {:95} \x1b[{}m{}\x1b[0m",
+                symbol, msg, color, label
+            )
+            .ok(),
+            Some(label) => writeln!(self.w, "{} {:95} {}", symbol, msg, label).ok(),
+            None => writeln!(self.w, "{} {}", symbol, msg).ok(),
+        };
+    }
+
+    fn clear(&mut self) {
+        self.take_pending();
+    }
+}
+
+/// One json object per line, for other programs
+pub struct Json<W> {
+    w: W,
+}
+
+impl<W: Write> Json<W> {
+    pub fn new(w: W) -> Json<W> {
+        Json { w }
+    }
+
+    fn write(&mut self, status: &str, msg: &str, label: Option<&str>) {
+        let event = serde_json::json!({
+            "status": status,
+            "message": msg,
+            "label": label,
+        });
+        writeln!(self.w, "{}", event).ok();
+    }
+}
+
+impl<W: Write + Send> StatusIndicator for Json<W> {
+    fn progress(&mut self, msg: &str) {
+        self.write("progress", msg, None);
+    }
+
+    fn finish(&mut self, status: Status, msg: &str, label: Option<&str>) {
+        self.write(status.as_str(), msg, label);
+    }
+
+    fn clear(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render<S: StatusIndicator>(ui: &mut S) {
+        ui.progress("Checking transparency log...");
+        ui.success("Package is present in transparency log");
+        ui.progress("Checking rebuilder...");
+        ui.clear();
+        ui.progress("Checking rebuilder...");
+        ui.finish(
+            Status::Failure,
+            "Rebuilder attested a different build",
+            Some("MISMATCH"),
+        );
+    }
+
+    #[test]
+    fn test_plain() {
+        let mut ui = Plain::new(Vec::new());
+        render(&mut ui);
+        assert_eq!(
+            String::from_utf8(ui.w).unwrap(),
+            "[%] Checking transparency log...
+[+] Package is present in transparency log
+[%] Checking rebuilder...
+[%] Checking rebuilder...
+[-] Rebuilder attested a different build MISMATCH
+"
+        );
+    }
+
+    #[test]
+    fn test_fancy_no_color() {
+        let mut ui = Fancy::new(Vec::new(), false);
+        render(&mut ui);
+        let out = String::from_utf8(ui.w).unwrap();
+        assert!(!out.contains("\x1b[0m"));
+        assert_eq!(out.matches("\x1b[1A\x1b[2K\r").count(), 3);
+        assert!(out.ends_with(&format!(
+            "[-] {:95} MISMATCH\n",
+            "Rebuilder attested a different build"
+        )));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut ui = Json::new(Vec::new());
+        render(&mut ui);
+        let out = String::from_utf8(ui.w).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[1],
+            r#"{"label":null,"message":"Package is present in transparency log","status":"success"}"#
+        );
+        assert_eq!(
+            lines[4],
+            r#"{"label":"MISMATCH","message":"Rebuilder attested a different build","status":"failure"}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!("auto".parse::<Mode>().unwrap(), Mode::Auto);
+        assert_eq!("json".parse::<Mode>().unwrap(), Mode::Json);
+        assert!("colorful".parse::<Mode>().is_err());
+    }
 }
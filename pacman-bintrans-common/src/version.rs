@@ -0,0 +1,364 @@
+use crate::errors::*;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Compare two version segments like rpmvercmp in libalpm
+fn rpmvercmp(a: &[u8], b: &[u8]) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let mut one = 0;
+    let mut two = 0;
+    // end of the previous segment, used to compare the length of separators
+    let mut ptr1 = 0;
+    let mut ptr2 = 0;
+
+    while one < a.len() && two < b.len() {
+        while one < a.len() && !a[one].is_ascii_alphanumeric() {
+            one += 1;
+        }
+        while two < b.len() && !b[two].is_ascii_alphanumeric() {
+            two += 1;
+        }
+
+        // if we ran to the end of either, we are finished with the loop
+        if one >= a.len() || two >= b.len() {
+            break;
+        }
+
+        // if the separator lengths were different, we are also finished
+        if one - ptr1 != two - ptr2 {
+            return (one - ptr1).cmp(&(two - ptr2));
+        }
+
+        ptr1 = one;
+        ptr2 = two;
+
+        // grab the first completely alpha or completely numeric segment
+        let isnum = a[ptr1].is_ascii_digit();
+        if isnum {
+            while ptr1 < a.len() && a[ptr1].is_ascii_digit() {
+                ptr1 += 1;
+            }
+            while ptr2 < b.len() && b[ptr2].is_ascii_digit() {
+                ptr2 += 1;
+            }
+        } else {
+            while ptr1 < a.len() && a[ptr1].is_ascii_alphabetic() {
+                ptr1 += 1;
+            }
+            while ptr2 < b.len() && b[ptr2].is_ascii_alphabetic() {
+                ptr2 += 1;
+            }
+        }
+
+        // numeric segments are always newer than alpha segments
+        if two == ptr2 {
+            return if isnum {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let mut seg1 = &a[one..ptr1];
+        let mut seg2 = &b[two..ptr2];
+
+        if isnum {
+            // throw away any leading zeros, then the longer number wins
+            while seg1.first() == Some(&b'0') {
+                seg1 = &seg1[1..];
+            }
+            while seg2.first() == Some(&b'0') {
+                seg2 = &seg2[1..];
+            }
+            match seg1.len().cmp(&seg2.len()) {
+                Ordering::Equal => (),
+                ord => return ord,
+            }
+        }
+
+        match seg1.cmp(seg2) {
+            Ordering::Equal => (),
+            ord => return ord,
+        }
+
+        one = ptr1;
+        two = ptr2;
+    }
+
+    let rest1 = a.get(one);
+    let rest2 = b.get(two);
+    match (rest1, rest2) {
+        (None, None) => Ordering::Equal,
+        // we never want a remaining alpha string to beat an empty string
+        (None, Some(c)) if !c.is_ascii_alphabetic() => Ordering::Less,
+        (Some(c), _) if c.is_ascii_alphabetic() => Ordering::Less,
+        _ => Ordering::Greater,
+    }
+}
+
+/// Split a version into epoch, version and (optional) release
+fn parse_evr(evr: &str) -> (Option<&str>, &str, Option<&str>) {
+    let digits = evr.bytes().take_while(u8::is_ascii_digit).count();
+
+    let (epoch, rest) = match evr[digits..].strip_prefix(':') {
+        Some(rest) if digits == 0 => (None, rest),
+        Some(rest) => (Some(&evr[..digits]), rest),
+        None => (None, evr),
+    };
+
+    match rest.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// A package version in the form of `[epoch:]pkgver[-pkgrel]`
+///
+/// Versions are ordered like `alpm_pkg_vercmp`, except that a version without
+/// pkgrel is older than the same version with any pkgrel. Use [`vercmp`] to
+/// ignore a missing pkgrel like pacman does.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub epoch: Option<String>,
+    pub pkgver: String,
+    pub pkgrel: Option<String>,
+}
+
+impl Version {
+    /// Parse a version the same way pacman does, this never fails
+    pub fn parse(s: &str) -> Version {
+        let (epoch, pkgver, pkgrel) = parse_evr(s);
+        Version {
+            epoch: epoch.map(String::from),
+            pkgver: pkgver.to_string(),
+            pkgrel: pkgrel.map(String::from),
+        }
+    }
+
+    fn epoch_or_default(&self) -> &str {
+        self.epoch.as_deref().unwrap_or("0")
+    }
+
+    /// Compare epoch and pkgver, without the pkgrel
+    fn cmp_pkgver(&self, other: &Version) -> Ordering {
+        rpmvercmp(
+            self.epoch_or_default().as_bytes(),
+            other.epoch_or_default().as_bytes(),
+        )
+        .then_with(|| rpmvercmp(self.pkgver.as_bytes(), other.pkgver.as_bytes()))
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Version> {
+        if s.is_empty() || s.chars().any(|c| c.is_whitespace() || c == '/') {
+            bail!("Invalid package version: {:?}", s);
+        }
+        let version = Version::parse(s);
+        if version.pkgver.is_empty() || version.pkgrel.as_deref() == Some("") {
+            bail!("Invalid package version: {:?}", s);
+        }
+        Ok(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(epoch) = &self.epoch {
+            write!(w, "{}:", epoch)?;
+        }
+        write!(w, "{}", self.pkgver)?;
+        if let Some(pkgrel) = &self.pkgrel {
+            write!(w, "-{}", pkgrel)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        self.cmp_pkgver(other)
+            .then_with(|| match (&self.pkgrel, &other.pkgrel) {
+                (Some(rel1), Some(rel2)) => rpmvercmp(rel1.as_bytes(), rel2.as_bytes()),
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+/// Compare two package versions like `vercmp(8)` of pacman
+///
+/// The pkgrel is only compared if both versions have one.
+pub fn vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+    let (a, b) = (Version::parse(a), Version::parse(b));
+    if a.pkgrel.is_some() && b.pkgrel.is_some() {
+        a.cmp(&b)
+    } else {
+        a.cmp_pkgver(&b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // taken from test/util/vercmptest.sh in pacman
+    const VERCMP_TESTS: &[(&str, &str, i8)] = &[
+        // all similar length, no pkgrel
+        ("1.5.0", "1.5.0", 0),
+        ("1.5.1", "1.5.0", 1),
+        // mixed length
+        ("1.5.1", "1.5", 1),
+        // with pkgrel, simple
+        ("1.5.0-1", "1.5.0-1", 0),
+        ("1.5.0-1", "1.5.0-2", -1),
+        ("1.5.0-1", "1.5.1-1", -1),
+        ("1.5.0-2", "1.5.1-1", -1),
+        // with pkgrel, mixed lengths
+        ("1.5-1", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-1", -1),
+        ("1.5-2", "1.5.1-2", -1),
+        // mixed pkgrel inclusion
+        ("1.5", "1.5-1", 0),
+        ("1.5-1", "1.5", 0),
+        ("1.1-1", "1.1", 0),
+        ("1.0-1", "1.1", -1),
+        ("1.1-1", "1.0", 1),
+        // alphanumeric versions
+        ("1.5b-1", "1.5-1", -1),
+        ("1.5b", "1.5", -1),
+        ("1.5b-1", "1.5", -1),
+        ("1.5b", "1.5.1", -1),
+        // from the manpage
+        ("1.0a", "1.0alpha", -1),
+        ("1.0alpha", "1.0b", -1),
+        ("1.0b", "1.0beta", -1),
+        ("1.0beta", "1.0rc", -1),
+        ("1.0rc", "1.0", -1),
+        // going crazy? alpha-dotted versions
+        ("1.5.a", "1.5", 1),
+        ("1.5.b", "1.5.a", 1),
+        ("1.5.1", "1.5.b", 1),
+        // alpha dots and dashes
+        ("1.5.b-1", "1.5.b", 0),
+        ("1.5-1", "1.5.b", -1),
+        // same/similar content, differing separators
+        ("2.0", "2_0", 0),
+        ("2.0_a", "2_0.a", 0),
+        ("2.0a", "2.0.a", -1),
+        ("2___a", "2_a", 1),
+        // epoch included version comparisons
+        ("0:1.0", "0:1.0", 0),
+        ("0:1.0", "0:1.1", -1),
+        ("1:1.0", "0:1.0", 1),
+        ("1:1.0", "0:1.1", 1),
+        ("1:1.0", "2:1.1", -1),
+        // epoch + sometimes present pkgrel
+        ("1:1.0", "0:1.0-1", 1),
+        ("1:1.0-1", "0:1.1-1", 1),
+        // epoch included on one version
+        ("0:1.0", "1.0", 0),
+        ("0:1.0", "1.1", -1),
+        ("0:1.1", "1.0", 1),
+        ("1:1.0", "1.0", 1),
+        ("1:1.0", "1.1", 1),
+        ("1:1.1", "1.1", 1),
+    ];
+
+    #[test]
+    fn vercmp_table() {
+        for (a, b, expected) in VERCMP_TESTS {
+            let expected = expected.cmp(&0);
+            assert_eq!(vercmp(a, b), expected, "vercmp({:?}, {:?})", a, b);
+            // the comparison needs to be symmetric
+            assert_eq!(vercmp(b, a), expected.reverse(), "vercmp({:?}, {:?})", b, a);
+        }
+    }
+
+    #[test]
+    fn vercmp_basic() {
+        assert_eq!(vercmp("1.0-1", "1.0-1"), Ordering::Equal);
+        assert_eq!(vercmp("1.0-1", "1.0-2"), Ordering::Less);
+        assert_eq!(vercmp("1.1-1", "1.0-2"), Ordering::Greater);
+        assert_eq!(vercmp("0.18.1-1", "0.9.0-1"), Ordering::Greater);
+        assert_eq!(vercmp("1:0.1-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(vercmp("1.0alpha-1", "1.0-1"), Ordering::Less);
+        assert_eq!(vercmp("1.0-1", "1.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn parse_version() {
+        let version = "1:0.18.1-1".parse::<Version>().unwrap();
+        assert_eq!(version.epoch.as_deref(), Some("1"));
+        assert_eq!(version.pkgver, "0.18.1");
+        assert_eq!(version.pkgrel.as_deref(), Some("1"));
+        assert_eq!(version.to_string(), "1:0.18.1-1");
+
+        let version = "0.18.1".parse::<Version>().unwrap();
+        assert_eq!(version.epoch, None);
+        assert_eq!(version.pkgrel, None);
+        assert_eq!(version.to_string(), "0.18.1");
+    }
+
+    #[test]
+    fn parse_version_invalid() {
+        assert!("".parse::<Version>().is_err());
+        assert!("1:".parse::<Version>().is_err());
+        assert!("1.0-".parse::<Version>().is_err());
+        assert!("1.0 -1".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn version_ord() {
+        let mut versions = ["1:0.1-1", "0.18.1-1", "0.9.0-2", "0.9.0-1"]
+            .iter()
+            .map(|v| v.parse::<Version>().unwrap())
+            .collect::<Vec<_>>();
+        versions.sort();
+        let versions = versions.iter().map(Version::to_string).collect::<Vec<_>>();
+        assert_eq!(versions, &["0.9.0-1", "0.9.0-2", "0.18.1-1", "1:0.1-1"]);
+        assert_eq!(
+            "1.0-1".parse::<Version>().unwrap(),
+            "0:1.0-1".parse::<Version>().unwrap()
+        );
+    }
+
+    #[test]
+    fn version_ord_without_pkgrel() {
+        let parse = |v: &str| v.parse::<Version>().unwrap();
+        // a missing pkgrel sorts before any pkgrel, so equality stays transitive
+        assert!(parse("1.0") < parse("1.0-1"));
+        assert!(parse("1.0-1") < parse("1.0-2"));
+        assert_ne!(parse("1.0"), parse("1.0-2"));
+        assert!(parse("1.0-2") < parse("1.1"));
+        assert_eq!(parse("1.0"), parse("0:1.0"));
+
+        // vercmp still ignores the missing pkgrel
+        assert_eq!(vercmp("1.0", "1.0-1"), Ordering::Equal);
+        assert_eq!(vercmp("1.0", "1.0-2"), Ordering::Equal);
+    }
+}
@@ -0,0 +1,9 @@
+CREATE TABLE dbs (
+    id INTEGER NOT NULL PRIMARY KEY,
+    sha256sum VARCHAR NOT NULL,
+    filename VARCHAR NOT NULL,
+    timestamp BIGINT NOT NULL,
+    signature VARCHAR NOT NULL,
+    uuid VARCHAR
+);
+CREATE UNIQUE INDEX dbs_uniq ON dbs(sha256sum, filename);
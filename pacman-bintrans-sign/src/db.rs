@@ -1,12 +1,12 @@
 // needed for diesel
 #![allow(clippy::extra_unused_lifetimes)]
 
-use crate::archlinux::Pkg;
 use crate::migrations;
 use crate::schema::*;
 use diesel::prelude::*;
 use diesel::{Connection, SqliteConnection};
 use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::syncdb::Pkg;
 
 pub struct Database {
     db: SqliteConnection,
@@ -78,6 +78,67 @@ impl Database {
             Err(err) => Err(err.into()),
         }
     }
+
+    pub fn db_already_signed(
+        &self,
+        db_filename: &str,
+        db_sha256sum: &str,
+    ) -> Result<Option<DbSignatureRow>> {
+        use crate::schema::dbs::dsl::*;
+
+        let row = dbs
+            .filter(sha256sum.eq(db_sha256sum))
+            .filter(filename.eq(db_filename))
+            .first::<DbSignatureRow>(&self.db)
+            .optional()?;
+
+        Ok(row)
+    }
+
+    /// Replace the signature of a database snapshot that was signed again
+    pub fn update_db_sig(&self, row: &NewDbSignatureRow) -> Result<()> {
+        use crate::schema::dbs::dsl::*;
+
+        let target = dbs
+            .filter(sha256sum.eq(&row.sha256sum))
+            .filter(filename.eq(&row.filename));
+        diesel::update(target)
+            .set((
+                timestamp.eq(row.timestamp),
+                signature.eq(&row.signature),
+                uuid.eq(&row.uuid),
+            ))
+            .execute(&self.db)?;
+        Ok(())
+    }
+
+    pub fn insert_db_sig(&self, row: NewDbSignatureRow) -> Result<()> {
+        let insert = diesel::insert_into(dbs::table)
+            .values(&row)
+            .execute(&self.db);
+
+        use diesel::result::DatabaseErrorKind;
+
+        match insert {
+            Ok(_) => Ok(()),
+            Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                if let Some(my_uuid) = row.uuid {
+                    use crate::schema::dbs::dsl::*;
+
+                    let target = dbs
+                        .filter(sha256sum.eq(&row.sha256sum))
+                        .filter(filename.eq(&row.filename))
+                        .filter(uuid.is_null());
+                    diesel::update(target)
+                        .set(uuid.eq(&my_uuid))
+                        .execute(&self.db)?;
+                }
+
+                Ok(())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
 #[derive(Identifiable, Queryable, AsChangeset, Clone, PartialEq, Eq, Debug)]
@@ -98,3 +159,24 @@ pub struct NewSignatureRow {
     pub signature: String,
     pub uuid: Option<String>,
 }
+
+#[derive(Identifiable, Queryable, AsChangeset, Clone, PartialEq, Eq, Debug)]
+#[table_name = "dbs"]
+pub struct DbSignatureRow {
+    pub id: i32,
+    pub sha256sum: String,
+    pub filename: String,
+    pub timestamp: i64,
+    pub signature: String,
+    pub uuid: Option<String>,
+}
+
+#[derive(Insertable, PartialEq, Eq, Debug, Clone)]
+#[table_name = "dbs"]
+pub struct NewDbSignatureRow {
+    pub sha256sum: String,
+    pub filename: String,
+    pub timestamp: i64,
+    pub signature: String,
+    pub uuid: Option<String>,
+}
@@ -1,3 +1,6 @@
+// needed for diesel 1.x derives and macros
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
 #[macro_use]
@@ -9,22 +12,30 @@ pub mod migrations;
 pub mod schema;
 
 use crate::archlinux::ArchRepo;
-use crate::db::Database;
+use crate::db::{Database, NewDbSignatureRow};
 use clap::Parser;
 use env_logger::Env;
 use minisign::{PublicKey, PublicKeyBox, SecretKey};
-use pacman_bintrans_common::decompress;
 use pacman_bintrans_common::errors::*;
 use pacman_bintrans_common::http::Client;
+use pacman_bintrans_common::limits::{self, Limits};
+use pacman_bintrans_common::proofarchive::ProofArchive;
+use pacman_bintrans_common::snapshot::DbSnapshot;
+use pacman_bintrans_common::syncdb;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tempfile::NamedTempFile;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
+/// One day, clients warn about snapshots that were signed more than a week ago
+const DEFAULT_DB_REFRESH_INTERVAL: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Parser)]
 struct Args {
     /// Verbose logging
@@ -42,6 +53,9 @@ struct Args {
     /// Url or path to pacman database file
     #[arg(long)]
     repo_db: Option<String>,
+    /// Url or path to pacman files database, defaults to the url next to the database if --repo-db isn't set
+    #[arg(long)]
+    repo_files: Option<String>,
     #[arg(long)]
     signature_dir: Option<PathBuf>,
     /// Minisign public key used to sign packages
@@ -58,6 +72,21 @@ struct Args {
     reupload_sigs: bool,
     #[arg(long)]
     dry_run: bool,
+    /// Sign an unchanged database snapshot again after this many seconds, so clients can tell it's still current
+    #[arg(long, default_value_t = DEFAULT_DB_REFRESH_INTERVAL)]
+    db_refresh_interval: u64,
+    /// Maximum number of bytes to download for the database
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_DOWNLOAD_SIZE)]
+    max_download_size: u64,
+    /// Maximum number of bytes to decompress while parsing the database
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_DECOMPRESSED_SIZE)]
+    max_decompressed_size: u64,
+    /// Maximum number of tar entries to read from the database
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_TAR_ENTRIES)]
+    max_tar_entries: usize,
+    /// Maximum size of a single database entry to read into memory
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_ENTRY_SIZE)]
+    max_entry_size: u64,
 }
 
 async fn rekor_upload(pubkey: &PublicKeyBox, artifact: &[u8], signature: &str) -> Result<()> {
@@ -113,6 +142,153 @@ fn write_sig_to_dir(dir: &Path, filename: &str, signature: &str) -> Result<()> {
     Ok(())
 }
 
+/// Publish the signatures of all packages of a database snapshot in a single file
+async fn publish_proof_archive(
+    args: &Args,
+    sk: &SecretKey,
+    pk: &PublicKeyBox,
+    archive: &ProofArchive,
+) -> Result<()> {
+    let sig_dir = if let Some(sig_dir) = &args.signature_dir {
+        sig_dir
+    } else {
+        return Ok(());
+    };
+
+    let name = ProofArchive::name(&archive.db, &archive.db_sha256);
+    if sig_dir.join(&name).exists() {
+        debug!("Proof archive already published: {:?}", name);
+        return Ok(());
+    }
+
+    if args.dry_run {
+        info!(
+            "Dry-run: would publish proof archive with {} signatures: {:?}",
+            archive.entries.len(),
+            name
+        );
+        return Ok(());
+    }
+
+    let bytes = archive.to_vec()?;
+    let sha256 = hex::encode(Sha256::digest(&bytes));
+
+    info!("Signing proof archive: {:?}", name);
+    let data_reader = Cursor::new(&sha256);
+    let sig = minisign::sign(None, sk, data_reader, Some(&name), None)?;
+    let sig = sig.to_string();
+
+    // write the signature first, so the archive is never published without one
+    write_sig_to_dir(sig_dir, &name, &sig)?;
+    let path = sig_dir.join(&name);
+    info!("Writing proof archive to folder: {:?}", path);
+    fs::write(path, &bytes)?;
+
+    if !args.skip_upload {
+        info!("Uploading to sigstore");
+        if let Err(err) = rekor_upload(pk, sha256.as_bytes(), &sig).await {
+            error!("Error(rekor): {:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+async fn sign_db_snapshot(
+    args: &Args,
+    sk: &SecretKey,
+    pk: &PublicKeyBox,
+    db: &Database,
+    db_filename: &str,
+    db_sha256: &str,
+) -> Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let known = db.db_already_signed(db_filename, db_sha256)?;
+    if let Some(known) = &known {
+        let age = timestamp.saturating_sub(known.timestamp as u64);
+        if age < args.db_refresh_interval {
+            debug!(
+                "Database snapshot already known: {:?} => {:?}",
+                db_sha256, db_filename
+            );
+
+            if args.reupload_sigs {
+                info!(
+                    "Reuploading to sigstore: {:?} => {:?}",
+                    db_sha256, db_filename
+                );
+                if let Err(err) = rekor_upload(pk, db_sha256.as_bytes(), &known.signature).await {
+                    error!("Error(rekor): {:?}", err);
+                }
+            }
+
+            return Ok(());
+        }
+
+        // clients measure the age of the database from the timestamp of the snapshot
+        info!(
+            "Database snapshot was signed {} seconds ago, signing it again: {:?} => {:?}",
+            age, db_sha256, db_filename
+        );
+    }
+
+    if args.dry_run {
+        info!(
+            "Dry-run: would sign database snapshot: {:?} => {:?}",
+            db_sha256, db_filename
+        );
+        return Ok(());
+    }
+
+    let snapshot = DbSnapshot {
+        filename: db_filename.to_string(),
+        timestamp,
+    };
+
+    info!("Signing database snapshot: {:?}", snapshot.to_string());
+    let data_reader = Cursor::new(db_sha256);
+    let sig = minisign::sign(None, sk, data_reader, Some(&snapshot.to_string()), None)?;
+    let sig = sig.to_string();
+
+    info!("Adding to database");
+    let mut row = NewDbSignatureRow {
+        sha256sum: db_sha256.to_string(),
+        filename: db_filename.to_string(),
+        timestamp: timestamp as i64,
+        signature: sig.clone(),
+        uuid: None,
+    };
+    if known.is_some() {
+        db.update_db_sig(&row)?;
+    } else {
+        db.insert_db_sig(row.clone())?;
+    }
+
+    if let Some(sig_dir) = &args.signature_dir {
+        let name = DbSnapshot::proof_name(db_filename, db_sha256);
+        if let Err(err) = write_sig_to_dir(sig_dir, &name, &sig) {
+            warn!("Failed to publish signature ({:?}): {:#}", name, err);
+        }
+    }
+
+    if !args.skip_upload {
+        info!("Uploading to sigstore");
+        match rekor_upload(pk, db_sha256.as_bytes(), &sig).await {
+            Ok(_) => {
+                debug!("Record uuid (todo)");
+                row.uuid = Some("dummy".into());
+                db.insert_db_sig(row)?;
+            }
+            Err(err) => {
+                error!("Error(rekor): {:?}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -123,22 +299,42 @@ async fn main() -> Result<()> {
 
     info!("Loading seckey");
     let password = env::var("PACMAN_BINTRANS_PASSWORD").ok();
-    let sk = SecretKey::from_file(args.seckey_path, password)?;
-    let pk = PublicKey::from_file(args.pubkey_path)?.to_box()?;
+    let sk = SecretKey::from_file(&args.seckey_path, password)?;
+    let pk = PublicKey::from_file(&args.pubkey_path)?.to_box()?;
     info!("Key loaded");
 
+    let limits = Limits {
+        max_download_size: args.max_download_size,
+        max_decompressed_size: args.max_decompressed_size,
+        max_tar_entries: args.max_tar_entries,
+        max_entry_size: args.max_entry_size,
+    };
+
     let client = Client::new(None)?;
-    let repo = ArchRepo::new(args.repo_url, args.repo_name, args.architecture);
+    let repo = ArchRepo::new(
+        args.repo_url.clone(),
+        args.repo_name.clone(),
+        args.architecture.clone(),
+    );
 
-    let db = if let Some(path) = args.repo_db {
-        path
+    let db = if let Some(path) = &args.repo_db {
+        path.clone()
     } else {
         repo.db_url()
     };
-    let db = archlinux::load_db(&client, &db).await?;
-    let pkgs = archlinux::db_parse_pkgs(&db)?;
+    let db = archlinux::load_db(&client, &db, &limits).await?;
+    let db_sha256 = hex::encode(Sha256::digest(&db));
+    let syncdb = syncdb::parse(&db, &limits)?;
+    for err in &syncdb.errors {
+        warn!(
+            "Skipping invalid database entry {:?}: {:#}",
+            err.entry, err.error
+        );
+    }
+    let pkgs = syncdb.pkgs;
 
     let db = Database::open("foo.db")?;
+    let mut archive = ProofArchive::new(&repo.db_filename(), &db_sha256);
 
     for pkg in pkgs {
         if let Some(sig) = db.already_signed(&pkg)? {
@@ -146,6 +342,7 @@ async fn main() -> Result<()> {
                 "Package already known: {:?} => {:?}",
                 pkg.sha256sum, pkg.filename
             );
+            archive.insert(&pkg.filename, &pkg.sha256sum, &sig);
 
             if args.reupload_sigs {
                 info!(
@@ -175,6 +372,7 @@ async fn main() -> Result<()> {
 
         info!("Adding to database");
         db.insert_sig(&pkg, sig.to_string(), None)?;
+        archive.insert(&pkg.filename, &pkg.sha256sum, &sig);
 
         if let Some(sig_dir) = &args.signature_dir {
             if let Err(err) = write_sig_to_dir(sig_dir, &pkg.filename, &sig) {
@@ -199,5 +397,23 @@ async fn main() -> Result<()> {
         }
     }
 
+    sign_db_snapshot(&args, &sk, &pk, &db, &repo.db_filename(), &db_sha256).await?;
+    if let Err(err) = publish_proof_archive(&args, &sk, &pk, &archive).await {
+        warn!("Failed to publish proof archive: {:#}", err);
+    }
+
+    let files = match (&args.repo_files, &args.repo_db) {
+        (Some(path), _) => Some(path.clone()),
+        (None, None) => Some(repo.files_url()),
+        (None, Some(_)) => None,
+    };
+    if let Some(files) = files {
+        let files = archlinux::load_db(&client, &files, &limits).await?;
+        let files_sha256 = hex::encode(Sha256::digest(&files));
+        sign_db_snapshot(&args, &sk, &pk, &db, &repo.files_filename(), &files_sha256).await?;
+    } else {
+        warn!("Not signing the files database, --repo-db is set but --repo-files isn't");
+    }
+
     Ok(())
 }
@@ -7,3 +7,16 @@ table! {
         uuid -> Nullable<Text>,
     }
 }
+
+table! {
+    dbs (id) {
+        id -> Integer,
+        sha256sum -> Text,
+        filename -> Text,
+        timestamp -> BigInt,
+        signature -> Text,
+        uuid -> Nullable<Text>,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(dbs, pkgs,);
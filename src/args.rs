@@ -1,15 +1,89 @@
+use crate::bundle::Checkpoint;
+use crate::cache::{self, Cache};
+use crate::daemon;
+use crate::repodb::{self, DbPolicy, StaleAction};
 use clap::ArgAction;
-use pacman_bintrans_common::http::Proxy;
-use std::path::PathBuf;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::http::{self, Proxy, RetryPolicy};
+use pacman_bintrans_common::limits::{self, Limits};
+use pacman_bintrans_common::ui;
+use std::path::{Path, PathBuf};
 use url::Url;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Listen on a unix socket and verify downloads on behalf of XferCommand invocations
+    Daemon,
+    /// Verify all pending upgrades with the hashes in the sync databases, before anything is downloaded
+    Preflight(PreflightArgs),
+    /// Verify package files that were obtained some other way
+    Verify(VerifyArgs),
+    /// Show everything that is known about a package and which policy checks apply to it
+    Inspect(InspectArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct PreflightArgs {
+    /// Directory with the local database of installed packages
+    #[arg(long, default_value = "/var/lib/pacman/local")]
+    pub local_db_dir: PathBuf,
+    /// Repositories to upgrade from in order of priority, defaults to the repositories in --pacman-conf
+    #[arg(long = "repo")]
+    pub repos: Vec<String>,
+    /// The pacman config to read the repositories and their order from
+    #[arg(long, default_value = "/etc/pacman.conf")]
+    pub pacman_conf: PathBuf,
+    /// Mirror to request transparency proofs from if --transparency-url isn't set, `$repo` and `$arch` are substituted
+    #[arg(long)]
+    pub mirror: Option<String>,
+    /// Number of packages to verify concurrently
+    #[arg(long, default_value_t = 8)]
+    pub jobs: usize,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct VerifyArgs {
+    /// Transparency signature or proof bundle to use instead of `<file>.t`, only for a single file
+    #[arg(long)]
+    pub proof: Option<PathBuf>,
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InspectArgs {
+    /// Transparency signature or proof bundle to use instead of `<file>.t`
+    #[arg(long)]
+    pub proof: Option<PathBuf>,
+    /// Path or url of the package
+    pub target: String,
+}
+
 #[derive(Debug, clap::Parser)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Verbose output
     #[arg(short = 'v', global = true, action(ArgAction::Count))]
     pub verbose: u8,
-    #[arg(short = 'O', long)]
-    pub output: PathBuf,
+    /// How to render status output: auto, plain, fancy, json or none
+    #[arg(long, default_value = "auto")]
+    pub ui: ui::Mode,
+    /// Print a json document describing the decision to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+    /// Path to the config file, defaults to /etc/pacman-bintrans.toml if it exists
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    #[arg(short = 'O', long, required = true)]
+    pub output: Option<PathBuf>,
     #[arg(long)]
     pub transparency_url: Option<Url>,
     #[arg(long)]
@@ -20,9 +94,137 @@ pub struct Args {
     /// Only use the proxy for transparency signatures, not the pkg
     #[arg(long)]
     pub bypass_proxy_for_pkgs: bool,
+    /// Use separate socks5 credentials for each purpose, so Tor uses separate circuits
+    #[arg(long)]
+    pub isolate_streams: bool,
     #[arg(long = "rebuilder")]
     pub rebuilders: Vec<Url>,
     #[arg(long, default_value = "0")]
     pub required_rebuild_confirms: usize,
-    pub url: Url,
+    /// Only count rebuilds that are attested to be built from the .BUILDINFO of this package
+    #[arg(long)]
+    pub require_buildinfo_match: bool,
+    /// Verify every file in the package against the embedded .MTREE before accepting it
+    #[arg(long)]
+    pub verify_mtree: bool,
+    /// Directory with the sync databases to cross-check the package sha256 with
+    #[arg(long, default_value = "/var/lib/pacman/sync")]
+    pub sync_db_dir: PathBuf,
+    /// Don't cross-check the package sha256 with the sync databases
+    #[arg(long)]
+    pub skip_sync_db_check: bool,
+    /// Directory to keep the newest accepted database snapshots and package versions in, to detect rollbacks
+    #[arg(long, default_value = "/var/lib/pacman-bintrans")]
+    pub state_dir: PathBuf,
+    /// Maximum age of a repository database snapshot in seconds, 0 disables the check
+    #[arg(long, default_value_t = repodb::DEFAULT_MAX_DB_AGE)]
+    pub max_db_age: u64,
+    /// What to do with database snapshots that are older than --max-db-age
+    #[arg(long, value_enum, default_value_t = StaleAction::Warn)]
+    pub stale_db_action: StaleAction,
+    /// Accept database snapshots that are older than the newest one accepted before
+    #[arg(long)]
+    pub allow_db_downgrade: bool,
+    /// Don't download proof archives, request the proof of every package individually instead
+    #[arg(long)]
+    pub skip_proof_archive: bool,
+    /// Request the proof of packages that aren't listed in any proof archive individually,
+    /// this tells the server which package is installed
+    #[arg(long)]
+    pub allow_individual_proofs: bool,
+    /// Don't use or update the cache of verified proofs and rebuilder verdicts in --state-dir
+    #[arg(long)]
+    pub skip_cache: bool,
+    /// Seconds until a cached negative rebuilder result is queried again
+    #[arg(long, default_value_t = cache::DEFAULT_NEGATIVE_TTL)]
+    pub negative_cache_ttl: u64,
+    /// Don't save the verified transparency proof of a package next to it as `<pkg>.t`
+    #[arg(long)]
+    pub skip_proof_bundle: bool,
+    /// Verify a package that already exists at the output path with its `<pkg>.t`, without network access
+    #[arg(long, requires = "checkpoint")]
+    pub offline: bool,
+    /// Pinned tree head of the transparency log, `<tree size>:<root hash>` as shown by `rekor-cli loginfo`
+    #[arg(long)]
+    pub checkpoint: Option<Checkpoint>,
+    /// Accept packages that are older than the newest version accepted before
+    #[arg(long)]
+    pub allow_pkg_downgrade: bool,
+    /// Number of retries for requests that failed with a transient error, 0 disables retries
+    #[arg(long, default_value_t = http::DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+    /// Total number of retries for all requests of this invocation
+    #[arg(long, default_value_t = http::DEFAULT_RETRY_BUDGET)]
+    pub retry_budget: usize,
+    /// Maximum number of bytes to download for a single file
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_DOWNLOAD_SIZE)]
+    pub max_download_size: u64,
+    /// Maximum number of bytes to decompress while inspecting a package
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_DECOMPRESSED_SIZE)]
+    pub max_decompressed_size: u64,
+    /// Maximum number of tar entries to scan while inspecting a package
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_TAR_ENTRIES)]
+    pub max_tar_entries: usize,
+    /// Maximum size of a single metadata file, eg. `.PKGINFO`, to read into memory
+    #[arg(long, default_value_t = limits::DEFAULT_MAX_ENTRY_SIZE)]
+    pub max_entry_size: u64,
+    /// The unix socket of the daemon, downloads are verified in-process if nothing is listening
+    #[arg(long, global = true, default_value = daemon::DEFAULT_SOCKET)]
+    pub daemon_socket: PathBuf,
+    /// Always verify in-process, even if the daemon is running
+    #[arg(long)]
+    pub no_daemon: bool,
+    #[arg(required = true)]
+    pub url: Option<Url>,
+}
+
+impl Args {
+    /// The url and output path of a XferCommand invocation
+    pub fn transfer(&self) -> Result<(&Url, &Path)> {
+        match (&self.url, &self.output) {
+            (Some(url), Some(output)) => Ok((url, output)),
+            _ => bail!("Both an url and an output path are required"),
+        }
+    }
+
+    /// Resolve relative paths against the working directory of the invoking process
+    pub fn resolve_paths(&mut self, cwd: &Path) {
+        self.config = self.config.as_ref().map(|path| cwd.join(path));
+        self.output = self.output.as_ref().map(|path| cwd.join(path));
+        self.sync_db_dir = cwd.join(&self.sync_db_dir);
+        self.state_dir = cwd.join(&self.state_dir);
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_download_size: self.max_download_size,
+            max_decompressed_size: self.max_decompressed_size,
+            max_tar_entries: self.max_tar_entries,
+            max_entry_size: self.max_entry_size,
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            budget: self.retry_budget,
+            ..Default::default()
+        }
+    }
+
+    pub fn cache(&self) -> Option<Cache> {
+        if self.skip_cache {
+            None
+        } else {
+            Some(Cache::new(&self.state_dir, self.negative_cache_ttl))
+        }
+    }
+
+    pub fn db_policy(&self) -> DbPolicy {
+        DbPolicy {
+            max_age: self.max_db_age,
+            stale_action: self.stale_db_action,
+            allow_downgrade: self.allow_db_downgrade,
+        }
+    }
 }
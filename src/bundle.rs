@@ -0,0 +1,824 @@
+use crate::proof::{self, Proof};
+use crate::proofarchive;
+use crate::state::{self, StoredArchive};
+use base64::prelude::*;
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::http::Client;
+use pacman_bintrans_common::merkle::{self, Hash};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+/// Version of the bundle format, bumped on incompatible changes
+pub const BUNDLE_VERSION: u32 = 1;
+/// The transparency log rekor-cli uses by default
+pub const REKOR_URL: &str = "https://rekor.sigstore.dev";
+const ENTRY_SIZE_LIMIT: u64 = 64 * 1024; // 64K
+
+/// A tree head of the transparency log that is trusted without querying the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub tree_size: u64,
+    pub root_hash: Hash,
+}
+
+impl FromStr for Checkpoint {
+    type Err = Error;
+
+    /// Parse `<tree size>:<root hash>`, as shown by `rekor-cli loginfo`
+    fn from_str(s: &str) -> Result<Checkpoint> {
+        let (tree_size, root_hash) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected `<tree size>:<root hash>`: {:?}", s))?;
+        let tree_size = tree_size
+            .parse()
+            .with_context(|| anyhow!("Invalid tree size: {:?}", tree_size))?;
+        let root_hash = merkle::parse_hash(root_hash)?;
+        Ok(Checkpoint {
+            tree_size,
+            root_hash,
+        })
+    }
+}
+
+/// Proof that the log entry is part of the tree with the given root hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InclusionProof {
+    pub log_index: u64,
+    pub tree_size: u64,
+    pub root_hash: String,
+    pub hashes: Vec<String>,
+}
+
+/// Proof that the tree of the inclusion proof and the pinned checkpoint belong to the same log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsistencyProof {
+    /// The tree size of the pinned checkpoint
+    pub tree_size: u64,
+    pub root_hash: String,
+    pub hashes: Vec<String>,
+}
+
+/// A verified transparency signature with everything needed to verify it again without network access
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bundle {
+    pub version: u32,
+    /// The signature, as published in the `.t` file
+    pub signature: String,
+    pub log_index: Option<u64>,
+    pub log_entry: Option<String>,
+    pub archive: Option<String>,
+    /// The base64 encoded log entry, as returned by rekor
+    pub body: Option<String>,
+    pub inclusion_proof: Option<InclusionProof>,
+    pub consistency_proof: Option<ConsistencyProof>,
+    /// Set if the signature was taken from a proof archive, it has no log entry of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived: Option<Box<ArchivedProof>>,
+}
+
+/// The proof archive a signature was taken from, the archive is in the transparency log instead
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchivedProof {
+    /// The content of the proof archive
+    pub content: String,
+    /// The signature of the proof archive with its log entry
+    pub bundle: Bundle,
+}
+
+impl ArchivedProof {
+    /// Verify the archive lists this signature, and the archive itself is in the transparency log
+    fn verify(
+        &self,
+        pubkey: &PublicKeyBox,
+        filename: &str,
+        sha256: &str,
+        signature: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<()> {
+        let content = self.content.as_bytes();
+        let archive =
+            proofarchive::verify_cached(pubkey, content, self.bundle.signature.as_bytes())?;
+        match archive.get(filename) {
+            Some(entry) if entry.sha256 == sha256 && entry.signature == signature => (),
+            _ => bail!(
+                "Proof archive for {:?} doesn't contain this signature for {:?}",
+                archive.db,
+                filename
+            ),
+        }
+
+        info!("Verifying transparency log entry of proof archive against pinned checkpoint");
+        let archive_sha256 = hex::encode(Sha256::digest(content));
+        self.bundle
+            .verify_log_evidence(&archive_sha256, Some(checkpoint))
+    }
+}
+
+/// A single entry of `GET /api/v1/log/entries/<uuid>`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RekorLogEntry {
+    body: String,
+    integrated_time: Option<i64>,
+    log_index: Option<u64>,
+    verification: Option<RekorVerification>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RekorVerification {
+    inclusion_proof: Option<RekorInclusionProof>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RekorInclusionProof {
+    log_index: u64,
+    tree_size: u64,
+    root_hash: String,
+    hashes: Vec<String>,
+}
+
+/// The response of `GET /api/v1/log/proof`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RekorConsistencyProof {
+    hashes: Vec<String>,
+}
+
+/// The parts of a minisign `rekord` entry that bind it to the signature
+#[derive(Debug, Deserialize)]
+struct Rekord {
+    kind: String,
+    spec: RekordSpec,
+}
+
+#[derive(Debug, Deserialize)]
+struct RekordSpec {
+    data: RekordData,
+    signature: RekordSignature,
+}
+
+#[derive(Debug, Deserialize)]
+struct RekordData {
+    hash: RekordHash,
+}
+
+#[derive(Debug, Deserialize)]
+struct RekordHash {
+    algorithm: String,
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RekordSignature {
+    content: String,
+}
+
+/// Where and when a signature was added to the transparency log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogEntry {
+    pub uuid: String,
+    pub log_index: Option<u64>,
+    /// Unix timestamp of when the entry was added to the log
+    pub integrated_time: Option<i64>,
+    /// The size of the tree the entry was proven to be included in
+    pub tree_size: Option<u64>,
+    pub root_hash: Option<String>,
+}
+
+impl LogEntry {
+    /// Parse a `GET /api/v1/log/entries/<uuid>` response
+    pub fn parse(response: &[u8]) -> Result<LogEntry> {
+        let (uuid, entry) = parse_log_entry(response)?;
+        let inclusion = entry.verification.and_then(|v| v.inclusion_proof);
+        Ok(LogEntry {
+            uuid,
+            log_index: entry.log_index,
+            integrated_time: entry.integrated_time,
+            tree_size: inclusion.as_ref().map(|p| p.tree_size),
+            root_hash: inclusion.map(|p| p.root_hash),
+        })
+    }
+}
+
+fn parse_log_entry(response: &[u8]) -> Result<(String, RekorLogEntry)> {
+    let entries = serde_json::from_slice::<BTreeMap<String, RekorLogEntry>>(response)
+        .context("Failed to parse transparency log entry")?;
+    let mut entries = entries.into_iter();
+    match (entries.next(), entries.next()) {
+        (Some(entry), None) => Ok(entry),
+        _ => bail!("Expected exactly one transparency log entry"),
+    }
+}
+
+async fn download_log_entry(client: &Client, log_entry: &str) -> Result<Vec<u8>> {
+    let url = REKOR_URL.parse::<Url>()?.join(log_entry)?;
+    info!("Downloading transparency log entry from {:?}", url.as_str());
+    client
+        .download_to_mem(url.as_str(), Some(ENTRY_SIZE_LIMIT))
+        .await
+}
+
+/// Download a transparency log entry, `log_entry` is its location as reported by rekor-cli
+pub async fn fetch_log_entry(client: &Client, log_entry: &str) -> Result<LogEntry> {
+    let response = download_log_entry(client, log_entry).await?;
+    LogEntry::parse(&response)
+}
+
+/// The path the bundle of a package is stored at, pacman renames `.part` files after the download
+pub fn path(pkg: &Path) -> PathBuf {
+    let pkg = pkg.as_os_str().to_string_lossy();
+    let pkg = pkg.strip_suffix(".part").unwrap_or(&pkg);
+    PathBuf::from(format!("{}.t", pkg))
+}
+
+fn parse_hashes(hashes: &[String]) -> Result<Vec<Hash>> {
+    hashes.iter().map(|h| merkle::parse_hash(h)).collect()
+}
+
+/// Ensure the log entry is about this signature and artifact
+fn verify_rekord(body: &[u8], sha256: &str, signature: &[u8]) -> Result<()> {
+    let rekord =
+        serde_json::from_slice::<Rekord>(body).context("Failed to parse transparency log entry")?;
+    if rekord.kind != "rekord" {
+        bail!(
+            "Unexpected kind of transparency log entry: {:?}",
+            rekord.kind
+        );
+    }
+
+    // the signed artifact is the sha256 of the package
+    let hash = &rekord.spec.data.hash;
+    let expected = hex::encode(Sha256::digest(sha256.as_bytes()));
+    if hash.algorithm != "sha256" || hash.value != expected {
+        bail!(
+            "Transparency log entry is for artifact {}:{}, expected sha256:{}",
+            hash.algorithm,
+            hash.value,
+            expected
+        );
+    }
+
+    let content = BASE64_STANDARD
+        .decode(&rekord.spec.signature.content)
+        .context("Signature in transparency log entry is not valid base64")?;
+    if content != signature {
+        bail!("Transparency log entry contains a different signature");
+    }
+    Ok(())
+}
+
+impl Bundle {
+    pub fn new(proof: &Proof) -> Bundle {
+        Bundle {
+            version: BUNDLE_VERSION,
+            signature: proof.signature.clone(),
+            log_index: proof.log_index,
+            log_entry: proof.log_entry.clone(),
+            archive: proof.archive.clone(),
+            body: None,
+            inclusion_proof: None,
+            consistency_proof: None,
+            archived: None,
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Bundle> {
+        let bundle =
+            serde_json::from_slice::<Bundle>(bytes).context("Failed to parse proof bundle")?;
+        if bundle.version != BUNDLE_VERSION {
+            bail!("Unsupported proof bundle version: {}", bundle.version);
+        }
+        Ok(bundle)
+    }
+
+    pub fn load(path: &Path) -> Result<Bundle> {
+        let bytes =
+            fs::read(path).with_context(|| anyhow!("Failed to read proof bundle {:?}", path))?;
+        Bundle::parse(&bytes).with_context(|| anyhow!("Invalid proof bundle {:?}", path))
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let mut bytes = serde_json::to_vec(self)?;
+        bytes.push(b'\n');
+        state::write_atomic(path, &bytes)
+            .with_context(|| anyhow!("Failed to write proof bundle to {:?}", path))
+    }
+
+    /// Add the log entry and its inclusion proof from a `GET /api/v1/log/entries/<uuid>` response
+    fn add_log_entry(&mut self, response: &[u8]) -> Result<()> {
+        let (_, entry) = parse_log_entry(response)?;
+        let inclusion = entry
+            .verification
+            .and_then(|v| v.inclusion_proof)
+            .ok_or_else(|| anyhow!("Transparency log entry has no inclusion proof"))?;
+
+        self.body = Some(entry.body);
+        self.inclusion_proof = Some(InclusionProof {
+            log_index: inclusion.log_index,
+            tree_size: inclusion.tree_size,
+            root_hash: inclusion.root_hash,
+            hashes: inclusion.hashes,
+        });
+        Ok(())
+    }
+
+    /// Add a consistency proof between the pinned checkpoint and the tree of the inclusion proof
+    fn add_consistency_proof(&mut self, checkpoint: &Checkpoint, response: &[u8]) -> Result<()> {
+        let proof = serde_json::from_slice::<RekorConsistencyProof>(response)
+            .context("Failed to parse consistency proof")?;
+        self.consistency_proof = Some(ConsistencyProof {
+            tree_size: checkpoint.tree_size,
+            root_hash: hex::encode(checkpoint.root_hash),
+            hashes: proof.hashes,
+        });
+        Ok(())
+    }
+
+    /// Download the log entry and the proofs needed to verify it offline later on
+    pub async fn fetch_evidence(
+        &mut self,
+        client: &Client,
+        sha256: &str,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<()> {
+        let rekor = REKOR_URL.parse::<Url>()?;
+        let log_entry = self
+            .log_entry
+            .as_ref()
+            .ok_or_else(|| anyhow!("Location of the transparency log entry is unknown"))?;
+        let response = download_log_entry(client, log_entry).await?;
+        self.add_log_entry(&response)?;
+
+        if let (Some(checkpoint), Some(inclusion)) = (checkpoint, &self.inclusion_proof) {
+            let (first, last) = if checkpoint.tree_size <= inclusion.tree_size {
+                (checkpoint.tree_size, inclusion.tree_size)
+            } else {
+                (inclusion.tree_size, checkpoint.tree_size)
+            };
+            let response = if first == last {
+                br#"{"hashes":[]}"#.to_vec()
+            } else {
+                let mut url = rekor.join("/api/v1/log/proof")?;
+                url.query_pairs_mut()
+                    .append_pair("firstSize", &first.to_string())
+                    .append_pair("lastSize", &last.to_string());
+                info!("Downloading consistency proof from {:?}", url.as_str());
+                client
+                    .download_to_mem(url.as_str(), Some(ENTRY_SIZE_LIMIT))
+                    .await?
+            };
+            self.add_consistency_proof(checkpoint, &response)?;
+        }
+
+        self.verify_log_evidence(sha256, checkpoint)
+    }
+
+    /// Add the proof archive the signature was taken from, with the evidence of its own log entry
+    pub async fn fetch_archive_evidence(
+        &mut self,
+        client: &Client,
+        archive: &StoredArchive,
+        checkpoint: Option<&Checkpoint>,
+    ) -> Result<()> {
+        let content = String::from_utf8(archive.archive.clone())
+            .context("Proof archive is not valid utf-8")?;
+        let mut bundle = Bundle::new(&Proof {
+            pubkey: String::new(),
+            trusted_comment: String::new(),
+            log_index: None,
+            log_entry: archive.log_entry.clone(),
+            archive: None,
+            signature: String::from_utf8(archive.sig.clone())
+                .context("Proof archive signature is not valid utf-8")?,
+        });
+        let sha256 = hex::encode(Sha256::digest(content.as_bytes()));
+        bundle.fetch_evidence(client, &sha256, checkpoint).await?;
+        self.archived = Some(Box::new(ArchivedProof { content, bundle }));
+        Ok(())
+    }
+
+    /// Verify the log entry is for this signature, and part of a tree consistent with the pinned checkpoint
+    fn verify_log_evidence(&self, sha256: &str, checkpoint: Option<&Checkpoint>) -> Result<()> {
+        let body = self
+            .body
+            .as_ref()
+            .ok_or_else(|| anyhow!("Proof bundle doesn't contain the transparency log entry"))?;
+        let inclusion = self
+            .inclusion_proof
+            .as_ref()
+            .ok_or_else(|| anyhow!("Proof bundle doesn't contain an inclusion proof"))?;
+
+        let body = BASE64_STANDARD
+            .decode(body)
+            .context("Transparency log entry is not valid base64")?;
+        verify_rekord(&body, sha256, self.signature.as_bytes())?;
+
+        let root = merkle::parse_hash(&inclusion.root_hash)?;
+        merkle::verify_inclusion(
+            inclusion.log_index,
+            inclusion.tree_size,
+            &merkle::leaf_hash(&body),
+            &parse_hashes(&inclusion.hashes)?,
+            &root,
+        )
+        .context("Invalid inclusion proof")?;
+
+        let checkpoint = if let Some(checkpoint) = checkpoint {
+            checkpoint
+        } else {
+            return Ok(());
+        };
+        let consistency = self
+            .consistency_proof
+            .as_ref()
+            .ok_or_else(|| anyhow!("Proof bundle doesn't contain a consistency proof"))?;
+        if consistency.tree_size != checkpoint.tree_size
+            || consistency.root_hash != hex::encode(checkpoint.root_hash)
+        {
+            bail!(
+                "Proof bundle was created for checkpoint {}:{}, but {}:{} is pinned",
+                consistency.tree_size,
+                consistency.root_hash,
+                checkpoint.tree_size,
+                hex::encode(checkpoint.root_hash)
+            );
+        }
+
+        let hashes = parse_hashes(&consistency.hashes)?;
+        if checkpoint.tree_size <= inclusion.tree_size {
+            merkle::verify_consistency(
+                checkpoint.tree_size,
+                inclusion.tree_size,
+                &checkpoint.root_hash,
+                &root,
+                &hashes,
+            )
+        } else {
+            merkle::verify_consistency(
+                inclusion.tree_size,
+                checkpoint.tree_size,
+                &root,
+                &checkpoint.root_hash,
+                &hashes,
+            )
+        }
+        .context("Invalid consistency proof")
+    }
+
+    /// The log entry as far as it's known from the bundle, the integration time isn't kept
+    pub fn log_entry_info(&self) -> Option<LogEntry> {
+        let uuid = self.log_entry.as_ref()?.rsplit('/').next()?.to_string();
+        Some(LogEntry {
+            uuid,
+            log_index: self.log_index,
+            integrated_time: None,
+            tree_size: self.inclusion_proof.as_ref().map(|p| p.tree_size),
+            root_hash: self.inclusion_proof.as_ref().map(|p| p.root_hash.clone()),
+        })
+    }
+
+    /// Verify the signature and its log entry without network access
+    pub fn verify(
+        &self,
+        pubkey: &PublicKeyBox,
+        pkg: &[u8],
+        checkpoint: &Checkpoint,
+    ) -> Result<Proof> {
+        let (sha256, trusted_comment) =
+            proof::verify_signature(pubkey, pkg, self.signature.as_bytes())?;
+        if let Some(archived) = &self.archived {
+            archived.verify(
+                pubkey,
+                &trusted_comment,
+                &sha256,
+                &self.signature,
+                checkpoint,
+            )?;
+        } else {
+            info!("Verifying transparency log entry against pinned checkpoint");
+            self.verify_log_evidence(&sha256, Some(checkpoint))?;
+        }
+
+        Ok(Proof {
+            pubkey: pubkey.clone().into_public_key()?.to_base64(),
+            trusted_comment,
+            log_index: self.log_index,
+            log_entry: self.log_entry.clone(),
+            archive: self.archive.clone(),
+            signature: self.signature.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, sign};
+    use pacman_bintrans_common::proofarchive::ProofArchive;
+
+    const PKG: &[u8] = b"rebuilderd-0.18.1-1-x86_64.pkg.tar.zst content";
+    const FILENAME: &str = "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst";
+
+    fn node(left: &Hash, right: &Hash) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([0x01]);
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+
+    /// Add the signature of the bundle as the second entry of a log with two entries
+    fn add_to_log(
+        bundle: &mut Bundle,
+        pubkey: &PublicKeyBox,
+        sha256: &str,
+    ) -> (Checkpoint, Checkpoint) {
+        let body = serde_json::json!({
+            "apiVersion": "0.0.1",
+            "kind": "rekord",
+            "spec": {
+                "data": {
+                    "hash": {
+                        "algorithm": "sha256",
+                        "value": hex::encode(Sha256::digest(sha256.as_bytes())),
+                    },
+                },
+                "signature": {
+                    "content": BASE64_STANDARD.encode(&bundle.signature),
+                    "format": "minisign",
+                    "publicKey": {
+                        "content": BASE64_STANDARD.encode(pubkey.to_string()),
+                    },
+                },
+            },
+        })
+        .to_string();
+
+        let first = merkle::leaf_hash(b"some other entry");
+        let leaf = merkle::leaf_hash(body.as_bytes());
+        let root = node(&first, &leaf);
+        let response = serde_json::json!({
+            "24296fb24b8ad77a": {
+                "body": BASE64_STANDARD.encode(&body),
+                "integratedTime": 1700000000,
+                "logID": "c0d23d6ad406973f",
+                "logIndex": 1146338,
+                "verification": {
+                    "inclusionProof": {
+                        "checkpoint": "rekor.sigstore.dev - 2605736670972794746\n2\n...\n",
+                        "hashes": [hex::encode(first)],
+                        "logIndex": 1,
+                        "rootHash": hex::encode(root),
+                        "treeSize": 2,
+                    },
+                    "signedEntryTimestamp": "MEUCIQ==",
+                },
+            },
+        });
+        bundle
+            .add_log_entry(response.to_string().as_bytes())
+            .unwrap();
+
+        let older = Checkpoint {
+            tree_size: 1,
+            root_hash: first,
+        };
+        bundle
+            .add_consistency_proof(
+                &older,
+                format!(r#"{{"hashes":["{}"]}}"#, hex::encode(leaf)).as_bytes(),
+            )
+            .unwrap();
+        let current = Checkpoint {
+            tree_size: 2,
+            root_hash: root,
+        };
+        (older, current)
+    }
+
+    /// A signed package that is the second entry of a log with two entries
+    fn setup() -> (PublicKeyBox, Bundle, Checkpoint, Checkpoint) {
+        let keypair = testutil::keypair();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        let mut bundle = Bundle::new(&Proof {
+            pubkey: keypair.pk.to_base64(),
+            trusted_comment: FILENAME.to_string(),
+            log_index: Some(1146338),
+            log_entry: Some("/api/v1/log/entries/24296fb24b8ad77a".to_string()),
+            archive: None,
+            signature: sign(&keypair.sk, &sha256, FILENAME),
+        });
+        let (older, current) = add_to_log(&mut bundle, &pubkey, &sha256);
+        (pubkey, bundle, older, current)
+    }
+
+    /// A signed package from a proof archive, the archive is the second entry of a log with two entries
+    fn setup_archived(archive: &mut ProofArchive) -> (PublicKeyBox, Bundle, Checkpoint) {
+        let keypair = testutil::keypair();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        let signature = sign(&keypair.sk, &sha256, FILENAME);
+        if archive.entries.is_empty() {
+            archive.insert(FILENAME, &sha256, &signature);
+        }
+
+        let content = String::from_utf8(archive.to_vec().unwrap()).unwrap();
+        let archive_sha256 = hex::encode(Sha256::digest(content.as_bytes()));
+        let name = ProofArchive::name(&archive.db, &archive.db_sha256);
+        let mut archive_bundle = Bundle::new(&Proof {
+            pubkey: keypair.pk.to_base64(),
+            trusted_comment: name.clone(),
+            log_index: Some(1146338),
+            log_entry: Some("/api/v1/log/entries/24296fb24b8ad77a".to_string()),
+            archive: None,
+            signature: sign(&keypair.sk, &archive_sha256, &name),
+        });
+        let (older, _) = add_to_log(&mut archive_bundle, &pubkey, &archive_sha256);
+
+        let mut bundle = Bundle::new(&Proof {
+            pubkey: keypair.pk.to_base64(),
+            trusted_comment: FILENAME.to_string(),
+            log_index: None,
+            log_entry: None,
+            archive: Some(name),
+            signature,
+        });
+        bundle.archived = Some(Box::new(ArchivedProof {
+            content,
+            bundle: archive_bundle,
+        }));
+        (pubkey, bundle, older)
+    }
+
+    #[test]
+    fn test_parse_log_entry() {
+        let response = serde_json::json!({
+            "24296fb24b8ad77a": {
+                "body": "e30=",
+                "integratedTime": 1700000000,
+                "logIndex": 1146338,
+                "verification": {
+                    "inclusionProof": {
+                        "hashes": [],
+                        "logIndex": 1146338,
+                        "rootHash": "ab".repeat(32),
+                        "treeSize": 1146339,
+                    },
+                },
+            },
+        });
+        let entry = LogEntry::parse(response.to_string().as_bytes()).unwrap();
+        assert_eq!(
+            entry,
+            LogEntry {
+                uuid: "24296fb24b8ad77a".to_string(),
+                log_index: Some(1146338),
+                integrated_time: Some(1700000000),
+                tree_size: Some(1146339),
+                root_hash: Some("ab".repeat(32)),
+            }
+        );
+        assert!(LogEntry::parse(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_parse_checkpoint() {
+        let checkpoint = format!("1146338:{}", "ab".repeat(32))
+            .parse::<Checkpoint>()
+            .unwrap();
+        assert_eq!(
+            checkpoint,
+            Checkpoint {
+                tree_size: 1146338,
+                root_hash: [0xab; 32],
+            }
+        );
+        assert!("1146338".parse::<Checkpoint>().is_err());
+        assert!(format!("x:{}", "ab".repeat(32))
+            .parse::<Checkpoint>()
+            .is_err());
+        assert!("1146338:abcd".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path(Path::new(
+                "/var/cache/pacman/pkg/foo-1-1-any.pkg.tar.zst.part"
+            )),
+            Path::new("/var/cache/pacman/pkg/foo-1-1-any.pkg.tar.zst.t")
+        );
+        assert_eq!(
+            path(Path::new("foo-1-1-any.pkg.tar.zst")),
+            Path::new("foo-1-1-any.pkg.tar.zst.t")
+        );
+    }
+
+    #[test]
+    fn test_verify_offline() {
+        let (pubkey, bundle, older, _) = setup();
+        let bytes = serde_json::to_vec(&bundle).unwrap();
+        let bundle = Bundle::parse(&bytes).unwrap();
+        let proof = bundle.verify(&pubkey, PKG, &older).unwrap();
+        assert_eq!(proof.trusted_comment, FILENAME);
+        assert_eq!(proof.log_index, Some(1146338));
+    }
+
+    #[test]
+    fn test_verify_offline_rejects_other_checkpoint() {
+        let (pubkey, bundle, _, current) = setup();
+        // the bundle was created for a different checkpoint
+        assert!(bundle.verify(&pubkey, PKG, &current).is_err());
+
+        // a forked log
+        let forked = Checkpoint {
+            tree_size: 1,
+            root_hash: merkle::leaf_hash(b"evil"),
+        };
+        let mut bundle = bundle;
+        bundle
+            .add_consistency_proof(&forked, br#"{"hashes":[]}"#)
+            .unwrap();
+        assert!(bundle.verify(&pubkey, PKG, &forked).is_err());
+    }
+
+    #[test]
+    fn test_verify_offline_rejects_tampering() {
+        let (pubkey, bundle, older, _) = setup();
+        assert!(bundle.verify(&pubkey, b"something else", &older).is_err());
+
+        let mut tampered = bundle.clone();
+        tampered.inclusion_proof.as_mut().unwrap().log_index = 0;
+        assert!(tampered.verify(&pubkey, PKG, &older).is_err());
+
+        let mut tampered = bundle.clone();
+        tampered.body = Some(BASE64_STANDARD.encode(b"{}"));
+        assert!(tampered.verify(&pubkey, PKG, &older).is_err());
+
+        let mut incomplete = bundle;
+        incomplete.body = None;
+        assert!(incomplete.verify(&pubkey, PKG, &older).is_err());
+    }
+
+    #[test]
+    fn test_verify_offline_requires_log_entry() {
+        let (pubkey, bundle, older, _) = setup();
+        let signature_only = Bundle::new(&Proof {
+            pubkey: String::new(),
+            trusted_comment: FILENAME.to_string(),
+            log_index: None,
+            log_entry: None,
+            archive: Some("core.db.abcd.proofs".to_string()),
+            signature: bundle.signature,
+        });
+        assert!(signature_only.verify(&pubkey, PKG, &older).is_err());
+    }
+
+    #[test]
+    fn test_verify_offline_archived() {
+        let mut archive = ProofArchive::new("core.db", &"ab".repeat(32));
+        let (pubkey, bundle, older) = setup_archived(&mut archive);
+        let bytes = serde_json::to_vec(&bundle).unwrap();
+        let bundle = Bundle::parse(&bytes).unwrap();
+        let proof = bundle.verify(&pubkey, PKG, &older).unwrap();
+        assert_eq!(proof.trusted_comment, FILENAME);
+        assert_eq!(
+            proof.archive,
+            Some(ProofArchive::name("core.db", &"ab".repeat(32)))
+        );
+
+        assert!(bundle.verify(&pubkey, b"something else", &older).is_err());
+
+        // the archive was modified after it was signed
+        let mut tampered = bundle.clone();
+        tampered.archived.as_mut().unwrap().content.insert(0, ' ');
+        assert!(tampered.verify(&pubkey, PKG, &older).is_err());
+
+        // the log entry is about the package, not the archive
+        let mut tampered = bundle;
+        tampered.archived.as_mut().unwrap().bundle.body = None;
+        assert!(tampered.verify(&pubkey, PKG, &older).is_err());
+    }
+
+    #[test]
+    fn test_verify_offline_archived_requires_entry() {
+        // the archive lists a different build of the package
+        let mut archive = ProofArchive::new("core.db", &"ab".repeat(32));
+        archive.insert(FILENAME, &"cd".repeat(32), "untrusted comment: ...");
+        let (pubkey, bundle, older) = setup_archived(&mut archive);
+        assert!(bundle.verify(&pubkey, PKG, &older).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::decision::RebuildStatus;
+use crate::proof::{self, Proof};
+use crate::state;
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Six hours, rebuilders usually catch up with new packages within a day
+pub const DEFAULT_NEGATIVE_TTL: u64 = 6 * 60 * 60;
+
+/// A verified transparency signature, the signature is checked again when loaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedProof {
+    signature: String,
+    log_index: Option<u64>,
+    log_entry: Option<String>,
+    archive: Option<String>,
+}
+
+/// A rebuilder that didn't reproduce the package yet
+///
+/// Attestation signatures aren't verified, so anybody who can write to the
+/// cache could claim a rebuild. Only negative results are cached for this reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedVerdict {
+    reproduced: bool,
+    /// Unix timestamp of the query, the result expires
+    checked_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    proof: Option<CachedProof>,
+    /// Verdicts by rebuilder url
+    #[serde(default)]
+    rebuilders: BTreeMap<String, CachedVerdict>,
+}
+
+/// Results of network checks, by sha256 of the package
+pub struct Cache {
+    dir: PathBuf,
+    /// Seconds until a negative rebuilder result is queried again
+    negative_ttl: u64,
+}
+
+impl Cache {
+    pub fn new(state_dir: &Path, negative_ttl: u64) -> Cache {
+        Cache {
+            dir: state_dir.join("cache"),
+            negative_ttl,
+        }
+    }
+
+    fn path(&self, sha256: &str) -> Result<PathBuf> {
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid sha256 for cache entry: {:?}", sha256);
+        }
+        Ok(self.dir.join(sha256))
+    }
+
+    fn load(&self, sha256: &str) -> Result<Entry> {
+        let path = self.path(sha256)?;
+        if !path.exists() {
+            return Ok(Entry::default());
+        }
+        let content =
+            fs::read(&path).with_context(|| anyhow!("Failed to read cache entry {:?}", path))?;
+        match serde_json::from_slice(&content) {
+            Ok(entry) => Ok(entry),
+            Err(err) => {
+                warn!("Ignoring invalid cache entry {:?}: {:#}", path, err);
+                Ok(Entry::default())
+            }
+        }
+    }
+
+    fn store(&self, sha256: &str, entry: &Entry) -> Result<()> {
+        let path = self.path(sha256)?;
+        let content = serde_json::to_vec(entry)?;
+        state::write_atomic(&path, &content)
+            .with_context(|| anyhow!("Failed to write cache entry {:?}", path))
+    }
+
+    /// Load the transparency signature of a package, if it's still valid for the given public key
+    pub fn load_proof(
+        &self,
+        pubkey: &PublicKeyBox,
+        pkg: &[u8],
+        sha256: &str,
+    ) -> Result<Option<Proof>> {
+        self.path(sha256)?;
+        if hex::encode(Sha256::digest(pkg)) != sha256 {
+            warn!(
+                "Package doesn't match sha256 {}, ignoring cached proof",
+                sha256
+            );
+            return Ok(None);
+        }
+        self.load_proof_sha256(pubkey, sha256)
+    }
+
+    /// Load the transparency signature of a package that wasn't downloaded yet
+    pub fn load_proof_sha256(&self, pubkey: &PublicKeyBox, sha256: &str) -> Result<Option<Proof>> {
+        let cached = if let Some(cached) = self.load(sha256)?.proof {
+            cached
+        } else {
+            return Ok(None);
+        };
+
+        let trusted_comment =
+            match proof::verify_signature_sha256(pubkey, sha256, cached.signature.as_bytes()) {
+                Ok(trusted_comment) => trusted_comment,
+                Err(err) => {
+                    warn!("Ignoring cached proof that failed to verify: {:#}", err);
+                    return Ok(None);
+                }
+            };
+
+        Ok(Some(Proof {
+            pubkey: pubkey.clone().into_public_key()?.to_base64(),
+            trusted_comment,
+            log_index: cached.log_index,
+            log_entry: cached.log_entry,
+            archive: cached.archive,
+            signature: cached.signature,
+        }))
+    }
+
+    pub fn store_proof(&self, sha256: &str, proof: &Proof) -> Result<()> {
+        let mut entry = self.load(sha256)?;
+        entry.proof = Some(CachedProof {
+            signature: proof.signature.clone(),
+            log_index: proof.log_index,
+            log_entry: proof.log_entry.clone(),
+            archive: proof.archive.clone(),
+        });
+        self.store(sha256, &entry)
+    }
+
+    /// Load a negative verdict of a rebuilder that didn't expire yet
+    pub fn load_verdict(
+        &self,
+        sha256: &str,
+        rebuilder: &Url,
+        now: u64,
+    ) -> Result<Option<RebuildStatus>> {
+        let entry = self.load(sha256)?;
+        let verdict = if let Some(verdict) = entry.rebuilders.get(rebuilder.as_str()) {
+            verdict
+        } else {
+            return Ok(None);
+        };
+
+        if verdict.reproduced {
+            debug!(
+                "Ignoring cached rebuild of {:?}, only negative verdicts are cached",
+                rebuilder.as_str()
+            );
+            return Ok(None);
+        }
+
+        if verdict.checked_at.saturating_add(self.negative_ttl) <= now {
+            debug!(
+                "Cached verdict of {:?} has expired, querying again",
+                rebuilder.as_str()
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(RebuildStatus::NotReproduced))
+    }
+
+    /// Remember the verdict of a rebuilder, only negative results are cached
+    pub fn store_verdict(
+        &self,
+        sha256: &str,
+        rebuilder: &Url,
+        status: &RebuildStatus,
+        now: u64,
+    ) -> Result<()> {
+        if *status != RebuildStatus::NotReproduced {
+            return Ok(());
+        }
+        let verdict = CachedVerdict {
+            reproduced: false,
+            checked_at: now,
+        };
+
+        let mut entry = self.load(sha256)?;
+        entry
+            .rebuilders
+            .insert(rebuilder.as_str().to_string(), verdict);
+        self.store(sha256, &entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decision::BuildinfoBinding;
+    use minisign::KeyPair;
+    use sha2::{Digest, Sha256};
+    use std::io::Cursor;
+
+    const PKG: &[u8] = b"rebuilderd-0.18.1-1-x86_64.pkg.tar.zst content";
+    fn rebuilder() -> Url {
+        "https://reproducible.archlinux.org/".parse().unwrap()
+    }
+
+    #[test]
+    fn test_proof_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), DEFAULT_NEGATIVE_TTL);
+        let keypair = KeyPair::generate_unencrypted_keypair().unwrap();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        assert_eq!(cache.load_proof(&pubkey, PKG, &sha256).unwrap(), None);
+
+        let sig = minisign::sign(
+            None,
+            &keypair.sk,
+            Cursor::new(&sha256),
+            Some("rebuilderd-0.18.1-1-x86_64.pkg.tar.zst"),
+            None,
+        )
+        .unwrap();
+        let proof = Proof {
+            pubkey: keypair.pk.to_base64(),
+            trusted_comment: "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst".to_string(),
+            log_index: Some(1146338),
+            log_entry: None,
+            archive: None,
+            signature: sig.to_string(),
+        };
+        cache.store_proof(&sha256, &proof).unwrap();
+        assert_eq!(
+            cache.load_proof(&pubkey, PKG, &sha256).unwrap(),
+            Some(proof)
+        );
+
+        // a different key or a different file don't match
+        let other = KeyPair::generate_unencrypted_keypair().unwrap();
+        let other = other.pk.to_box().unwrap();
+        assert_eq!(cache.load_proof(&other, PKG, &sha256).unwrap(), None);
+        assert_eq!(
+            cache
+                .load_proof(&pubkey, b"something else", &sha256)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_proof_tampered() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), DEFAULT_NEGATIVE_TTL);
+        let keypair = KeyPair::generate_unencrypted_keypair().unwrap();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+
+        // signed with a key we don't trust
+        let other = KeyPair::generate_unencrypted_keypair().unwrap();
+        let sig = minisign::sign(None, &other.sk, Cursor::new(&sha256), None, None).unwrap();
+        let entry = serde_json::json!({
+            "proof": {"signature": sig.to_string(), "log_index": null, "log_entry": null, "archive": null},
+        });
+        fs::create_dir_all(dir.path().join("cache")).unwrap();
+        fs::write(dir.path().join("cache").join(&sha256), entry.to_string()).unwrap();
+        assert_eq!(cache.load_proof(&pubkey, PKG, &sha256).unwrap(), None);
+
+        fs::write(dir.path().join("cache").join(&sha256), "garbage").unwrap();
+        assert_eq!(cache.load_proof(&pubkey, PKG, &sha256).unwrap(), None);
+        assert!(cache.load_proof(&pubkey, PKG, "../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_verdict_positive_is_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), 60);
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        for status in [
+            RebuildStatus::Reproduced(BuildinfoBinding::Unknown),
+            RebuildStatus::Reproduced(BuildinfoBinding::Matches),
+            RebuildStatus::Error("timeout".to_string()),
+        ] {
+            cache
+                .store_verdict(&sha256, &rebuilder(), &status, 1000)
+                .unwrap();
+            assert_eq!(
+                cache.load_verdict(&sha256, &rebuilder(), 1000).unwrap(),
+                None
+            );
+        }
+
+        // editing the file to claim a rebuild has no effect
+        let entry = serde_json::json!({
+            "rebuilders": {
+                rebuilder().as_str(): {"reproduced": true, "checked_at": 1000},
+            },
+        });
+        fs::create_dir_all(dir.path().join("cache")).unwrap();
+        fs::write(dir.path().join("cache").join(&sha256), entry.to_string()).unwrap();
+        assert_eq!(
+            cache.load_verdict(&sha256, &rebuilder(), 1000).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_verdict_negative_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), 60);
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        cache
+            .store_verdict(&sha256, &rebuilder(), &RebuildStatus::NotReproduced, 1000)
+            .unwrap();
+        assert_eq!(
+            cache.load_verdict(&sha256, &rebuilder(), 1059).unwrap(),
+            Some(RebuildStatus::NotReproduced)
+        );
+        assert_eq!(
+            cache.load_verdict(&sha256, &rebuilder(), 1060).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::tls::TlsConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The config file that is loaded if `--config` isn't set, it's fine if it doesn't exist
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/pacman-bintrans.toml";
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// TLS settings, keyed by hostname
+    #[serde(default)]
+    pub tls: BTreeMap<String, TlsConfig>,
+}
+
+/// Which proxy to use for each purpose, either a proxy url or `direct`
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Used for every purpose without a more specific rule, `--proxy` takes precedence
+    pub default: Option<String>,
+    /// Packages and databases from the mirror
+    pub packages: Option<String>,
+    /// Transparency signatures from `--transparency-url`
+    pub proofs: Option<String>,
+    /// The transparency log, queried by rekor-cli
+    pub log: Option<String>,
+    pub rebuilders: Option<String>,
+    /// Hosts that are always connected to directly, see `NO_PROXY` in curl(1)
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    /// Use separate socks5 credentials for each purpose and invocation
+    #[serde(default)]
+    pub isolate_streams: bool,
+}
+
+impl Config {
+    pub fn parse(content: &str) -> Result<Config> {
+        let config = toml::from_str(content)?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path)
+            .with_context(|| anyhow!("Failed to read config file {:?}", path))?;
+        Config::parse(&content).with_context(|| anyhow!("Failed to parse config file {:?}", path))
+    }
+
+    /// Load the given config file, or the default one if it exists
+    pub fn load_or_default(path: Option<&Path>) -> Result<Config> {
+        if let Some(path) = path {
+            return Config::load(path);
+        }
+
+        let path = Path::new(DEFAULT_CONFIG_PATH);
+        match fs::metadata(path) {
+            Ok(_) => Config::load(path),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => {
+                Err(err).with_context(|| anyhow!("Failed to access config file {:?}", path))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pacman_bintrans_common::tls::TlsVersion;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+[tls."pacman-bintrans.vulns.xyz"]
+pins = ["sha256/1zwWNzmuBauRhVc/jmLVSQoLnRNTiJReCe/u/fegOJs="]
+min_tls_version = "1.2"
+
+[tls."rebuilder.example.com"]
+ca_bundle = "/etc/pacman-bintrans/rebuilder-ca.pem"
+client_cert = "/etc/pacman-bintrans/client.pem"
+client_key = "/etc/pacman-bintrans/client.key"
+"#,
+        )
+        .unwrap();
+
+        let mut tls = BTreeMap::new();
+        tls.insert(
+            "pacman-bintrans.vulns.xyz".to_string(),
+            TlsConfig {
+                pins: vec!["sha256/1zwWNzmuBauRhVc/jmLVSQoLnRNTiJReCe/u/fegOJs=".to_string()],
+                min_tls_version: Some(TlsVersion::Tls12),
+                ..Default::default()
+            },
+        );
+        tls.insert(
+            "rebuilder.example.com".to_string(),
+            TlsConfig {
+                ca_bundle: Some("/etc/pacman-bintrans/rebuilder-ca.pem".into()),
+                client_cert: Some("/etc/pacman-bintrans/client.pem".into()),
+                client_key: Some("/etc/pacman-bintrans/client.key".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            config,
+            Config {
+                tls,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_proxy_config() {
+        let config = Config::parse(
+            r#"
+[proxy]
+default = "socks5h://127.0.0.1:9050"
+packages = "direct"
+no_proxy = ["mirror.lan", "10.0.0.0/8"]
+isolate_streams = true
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.proxy,
+            ProxyConfig {
+                default: Some("socks5h://127.0.0.1:9050".to_string()),
+                packages: Some("direct".to_string()),
+                no_proxy: vec!["mirror.lan".to_string(), "10.0.0.0/8".to_string()],
+                isolate_streams: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_reject_unknown_keys() {
+        assert!(Config::parse("[tls.\"example.com\"]\npin = []\n").is_err());
+        assert!(Config::parse("min_tls_version = \"1.2\"\n").is_err());
+        assert!(Config::parse("[proxy]\nsignatures = \"direct\"\n").is_err());
+        assert!(Config::parse("[tls.\"example.com\"]\nmin_tls_version = \"1.4\"\n").is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::args::Args;
+use crate::config::{self, Config};
+use crate::decision::Decision;
+use crate::routing::{Clients, Routes};
+use crate::xfer;
+use clap::Parser;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::ui;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+pub const DEFAULT_SOCKET: &str = "/run/pacman-bintrans.sock";
+const REQUEST_SIZE_LIMIT: u64 = 64 * 1024; // 64K
+const RESPONSE_SIZE_LIMIT: u64 = 16 * 1024 * 1024; // 16M
+
+/// A XferCommand invocation that is handed off to the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+    /// The command line arguments, without the program name
+    pub args: Vec<String>,
+    /// The working directory of the invocation, relative paths are resolved against it
+    pub cwd: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// The decision about packages and databases, as printed by `--output-format json`
+    pub decision: Option<serde_json::Value>,
+    /// Set if the download was rejected or failed
+    pub error: Option<String>,
+}
+
+/// The settings the http clients are created from, requests with the same settings share their clients
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct NetworkKey {
+    config: Option<PathBuf>,
+    /// Size and modification time of the config file, edits to it result in new clients
+    config_version: Option<(u64, i64, i64)>,
+    proxy: Option<String>,
+    bypass_proxy_for_pkgs: bool,
+    isolate_streams: bool,
+    max_retries: u32,
+    retry_budget: usize,
+}
+
+impl NetworkKey {
+    fn new(args: &Args) -> NetworkKey {
+        let path = args
+            .config
+            .as_deref()
+            .unwrap_or_else(|| Path::new(config::DEFAULT_CONFIG_PATH));
+        let config_version = fs::metadata(path)
+            .ok()
+            .map(|md| (md.len(), md.mtime(), md.mtime_nsec()));
+        NetworkKey {
+            config: args.config.clone(),
+            config_version,
+            proxy: args.proxy.as_ref().map(|proxy| proxy.as_text().to_string()),
+            bypass_proxy_for_pkgs: args.bypass_proxy_for_pkgs,
+            isolate_streams: args.isolate_streams,
+            max_retries: args.max_retries,
+            retry_budget: args.retry_budget,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Daemon {
+    clients: Mutex<HashMap<NetworkKey, Clients>>,
+}
+
+impl Daemon {
+    /// Get the clients for these settings, they're created on first use
+    ///
+    /// With stream isolation every request gets new clients for its proxied routes.
+    fn clients(&self, args: &Args) -> Result<Clients> {
+        let key = NetworkKey::new(args);
+        let config = Config::load_or_default(args.config.as_deref())?;
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|_| anyhow!("Client pool lock is poisoned"))?;
+        let shared = if let Some(clients) = clients.get(&key) {
+            clients
+        } else {
+            // clients of an older version of the config file are never used again
+            clients.retain(|old, _| {
+                old.config != key.config || old.config_version == key.config_version
+            });
+            debug!("Creating http clients for {:?}", key);
+            let routes = Routes::from_args(args, &config)?;
+            let created = Clients::new(routes, &config, args.retry_policy())?;
+            clients.entry(key).or_insert(created)
+        };
+
+        if Routes::isolate_streams(args, &config) {
+            let routes = Routes::from_args(args, &config)?;
+            shared.isolate(routes, &config, args.retry_policy())
+        } else {
+            Ok(shared.share())
+        }
+    }
+
+    async fn verify(&self, request: Request, decision: &mut Option<Decision>) -> Result<()> {
+        let mut args = Args::try_parse_from(
+            std::iter::once("pacman-bintrans".to_string()).chain(request.args),
+        )?;
+        if args.command.is_some() {
+            bail!("Subcommands can't be handed off to the daemon");
+        }
+        args.resolve_paths(&request.cwd);
+        let (url, output) = args.transfer()?;
+        info!("Verifying {:?} for {:?}", url.as_str(), output);
+
+        let clients = self.clients(&args)?;
+        let mut ui = ui::new(ui::Mode::None, false);
+        xfer::run(&args, &clients, ui.as_mut(), decision).await
+    }
+
+    async fn process(&self, request: Request) -> Result<Response> {
+        let mut decision = None;
+        let result = self.verify(request, &mut decision).await;
+        if let Err(err) = &result {
+            warn!("Request failed: {:#}", err);
+        }
+        Ok(Response {
+            decision: decision.map(serde_json::to_value).transpose()?,
+            error: result.err().map(|err| format!("{:#}", err)),
+        })
+    }
+
+    async fn handle(&self, stream: UnixStream, uid: u32) -> Result<()> {
+        let peer = stream.peer_cred()?;
+        if peer.uid() != uid {
+            bail!("Rejecting connection from uid {}", peer.uid());
+        }
+
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(reader.take(REQUEST_SIZE_LIMIT))
+            .read_line(&mut line)
+            .await?;
+        let request = serde_json::from_str::<Request>(&line).context("Invalid request")?;
+
+        let response = self.process(request).await?;
+        let mut bytes = serde_json::to_vec(&response)?;
+        bytes.push(b'\n');
+        writer.write_all(&bytes).await?;
+        Ok(())
+    }
+}
+
+/// Create the socket, an existing socket is only replaced if nothing is listening on it anymore
+async fn bind(path: &Path) -> Result<UnixListener> {
+    if let Ok(md) = fs::symlink_metadata(path) {
+        if !md.file_type().is_socket() {
+            bail!("Refusing to replace {:?}, it's not a socket", path);
+        }
+        if UnixStream::connect(path).await.is_ok() {
+            bail!("Daemon is already listening on {:?}", path);
+        }
+        debug!("Removing stale socket {:?}", path);
+        fs::remove_file(path)
+            .with_context(|| anyhow!("Failed to remove stale socket {:?}", path))?;
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| anyhow!("Failed to bind to {:?}", path))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| anyhow!("Failed to set permissions of {:?}", path))?;
+    Ok(listener)
+}
+
+/// Verify downloads for XferCommand invocations of the same user, until the process is stopped
+pub async fn serve(path: &Path) -> Result<()> {
+    let listener = bind(path).await?;
+    let uid = fs::metadata(path)?.uid();
+    info!("Listening on {:?}", path);
+
+    let daemon = Arc::new(Daemon::default());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            if let Err(err) = daemon.handle(stream, uid).await {
+                warn!("Failed to handle connection: {:#}", err);
+            }
+        });
+    }
+}
+
+/// Only a daemon of our own user or root may decide about our downloads
+fn is_trusted_peer(peer: u32, uid: u32) -> bool {
+    peer == uid || peer == 0
+}
+
+/// Hand a XferCommand invocation off to the daemon, returns `None` if no daemon is listening
+pub async fn handoff(path: &Path, request: &Request) -> Result<Option<Response>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            debug!("Daemon isn't reachable at {:?}: {:#}", path, err);
+            return Ok(None);
+        }
+    };
+
+    let peer = stream.peer_cred()?;
+    let uid = unsafe { libc::getuid() };
+    if !is_trusted_peer(peer.uid(), uid) {
+        bail!(
+            "Refusing to trust daemon at {:?}, it's running as uid {}",
+            path,
+            peer.uid()
+        );
+    }
+    info!("Handing off to daemon at {:?}", path);
+
+    let (reader, mut writer) = stream.into_split();
+    let mut bytes = serde_json::to_vec(request)?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+
+    let mut line = String::new();
+    BufReader::new(reader.take(RESPONSE_SIZE_LIMIT))
+        .read_line(&mut line)
+        .await?;
+    if line.is_empty() {
+        bail!("Daemon closed the connection without a response");
+    }
+    let response = serde_json::from_str(&line).context("Invalid response from daemon")?;
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn start(dir: &Path) -> PathBuf {
+        let socket = dir.join("daemon.sock");
+        let path = socket.clone();
+        tokio::spawn(async move { serve(&path).await });
+        for _ in 0..100 {
+            if socket.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        socket
+    }
+
+    fn request(dir: &Path, args: &[&str]) -> Request {
+        Request {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            cwd: dir.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        assert!(is_trusted_peer(1000, 1000));
+        assert!(is_trusted_peer(0, 1000));
+        assert!(!is_trusted_peer(1001, 1000));
+        assert!(is_trusted_peer(0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = start(dir.path()).await;
+
+        let src = dir.path().join("src.txt");
+        fs::write(&src, b"ohai").unwrap();
+        let url = url::Url::from_file_path(&src).unwrap();
+        let response = handoff(
+            &socket,
+            &request(dir.path(), &["-O", "out.txt", url.as_str()]),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            response,
+            Response {
+                decision: None,
+                error: None,
+            }
+        );
+        // the output path is relative to the invocation
+        assert_eq!(fs::read(dir.path().join("out.txt")).unwrap(), b"ohai");
+
+        let missing = url::Url::from_file_path(dir.path().join("missing.txt")).unwrap();
+        let response = handoff(
+            &socket,
+            &request(dir.path(), &["-O", "out.txt", missing.as_str()]),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(response
+            .error
+            .unwrap()
+            .contains("Failed to copy from file://"));
+
+        let response = handoff(&socket, &request(dir.path(), &["daemon"]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handoff_without_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("daemon.sock");
+        let response = handoff(
+            &socket,
+            &request(dir.path(), &["-O", "out.txt", "file:///x"]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response, None);
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("daemon.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        let listener = bind(&socket).await.unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // the socket is in use now
+        assert!(bind(&socket).await.is_err());
+        drop(listener);
+
+        let file = dir.path().join("file");
+        fs::write(&file, b"").unwrap();
+        assert!(bind(&file).await.is_err());
+    }
+
+    #[test]
+    fn test_isolated_clients() {
+        let daemon = Daemon::default();
+        let parse = |extra: &[&str]| {
+            let mut args = vec![
+                "pacman-bintrans",
+                "--proxy",
+                "socks5h://127.0.0.1:9050",
+                "--bypass-proxy-for-pkgs",
+            ];
+            args.extend(extra);
+            args.extend(["-O", "out", "https://example.com/core.db"]);
+            Args::try_parse_from(args).unwrap()
+        };
+        let log_proxy = |args: &Args| {
+            let clients = daemon.clients(args).unwrap();
+            clients.log_proxy.unwrap().as_text().to_string()
+        };
+
+        let args = parse(&["--isolate-streams"]);
+        let first = log_proxy(&args);
+        let second = log_proxy(&args);
+        assert!(first.starts_with("socks5://log:"), "{}", first);
+        assert_ne!(first, second);
+
+        let args = parse(&[]);
+        assert_eq!(log_proxy(&args), log_proxy(&args));
+    }
+
+    #[test]
+    fn test_config_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.toml");
+        let daemon = Daemon::default();
+        let args = Args::try_parse_from([
+            "pacman-bintrans",
+            "--config",
+            config.to_str().unwrap(),
+            "-O",
+            "out",
+            "https://example.com/core.db",
+        ])
+        .unwrap();
+
+        fs::write(&config, "[proxy]\n").unwrap();
+        assert!(daemon.clients(&args).unwrap().log_proxy.is_none());
+
+        fs::write(&config, "[proxy]\nlog = \"socks5h://127.0.0.1:9050\"\n").unwrap();
+        assert!(daemon.clients(&args).unwrap().log_proxy.is_some());
+        assert_eq!(daemon.clients.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = Args::try_parse_from(["pacman-bintrans", "daemon"]).unwrap();
+        assert!(args.command.is_some());
+        assert!(args.transfer().is_err());
+
+        let args = Args::try_parse_from([
+            "pacman-bintrans",
+            "-O",
+            "out",
+            "https://example.com/core.db",
+        ])
+        .unwrap();
+        assert!(args.command.is_none());
+        assert!(args.transfer().is_ok());
+
+        assert!(Args::try_parse_from(["pacman-bintrans", "https://example.com/core.db"]).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::proof::Proof;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::mtree::Mismatch;
+use serde::{Serialize, Serializer};
+use url::Url;
+
+/// Version of the json document written by `--output-format json`, bumped on incompatible changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How a rebuild result relates to the .BUILDINFO of the package we're verifying
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildinfoBinding {
+    /// The rebuilder attested it rebuilt from our exact .BUILDINFO
+    Matches,
+    /// The rebuilder didn't tell us which .BUILDINFO it used
+    Unknown,
+    /// The rebuilder attested a different .BUILDINFO or artifact
+    Mismatch { reported: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", content = "detail", rename_all = "snake_case")]
+pub enum RebuildStatus {
+    Reproduced(BuildinfoBinding),
+    NotReproduced,
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RebuilderResult {
+    pub rebuilder: Url,
+    pub status: RebuildStatus,
+    /// How long it took to query the rebuilder, in milliseconds
+    pub duration_ms: u64,
+}
+
+impl RebuilderResult {
+    /// Returns true if this result counts towards the required rebuild confirms
+    pub fn is_confirm(&self, require_buildinfo_match: bool) -> bool {
+        match &self.status {
+            RebuildStatus::Reproduced(BuildinfoBinding::Matches) => true,
+            RebuildStatus::Reproduced(BuildinfoBinding::Unknown) => !require_buildinfo_match,
+            _ => false,
+        }
+    }
+}
+
+/// An entry of a pacman sync database that lists the package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SyncDbEntry {
+    pub repo: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    #[default]
+    Package,
+    Database,
+}
+
+/// The outcome of a single policy check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    /// No verdict has been reached yet
+    #[default]
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+/// Everything that was learned about a package while deciding if it's accepted
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Decision {
+    pub schema_version: u32,
+    pub kind: Kind,
+    pub url: String,
+    /// The size of the downloaded file in bytes
+    pub size: Option<usize>,
+    pub sha256: Option<String>,
+    /// Entries of the local sync databases for this filename, if the check was enabled
+    pub syncdb: Option<Vec<SyncDbEntry>>,
+    /// The verified transparency signature, the trusted comment is the filename it was issued for
+    pub proof: Option<Proof>,
+    /// Disagreements between the file, the sync databases and the transparency signature
+    pub inconsistencies: Vec<String>,
+    pub pkgname: Option<String>,
+    pub pkgver: Option<String>,
+    pub buildinfo_sha256: Option<String>,
+    pub pkgbuild_sha256sum: Option<String>,
+    pub rebuilders: Vec<RebuilderResult>,
+    /// Differences between the package and its .MTREE, if the check was enabled
+    #[serde(serialize_with = "serialize_mismatches")]
+    pub mtree_mismatches: Option<Vec<Mismatch>>,
+    pub policy: Vec<PolicyCheck>,
+    pub verdict: Verdict,
+    /// The reason the file was rejected
+    pub error: Option<String>,
+}
+
+fn serialize_mismatches<S: Serializer>(
+    mismatches: &Option<Vec<Mismatch>>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    mismatches
+        .as_ref()
+        .map(|mismatches| mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>())
+        .serialize(s)
+}
+
+impl Decision {
+    pub fn new(url: &Url, kind: Kind) -> Decision {
+        Decision {
+            schema_version: SCHEMA_VERSION,
+            kind,
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Record the outcome of a policy check
+    pub fn check(&mut self, name: &str, passed: bool, detail: Option<String>) {
+        self.policy.push(PolicyCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+        });
+    }
+
+    /// Record the outcome of a policy check that is expressed as result, then pass it on
+    pub fn evaluate<T>(&mut self, name: &str, result: Result<T>) -> Result<T> {
+        match &result {
+            Ok(_) => self.check(name, true, None),
+            Err(err) => self.check(name, false, Some(format!("{:#}", err))),
+        }
+        result
+    }
+
+    /// Set the verdict from the result of the verification
+    pub fn conclude(&mut self, result: &Result<()>) {
+        match result {
+            Ok(()) => self.verdict = Verdict::Accepted,
+            Err(err) => {
+                self.verdict = Verdict::Rejected;
+                self.error = Some(format!("{:#}", err));
+            }
+        }
+    }
+
+    pub fn rebuild_confirms(&self, require_buildinfo_match: bool) -> usize {
+        self.rebuilders
+            .iter()
+            .filter(|r| r.is_confirm(require_buildinfo_match))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(status: RebuildStatus) -> RebuilderResult {
+        RebuilderResult {
+            rebuilder: "https://reproducible.archlinux.org/".parse().unwrap(),
+            status,
+            duration_ms: 250,
+        }
+    }
+
+    #[test]
+    fn test_rebuild_confirms() {
+        let decision = Decision {
+            rebuilders: vec![
+                result(RebuildStatus::Reproduced(BuildinfoBinding::Matches)),
+                result(RebuildStatus::Reproduced(BuildinfoBinding::Unknown)),
+                result(RebuildStatus::Reproduced(BuildinfoBinding::Mismatch {
+                    reported: vec!["ab".repeat(32)],
+                })),
+                result(RebuildStatus::NotReproduced),
+                result(RebuildStatus::Error("timeout".to_string())),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(decision.rebuild_confirms(false), 2);
+        assert_eq!(decision.rebuild_confirms(true), 1);
+    }
+
+    #[test]
+    fn test_serialize_decision() {
+        let url = "https://geo.mirror.pkgbuild.com/extra/os/x86_64/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst"
+            .parse()
+            .unwrap();
+        let mut decision = Decision::new(&url, Kind::Package);
+        decision.size = Some(1024);
+        decision.sha256 = Some("ab".repeat(32));
+        decision.rebuilders = vec![
+            result(RebuildStatus::Reproduced(BuildinfoBinding::Matches)),
+            result(RebuildStatus::Error("timeout".to_string())),
+        ];
+        decision.check(
+            "rebuild_confirms",
+            false,
+            Some("got 1, required 2".to_string()),
+        );
+        let result = decision.evaluate("downgrade", Ok(()));
+        decision.conclude(&result);
+
+        let json = serde_json::to_value(&decision).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["kind"], "package");
+        assert_eq!(json["size"], 1024);
+        assert_eq!(json["proof"], serde_json::Value::Null);
+        assert_eq!(
+            json["rebuilders"][0]["status"],
+            serde_json::json!({"result": "reproduced", "detail": "matches"})
+        );
+        assert_eq!(
+            json["rebuilders"][1]["status"],
+            serde_json::json!({"result": "error", "detail": "timeout"})
+        );
+        assert_eq!(json["rebuilders"][1]["duration_ms"], 250);
+        assert_eq!(
+            json["policy"],
+            serde_json::json!([
+                {"name": "rebuild_confirms", "passed": false, "detail": "got 1, required 2"},
+                {"name": "downgrade", "passed": true, "detail": null},
+            ])
+        );
+        assert_eq!(json["verdict"], "accepted");
+        assert_eq!(json["error"], serde_json::Value::Null);
+
+        decision.conclude(&Err(anyhow!("Not enough rebuild confirms")));
+        let json = serde_json::to_value(&decision).unwrap();
+        assert_eq!(json["verdict"], "rejected");
+        assert_eq!(json["error"], "Not enough rebuild confirms");
+    }
+}
@@ -0,0 +1,58 @@
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::version::vercmp;
+use std::cmp::Ordering;
+
+/// Ensure a package isn't older than the newest version of it that was accepted before
+pub fn check(previous: Option<&str>, current: &str, allow_downgrade: bool) -> Result<()> {
+    let previous = if let Some(previous) = previous {
+        previous
+    } else {
+        return Ok(());
+    };
+
+    if vercmp(current, previous) != Ordering::Less {
+        return Ok(());
+    }
+
+    if allow_downgrade {
+        warn!(
+            "Allowing package downgrade from {:?} to {:?}",
+            previous, current
+        );
+        Ok(())
+    } else {
+        bail!(
+            "Package version {:?} is older than the version accepted before: {:?}",
+            current,
+            previous
+        )
+    }
+}
+
+/// Returns true if the accepted version is newer than the previous high-water mark
+pub fn is_newer(previous: Option<&str>, current: &str) -> bool {
+    previous.is_none_or(|previous| vercmp(current, previous) == Ordering::Greater)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_downgrade() {
+        assert!(check(None, "0.18.1-1", false).is_ok());
+        assert!(check(Some("0.18.1-1"), "0.18.1-1", false).is_ok());
+        assert!(check(Some("0.18.1-1"), "0.18.1-2", false).is_ok());
+        assert!(check(Some("0.18.1-1"), "0.9.0-1", false).is_err());
+        assert!(check(Some("1:0.1-1"), "0.18.1-1", false).is_err());
+        assert!(check(Some("0.18.1-1"), "0.9.0-1", true).is_ok());
+    }
+
+    #[test]
+    fn test_is_newer() {
+        assert!(is_newer(None, "0.18.1-1"));
+        assert!(is_newer(Some("0.9.0-1"), "0.18.1-1"));
+        assert!(!is_newer(Some("0.18.1-1"), "0.18.1-1"));
+        assert!(!is_newer(Some("0.18.1-1"), "0.9.0-1"));
+    }
+}
@@ -0,0 +1,594 @@
+use crate::args::{Args, InspectArgs, OutputFormat};
+use crate::bundle::{self, Bundle, LogEntry};
+use crate::decision::{Decision, Kind};
+use crate::downgrade;
+use crate::proof::{self, PROOF_SIZE_LIMIT};
+use crate::reproducible::Build;
+use crate::routing::Clients;
+use crate::state::State;
+use crate::xfer;
+use minisign::SignatureBox;
+use pacman_bintrans_common::buildinfo::BuildInfo;
+use pacman_bintrans_common::decompress::{self, CompressedWith};
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::pkginfo::PkgInfo;
+use pacman_bintrans_common::ui::StatusIndicator;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use url::Url;
+
+/// A package file or the url to download it from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    File(PathBuf),
+    Url(Url),
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Result<Target> {
+        match s.parse::<Url>() {
+            Ok(url) if url.scheme() == "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| anyhow!("Failed to convert file:// url to path"))?;
+                Ok(Target::File(path))
+            }
+            Ok(url) => Ok(Target::Url(url)),
+            Err(_) => Ok(Target::File(PathBuf::from(s))),
+        }
+    }
+
+    fn url(&self) -> Result<Url> {
+        match self {
+            Target::File(path) => Url::from_file_path(env::current_dir()?.join(path))
+                .map_err(|_| anyhow!("Failed to convert path to url: {:?}", path)),
+            Target::Url(url) => Ok(url.clone()),
+        }
+    }
+
+    fn file_name(&self) -> Option<String> {
+        match self {
+            Target::File(path) => path.file_name()?.to_str().map(String::from),
+            Target::Url(url) => xfer::filename_from_url(url),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::File(path) => write!(f, "{}", path.display()),
+            Target::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// The minisign key id, as shown by `minisign -V`
+fn key_id(keynum: &[u8]) -> Result<String> {
+    let keynum = keynum
+        .try_into()
+        .map_err(|_| anyhow!("Key id has an invalid length"))?;
+    Ok(format!("{:016X}", u64::from_le_bytes(keynum)))
+}
+
+/// A decoded transparency signature, before it's verified
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Signature {
+    /// Where the signature was loaded from
+    pub source: String,
+    pub key_id: String,
+    pub untrusted_comment: String,
+    pub trusted_comment: String,
+    pub prehashed: bool,
+}
+
+impl Signature {
+    pub fn decode(source: String, sig: &str) -> Result<Signature> {
+        let sig = SignatureBox::from_string(sig).context("Failed to decode signature")?;
+        Ok(Signature {
+            source,
+            key_id: key_id(sig.keynum())?,
+            untrusted_comment: sig.untrusted_comment()?,
+            trusted_comment: sig.trusted_comment()?,
+            prehashed: sig.is_prehashed(),
+        })
+    }
+}
+
+/// Everything that is known about a package
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Inspection {
+    pub file_name: String,
+    pub compression: CompressedWith,
+    pub pkginfo: Option<PkgInfo>,
+    pub buildinfo: Option<BuildInfo>,
+    pub signature: Option<Signature>,
+    /// The key id of --pubkey, to compare it with the signature
+    pub pubkey_key_id: Option<String>,
+    pub log_entry: Option<LogEntry>,
+    pub builds: Vec<Build>,
+    /// The outcome of the policy checks, size and sha256
+    pub decision: Decision,
+    /// Information that couldn't be collected
+    pub errors: Vec<String>,
+}
+
+impl Inspection {
+    /// Record a failure to collect some information, inspecting continues regardless
+    fn note<T>(&mut self, what: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(err) => {
+                let msg = format!("{}: {:#}", what, err);
+                warn!("{}", msg);
+                self.errors.push(msg);
+                None
+            }
+        }
+    }
+}
+
+async fn load_target(args: &Args, clients: &Clients, target: &Target) -> Result<Vec<u8>> {
+    let limits = args.limits();
+    match target {
+        Target::File(path) => {
+            let md = fs::metadata(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+            limits.check_download_size(md.len())?;
+            fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))
+        }
+        Target::Url(_) if args.offline => bail!("Can't download {} in offline mode", target),
+        Target::Url(url) => {
+            clients
+                .packages
+                .download_to_mem(url.as_str(), Some(limits.max_download_size))
+                .await
+        }
+    }
+}
+
+/// Load the transparency proof like `verify` and XferCommand would, returns where it was found
+async fn load_proof(
+    args: &Args,
+    inspect: &InspectArgs,
+    clients: &Clients,
+    target: &Target,
+    file_name: &str,
+) -> Result<(String, Vec<u8>)> {
+    let path = match (&inspect.proof, target) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Target::File(path)) => Some(bundle::path(path)).filter(|path| path.exists()),
+        (None, Target::Url(_)) => None,
+    };
+    if let Some(path) = path {
+        let md = fs::metadata(&path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        args.limits().check_download_size(md.len())?;
+        let bytes = fs::read(&path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        return Ok((path.display().to_string(), bytes));
+    }
+
+    let url = match target {
+        _ if args.offline => None,
+        Target::Url(url) => Some(url.clone()),
+        Target::File(_) => args.transparency_url.clone(),
+    };
+    let url = if let Some(url) = url {
+        xfer::proof_url(&url, &args.transparency_url, file_name)?
+    } else {
+        bail!("No transparency proof found, expected `<file>.t`, --proof or --transparency-url");
+    };
+    let url = format!("{}.t", url);
+    info!("Downloading transparency proof from {:?}", url);
+    let bytes = clients
+        .proofs
+        .download_to_mem(url.as_str(), Some(PROOF_SIZE_LIMIT))
+        .await?;
+    Ok((url, bytes))
+}
+
+/// Collect everything that is known about a package and apply the policy to it
+///
+/// Unlike verification this doesn't stop at the first failure, and nothing is written to the state directory.
+pub async fn inspect(
+    args: &Args,
+    inspect: &InspectArgs,
+    clients: &Clients,
+    ui: &mut dyn StatusIndicator,
+) -> Result<Inspection> {
+    let limits = args.limits();
+    let target = Target::parse(&inspect.target)?;
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("Couldn't detect filename for {}", target))?;
+    let pkg = load_target(args, clients, &target).await?;
+    let sha256 = hex::encode(Sha256::digest(&pkg));
+
+    let mut decision = Decision::new(&target.url()?, Kind::Package);
+    decision.size = Some(pkg.len());
+    decision.sha256 = Some(sha256.clone());
+    let mut inspection = Inspection {
+        file_name: file_name.clone(),
+        compression: decompress::detect_compression_with_filename(&pkg, &file_name),
+        pkginfo: None,
+        buildinfo: None,
+        signature: None,
+        pubkey_key_id: None,
+        log_entry: None,
+        builds: Vec::new(),
+        decision: Decision::default(),
+        errors: Vec::new(),
+    };
+    let mut results = Vec::new();
+
+    let pkginfo = PkgInfo::from_pkg(&pkg, &limits);
+    let pkginfo = inspection.note("Failed to parse .PKGINFO", pkginfo);
+    let buildinfo = BuildInfo::extract_from_pkg(&pkg, &limits).and_then(|bytes| {
+        decision.buildinfo_sha256 = Some(hex::encode(Sha256::digest(&bytes)));
+        BuildInfo::from_bytes(&bytes)
+    });
+    inspection.buildinfo = inspection.note("Failed to parse .BUILDINFO", buildinfo);
+    if let Some(buildinfo) = &inspection.buildinfo {
+        decision.pkgbuild_sha256sum = Some(buildinfo.pkgbuild_sha256sum.clone());
+    }
+
+    results.push(xfer::check_sync_db(
+        args,
+        &file_name,
+        &sha256,
+        &mut decision,
+    ));
+
+    if let Some(pkginfo) = &pkginfo {
+        decision.pkgname = Some(pkginfo.pkgname.clone());
+        decision.pkgver = Some(pkginfo.pkgver.clone());
+        let state = State::new(&args.state_dir);
+        let result = state
+            .load_pkg_version(&pkginfo.pkgname, &pkginfo.arch)
+            .and_then(|previous| {
+                downgrade::check(
+                    previous.as_deref(),
+                    &pkginfo.pkgver,
+                    args.allow_pkg_downgrade,
+                )
+            });
+        results.push(decision.evaluate("downgrade", result));
+    }
+
+    let proof = load_proof(args, inspect, clients, &target, &file_name).await;
+    let proof = proof.and_then(|(source, bytes)| {
+        if bytes.starts_with(b"{") {
+            let bundle = Bundle::parse(&bytes)?;
+            let sig = bundle.signature.clone();
+            Ok((source, Some(bundle), sig))
+        } else {
+            Ok((source, None, String::from_utf8_lossy(&bytes).into_owned()))
+        }
+    });
+    let (bundle, sig) = match inspection.note("Failed to load transparency proof", proof) {
+        Some((source, bundle, sig)) => {
+            let signature = Signature::decode(source, &sig);
+            inspection.signature = inspection.note("Invalid transparency signature", signature);
+            (bundle, Some(sig))
+        }
+        None => (None, None),
+    };
+
+    let pubkey = inspection.note("Invalid --pubkey", xfer::load_pubkey(args));
+    if let Some(Some(pubkey)) = pubkey {
+        let pubkey_key_id = pubkey
+            .clone()
+            .into_public_key()
+            .map_err(Error::from)
+            .and_then(|pubkey| key_id(pubkey.keynum()));
+        inspection.pubkey_key_id = inspection.note("Invalid --pubkey", pubkey_key_id);
+
+        ui.progress("Checking transparency log...");
+        let result = match (&sig, &bundle, &args.checkpoint) {
+            (None, _, _) => Err(anyhow!("No transparency proof found")),
+            (Some(_), Some(bundle), Some(checkpoint)) => bundle.verify(&pubkey, &pkg, checkpoint),
+            (Some(_), _, _) if args.offline => Err(anyhow!(
+                "Transparency proof is not a proof bundle and can't be verified offline"
+            )),
+            (Some(sig), _, _) => {
+                proof::verify(&pubkey, &pkg, sig.as_bytes(), &clients.log_proxy).await
+            }
+        };
+        ui.clear();
+        results.push(xfer::accept_proof(&mut decision, result, &file_name));
+    } else if pubkey.is_none() {
+        let result = Err(anyhow!("Failed to load transparency public key"));
+        results.push(xfer::accept_proof(&mut decision, result, &file_name));
+    } else {
+        debug!("No --pubkey configured, skipping transparency log check");
+    }
+
+    let location = decision
+        .proof
+        .as_ref()
+        .and_then(|proof| proof.log_entry.clone())
+        .or_else(|| bundle.as_ref().and_then(|bundle| bundle.log_entry.clone()));
+    inspection.log_entry = match location {
+        Some(location) if !args.offline => {
+            let entry = bundle::fetch_log_entry(&clients.log, &location).await;
+            inspection.note("Failed to fetch transparency log entry", entry)
+        }
+        _ => None,
+    }
+    .or_else(|| bundle.as_ref().and_then(|bundle| bundle.log_entry_info()));
+
+    results.push(xfer::check_mtree(args, &pkg, ui, &mut decision));
+
+    if let Some(pkginfo) = &pkginfo {
+        if !args.rebuilders.is_empty() || args.required_rebuild_confirms > 0 {
+            // the verdict cache is in the state directory, don't use it
+            let result = xfer::check_rebuild_policy(
+                args,
+                clients,
+                None,
+                &pkg,
+                pkginfo,
+                ui,
+                &mut decision,
+                Some(&mut inspection.builds),
+            )
+            .await;
+            results.push(result);
+        }
+    }
+
+    let result = results.into_iter().collect::<Result<Vec<_>>>().map(|_| ());
+    decision.conclude(&result);
+    inspection.pkginfo = pkginfo;
+    inspection.decision = decision;
+    Ok(inspection)
+}
+
+fn print_field<T: fmt::Display>(name: &str, value: Option<T>) {
+    if let Some(value) = value {
+        println!("  {:<20} {}", format!("{}:", name), value);
+    }
+}
+
+fn print_list(name: &str, values: &[String]) {
+    if !values.is_empty() {
+        print_field(name, Some(values.join(", ")));
+    }
+}
+
+fn print_text(inspection: &Inspection) {
+    let decision = &inspection.decision;
+    println!("{}", decision.url);
+    print_field("filename", Some(&inspection.file_name));
+    print_field("size", decision.size);
+    print_field("sha256", decision.sha256.as_ref());
+    print_field("compression", Some(format!("{:?}", inspection.compression)));
+
+    if let Some(pkginfo) = &inspection.pkginfo {
+        println!("\n.PKGINFO");
+        print_field("pkgname", Some(&pkginfo.pkgname));
+        print_field("pkgbase", pkginfo.pkgbase.as_ref());
+        print_field("pkgver", Some(&pkginfo.pkgver));
+        print_field("pkgdesc", pkginfo.pkgdesc.as_ref());
+        print_field("url", pkginfo.url.as_ref());
+        print_field("arch", Some(&pkginfo.arch));
+        print_field("packager", pkginfo.packager.as_ref());
+        print_field("builddate", pkginfo.builddate);
+        print_field("size", pkginfo.size);
+        print_list("license", &pkginfo.license);
+        print_list("depends", &pkginfo.depends);
+        print_list("provides", &pkginfo.provides);
+        print_list("conflicts", &pkginfo.conflicts);
+        print_list("replaces", &pkginfo.replaces);
+        for (key, value) in &pkginfo.xdata {
+            print_field(key, Some(value));
+        }
+    }
+
+    if let Some(buildinfo) = &inspection.buildinfo {
+        println!("\n.BUILDINFO");
+        print_field("sha256", decision.buildinfo_sha256.as_ref());
+        print_field("format", Some(buildinfo.format));
+        print_field("pkgbuild_sha256sum", Some(&buildinfo.pkgbuild_sha256sum));
+        print_field("packager", buildinfo.packager.as_ref());
+        print_field("builddate", buildinfo.builddate);
+        print_field("builddir", buildinfo.builddir.as_ref());
+        print_field("startdir", buildinfo.startdir.as_ref());
+        print_field("buildtool", buildinfo.buildtool.as_ref());
+        print_field("buildtoolver", buildinfo.buildtoolver.as_ref());
+        print_list("buildenv", &buildinfo.buildenv);
+        print_list("options", &buildinfo.options);
+        print_field(
+            "installed",
+            Some(format!("{} packages", buildinfo.installed.len())),
+        );
+    }
+
+    if let Some(signature) = &inspection.signature {
+        println!("\nTransparency signature");
+        print_field("source", Some(&signature.source));
+        print_field("key id", Some(&signature.key_id));
+        print_field("pubkey key id", inspection.pubkey_key_id.as_ref());
+        print_field("untrusted comment", Some(&signature.untrusted_comment));
+        print_field("trusted comment", Some(&signature.trusted_comment));
+        print_field("prehashed", Some(signature.prehashed));
+    }
+
+    if let Some(entry) = &inspection.log_entry {
+        println!("\nTransparency log entry");
+        print_field("uuid", Some(&entry.uuid));
+        print_field("log index", entry.log_index);
+        print_field("integrated time", entry.integrated_time);
+        print_field("tree size", entry.tree_size);
+        print_field("root hash", entry.root_hash.as_ref());
+    }
+
+    if !decision.rebuilders.is_empty() || !inspection.builds.is_empty() {
+        println!("\nRebuilders");
+        for result in &decision.rebuilders {
+            print_field(
+                result.rebuilder.as_str(),
+                Some(format!("{:?}", result.status)),
+            );
+        }
+        for build in &inspection.builds {
+            let id = build
+                .build_id
+                .map(|id| format!(" (build {})", id))
+                .unwrap_or_default();
+            print_field(
+                build.rebuilder.as_str(),
+                Some(format!("{:?}{}", build.status, id)),
+            );
+            print_field("  log", build.log.as_ref());
+            print_field("  attestation", build.attestation.as_ref());
+        }
+    }
+
+    println!("\nPolicy");
+    for check in &decision.policy {
+        let status = if check.passed { "pass" } else { "FAIL" };
+        match &check.detail {
+            Some(detail) => println!("  {}  {}: {}", status, check.name, detail),
+            None => println!("  {}  {}", status, check.name),
+        }
+    }
+    print_field("verdict", Some(format!("{:?}", decision.verdict)));
+    print_field("error", decision.error.as_ref());
+
+    if !inspection.errors.is_empty() {
+        println!("\nErrors");
+        for err in &inspection.errors {
+            println!("  {}", err);
+        }
+    }
+}
+
+/// Inspect a package and print the result, a rejected package is not an error
+pub async fn run(
+    args: &Args,
+    inspect_args: &InspectArgs,
+    clients: &Clients,
+    ui: &mut dyn StatusIndicator,
+) -> Result<()> {
+    let inspection = inspect(args, inspect_args, clients, ui).await?;
+    match args.output_format {
+        OutputFormat::Text => print_text(&inspection),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&inspection)?),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Command;
+    use crate::config::Config;
+    use crate::decision::Verdict;
+    use crate::routing::Routes;
+    use crate::testutil::{self, sign};
+    use clap::Parser;
+    use pacman_bintrans_common::http::RetryPolicy;
+    use pacman_bintrans_common::ui;
+
+    const PKG: &[u8] = include_bytes!("../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+    const FILENAME: &str = "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst";
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            Target::parse("foo-1.0-1-any.pkg.tar.zst").unwrap(),
+            Target::File(PathBuf::from("foo-1.0-1-any.pkg.tar.zst"))
+        );
+        assert_eq!(
+            Target::parse("file:///var/cache/pacman/pkg/foo-1.0-1-any.pkg.tar.zst").unwrap(),
+            Target::File(PathBuf::from(
+                "/var/cache/pacman/pkg/foo-1.0-1-any.pkg.tar.zst"
+            ))
+        );
+        let target =
+            Target::parse("https://example.com/core/os/x86_64/foo-1.0-1-any.pkg.tar.zst").unwrap();
+        assert_eq!(
+            target.file_name().as_deref(),
+            Some("foo-1.0-1-any.pkg.tar.zst")
+        );
+    }
+
+    #[test]
+    fn test_decode_signature() {
+        let keypair = testutil::keypair();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        let signature =
+            Signature::decode("foo.t".to_string(), &sign(&keypair.sk, &sha256, FILENAME)).unwrap();
+        assert_eq!(signature.key_id, key_id(keypair.pk.keynum()).unwrap());
+        assert_eq!(signature.key_id.len(), 16);
+        assert_eq!(signature.trusted_comment, FILENAME);
+        assert!(signature.prehashed);
+        assert!(Signature::decode("foo.t".to_string(), "garbage").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inspect_continues_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = testutil::keypair();
+        let pkg = dir.path().join(FILENAME);
+        fs::write(&pkg, PKG).unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        fs::write(bundle::path(&pkg), sign(&keypair.sk, &sha256, FILENAME)).unwrap();
+
+        let pubkey = keypair.pk.to_base64();
+        let checkpoint = format!("1:{}", "ab".repeat(32));
+        let mut args = Args::try_parse_from([
+            "pacman-bintrans",
+            "--pubkey",
+            &pubkey,
+            "--offline",
+            "--checkpoint",
+            &checkpoint,
+            "--skip-sync-db-check",
+            "--state-dir",
+            dir.path().to_str().unwrap(),
+            "inspect",
+            pkg.to_str().unwrap(),
+        ])
+        .unwrap();
+        let inspect_args = match args.command.take() {
+            Some(Command::Inspect(inspect)) => inspect,
+            _ => panic!("Expected inspect subcommand"),
+        };
+        let clients = Clients::new(
+            Routes::default(),
+            &Config::default(),
+            RetryPolicy::default(),
+        )
+        .unwrap();
+        let mut ui = ui::new(ui::Mode::None, false);
+
+        let inspection = inspect(&args, &inspect_args, &clients, ui.as_mut())
+            .await
+            .unwrap();
+        assert_eq!(inspection.compression, CompressedWith::Zstd);
+        assert_eq!(inspection.pkginfo.unwrap().pkgname, "rebuilderd");
+        assert_eq!(inspection.buildinfo.unwrap().pkgname, "rebuilderd");
+        let signature = inspection.signature.unwrap();
+        assert_eq!(signature.trusted_comment, FILENAME);
+        assert_eq!(Some(signature.key_id), inspection.pubkey_key_id);
+
+        // a plain signature can't be verified offline, the other checks still ran
+        let decision = inspection.decision;
+        assert_eq!(decision.verdict, Verdict::Rejected);
+        let policy = decision
+            .policy
+            .iter()
+            .map(|check| (check.name.as_str(), check.passed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            policy,
+            vec![("downgrade", true), ("transparency_log", false)]
+        );
+        assert!(decision.buildinfo_sha256.is_some());
+    }
+}
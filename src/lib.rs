@@ -1,3 +1,20 @@
 pub mod args;
+pub mod bundle;
+pub mod cache;
+pub mod config;
+pub mod daemon;
+pub mod decision;
+pub mod downgrade;
+pub mod inspect;
+pub mod preflight;
 pub mod proof;
+pub mod proofarchive;
+pub mod repodb;
 pub mod reproducible;
+pub mod routing;
+pub mod state;
+pub mod syncdb;
+#[cfg(test)]
+mod testutil;
+pub mod verify;
+pub mod xfer;
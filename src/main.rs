@@ -1,37 +1,67 @@
-use crate::http::Client;
 use clap::Parser;
 use env_logger::Env;
-use minisign::PublicKey;
-use pacman_bintrans::args::Args;
-use pacman_bintrans::proof;
-use pacman_bintrans::reproducible;
+use pacman_bintrans::args::{Args, Command, OutputFormat};
+use pacman_bintrans::config::Config;
+use pacman_bintrans::daemon::{self, Request};
+use pacman_bintrans::decision::Decision;
+use pacman_bintrans::inspect;
+use pacman_bintrans::preflight;
+use pacman_bintrans::routing::{Clients, Routes};
+use pacman_bintrans::verify;
+use pacman_bintrans::xfer;
 use pacman_bintrans_common::errors::*;
-use pacman_bintrans_common::http;
+use pacman_bintrans_common::ui::{self, StatusIndicator};
 use std::env;
-use std::fs;
-use std::rc::Rc;
-use url::Url;
+use std::process;
 
-fn needs_transparency_proof(url: &str) -> bool {
-    let parts = url.split('.').collect::<Vec<_>>();
+/// Print the decision if requested
+fn report(args: &Args, decision: Option<&serde_json::Value>) {
+    if let (OutputFormat::Json, Some(decision)) = (args.output_format, decision) {
+        println!("{}", decision);
+    }
+}
 
-    let mut iter = parts.iter().rev();
+/// Create the http clients for this invocation
+fn clients(args: &Args) -> Result<Clients> {
+    let config = Config::load_or_default(args.config.as_deref())?;
+    let routes = Routes::from_args(args, &config)?;
+    debug!("Using proxy routes: {:?}", routes);
+    Clients::new(routes, &config, args.retry_policy())
+}
 
-    // strip .tar.zstd
-    if let Some(x) = iter.next() {
-        // if the extension is .tar there is possibly no compression
-        if *x != "tar" && iter.next() != Some(&"tar") {
-            return false;
+/// Try to let the daemon verify the download, returns `None` if it isn't available
+async fn handoff(args: &Args, ui: &mut dyn StatusIndicator) -> Option<Result<()>> {
+    let args_utf8 = env::args_os()
+        .skip(1)
+        .map(|arg| arg.into_string())
+        .collect::<Result<Vec<_>, _>>();
+    let request = match (args_utf8, env::current_dir()) {
+        (Ok(args), Ok(cwd)) => Request { args, cwd },
+        _ => {
+            debug!("Can't hand off invocation with non-utf8 arguments or working directory");
+            return None;
         }
-    }
+    };
 
-    iter.next() == Some(&"pkg")
-}
+    let response = match daemon::handoff(&args.daemon_socket, &request).await {
+        Ok(Some(response)) => response,
+        Ok(None) => return None,
+        Err(err) => {
+            warn!("Daemon failed, verifying in-process instead: {:#}", err);
+            return None;
+        }
+    };
 
-fn filename_from_url(url: &Url) -> Option<String> {
-    let segments = url.path_segments()?;
-    let filename = segments.last()?;
-    Some(filename.to_string())
+    report(args, response.decision.as_ref());
+    Some(match response.error {
+        Some(err) => Err(anyhow!("{}", err)),
+        None => {
+            if let Some(url) = &args.url {
+                ui.success(&format!("Verified {:?} with daemon", url.as_str()));
+            }
+            Ok(())
+        }
+    })
 }
 
 #[tokio::main]
@@ -49,119 +79,37 @@ async fn main() -> Result<()> {
     if let Some(log) = log {
         env_logger::init_from_env(Env::default().default_filter_or(log));
     }
+    let mut ui = ui::new(args.ui, log.is_some());
 
-    let pubkey = if let Some(pubkey) = &args.pubkey {
-        let pubkey = PublicKey::from_base64(pubkey)
-            .context("Failed to load transparency public key")?
-            .to_box()?;
-        Some(pubkey)
-    } else {
-        None
-    };
-
-    if args.url.scheme() == "file" {
-        let path = args
-            .url
-            .to_file_path()
-            .map_err(|_| anyhow!("Failed to convert file:// url to path"))?;
-        info!("Copying from file {:?} to {:?}", path, args.output);
-        let n = fs::copy(path, &args.output).context("Failed to copy from file://")?;
-        debug!("Copied {} bytes", n);
-        return Ok(());
-    }
-
-    let client = Rc::new(Client::new(args.proxy.clone())?);
-    let pkg_client = if args.bypass_proxy_for_pkgs {
-        Rc::new(Client::new(None)?)
-    } else {
-        client.clone()
-    };
-
-    if needs_transparency_proof(args.url.as_str()) {
-        info!(
-            "Transparency proof is required for {:?}, downloading into memory",
-            args.url.as_str()
-        );
-        let pkg = if args.output.exists() {
-            info!("Target path already exists, reading from disk instead of downloading");
-            fs::read(&args.output).context("Failed to read existing file")?
-        } else {
-            let pkg = pkg_client.download_to_mem(args.url.as_str(), None).await?;
-            debug!("Downloaded {} bytes", pkg.len());
-            pkg
-        };
-
-        if log.is_none() {
-            println!(
-                "\x1b[1m[\x1b[32m+\x1b[0;1m]\x1b[0m Downloaded {:?}",
-                args.url.as_str()
-            );
+    match &args.command {
+        Some(Command::Daemon) => return daemon::serve(&args.daemon_socket).await,
+        Some(Command::Preflight(preflight)) => {
+            return preflight::run(&args, preflight, &clients(&args)?).await
         }
-
-        let url = if let Some(transparency_url) = &args.transparency_url {
-            let file_name = filename_from_url(&args.url).ok_or_else(|| {
-                anyhow!("Couldn't detect filename for url: {:?}", args.url.as_str())
-            })?;
-            let mut url = transparency_url.clone();
-            url.path_segments_mut()
-                .map_err(|_| anyhow!("Failed to get path segments for url"))?
-                .pop_if_empty()
-                .push(&file_name);
-            url
-        } else {
-            args.url
-        };
-
-        if let Some(pubkey) = &pubkey {
-            if log.is_none() {
-                println!(
-                    "\x1b[2K\r\x1b[1m[\x1b[34m%\x1b[0;1m]\x1b[0m Checking transparency log..."
-                );
-            }
-
-            proof::fetch_and_verify(&client, pubkey, &url, &pkg, &args.proxy)
-                .await
-                .context("Failed to check transparency log")?;
-
-            if log.is_none() {
-                println!("\x1b[1A\x1b[2K\r\x1b[1m[\x1b[32m+\x1b[0;1m]\x1b[0m Package is present in transparency log");
-            }
+        Some(Command::Inspect(inspect)) => {
+            return inspect::run(&args, inspect, &clients(&args)?, ui.as_mut()).await
         }
-
-        if !args.rebuilders.is_empty() || args.required_rebuild_confirms > 0 {
-            let rebuild_confirms =
-                reproducible::check_rebuilds(&client, &pkg, &args.rebuilders, &log)
-                    .await
-                    .context("Failed to check rebuilds")?;
-
-            if rebuild_confirms < args.required_rebuild_confirms {
-                bail!(
-                    "Not enough rebuild confirms: got {}, expected {}",
-                    rebuild_confirms,
-                    args.required_rebuild_confirms
-                );
+        Some(Command::Verify(verify)) => {
+            let code = verify::run(&args, verify, &clients(&args)?, ui.as_mut()).await?;
+            if code != 0 {
+                process::exit(code);
             }
-        } else if pubkey.is_none() {
-            warn!("There is no configured authentication method, pacman-bintrans is used as a simple downloader!");
+            return Ok(());
         }
+        None => (),
+    }
 
-        info!("Writing pkg to {:?}", args.output);
-        fs::write(args.output, &pkg).context("Failed to write database file after verification")?;
-        debug!("Wrote {} bytes", pkg.len());
-    } else {
-        info!("Downloading {:?} to {:?}", args.url.as_str(), args.output);
-        let n = client
-            .download_to_file(args.url.as_str(), &args.output)
-            .await?;
-        debug!("Downloaded {} bytes", n);
-
-        if log.is_none() {
-            println!(
-                "\x1b[1m[\x1b[32m+\x1b[0;1m]\x1b[0m Downloaded {:?}",
-                args.url.as_str()
-            );
+    if !args.no_daemon {
+        if let Some(result) = handoff(&args, ui.as_mut()).await {
+            return result;
         }
     }
 
-    Ok(())
+    let clients = clients(&args)?;
+
+    let mut decision: Option<Decision> = None;
+    let result = xfer::run(&args, &clients, ui.as_mut(), &mut decision).await;
+    let decision = decision.map(serde_json::to_value).transpose()?;
+    report(&args, decision.as_ref());
+    result
 }
@@ -0,0 +1,590 @@
+use crate::args::{Args, OutputFormat, PreflightArgs};
+use crate::cache::Cache;
+use crate::decision::{self, Decision, RebuildStatus, RebuilderResult};
+use crate::proof::{self, Proof};
+use crate::proofarchive;
+use crate::reproducible::{self, RebuildQuery};
+use crate::routing::Clients;
+use crate::state::State;
+use crate::syncdb;
+use crate::xfer;
+use futures_util::stream::{self, StreamExt};
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::syncdb::{self as common_syncdb, LocalPkg, SyncDb};
+use pacman_bintrans_common::version::vercmp;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+use url::Url;
+
+/// A package that would be upgraded by `pacman -Su`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Upgrade {
+    pub repo: String,
+    pub name: String,
+    pub installed: String,
+    pub version: String,
+    pub arch: String,
+    pub filename: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    #[serde(flatten)]
+    pub upgrade: Upgrade,
+    pub proof: Option<Proof>,
+    pub rebuilders: Vec<RebuilderResult>,
+    /// Set if the upgrade failed verification
+    pub error: Option<String>,
+}
+
+/// The json document printed by `preflight --output-format json`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub schema_version: u32,
+    /// True if all pending upgrades passed verification
+    pub go: bool,
+    pub packages: Vec<Report>,
+}
+
+impl Summary {
+    pub fn failed(&self) -> usize {
+        self.packages.iter().filter(|r| r.error.is_some()).count()
+    }
+}
+
+/// Read the name and version of all installed packages
+pub fn load_installed(dir: &Path) -> Result<Vec<LocalPkg>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| anyhow!("Failed to list {:?}", dir))? {
+        let path = entry?.path().join("desc");
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut installed = Vec::new();
+    for path in paths {
+        let content =
+            fs::read_to_string(&path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        let pkg = common_syncdb::parse_local(&content)
+            .with_context(|| anyhow!("Failed to parse {:?}", path))?;
+        installed.push(pkg);
+    }
+    Ok(installed)
+}
+
+/// Compute the upgrades of installed packages, the first repository that has a package wins
+///
+/// Ignored packages and replacements from pacman.conf are not taken into account.
+pub fn pending_upgrades(installed: &[LocalPkg], dbs: &[(String, SyncDb)]) -> Vec<Upgrade> {
+    let mut upgrades = Vec::new();
+    for local in installed {
+        let found = dbs.iter().find_map(|(repo, db)| {
+            db.pkgs
+                .iter()
+                .find(|pkg| pkg.name == local.name)
+                .map(|pkg| (repo, pkg))
+        });
+        let (repo, pkg) = if let Some(found) = found {
+            found
+        } else {
+            debug!("Package {:?} is not in any sync database", local.name);
+            continue;
+        };
+
+        if vercmp(&pkg.version, &local.version) == Ordering::Greater {
+            upgrades.push(Upgrade {
+                repo: repo.clone(),
+                name: pkg.name.clone(),
+                installed: local.version.clone(),
+                version: pkg.version.clone(),
+                arch: pkg.arch.clone(),
+                filename: pkg.filename.clone(),
+                sha256: pkg.sha256sum.to_lowercase(),
+            });
+        }
+    }
+    upgrades
+}
+
+/// Select the sync databases to upgrade from, in order of priority
+fn select_repos(dbs: Vec<(String, SyncDb)>, repos: &[String]) -> Result<Vec<(String, SyncDb)>> {
+    let mut dbs = dbs;
+    let mut selected = Vec::new();
+    for repo in repos {
+        let idx = dbs
+            .iter()
+            .position(|(name, _)| name == repo)
+            .ok_or_else(|| anyhow!("Sync database for repository {:?} not found", repo))?;
+        selected.push(dbs.remove(idx));
+    }
+    Ok(selected)
+}
+
+/// The url of a package on a mirror, in the format of pacman's mirrorlist
+fn mirror_url(mirror: &str, repo: &str, filename: &str) -> Result<Url> {
+    let mirror = mirror
+        .replace("$repo", repo)
+        .replace("$arch", std::env::consts::ARCH);
+    let mut url = mirror
+        .parse::<Url>()
+        .with_context(|| anyhow!("Invalid mirror url: {:?}", mirror))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Failed to get path segments for url"))?
+        .pop_if_empty()
+        .push(filename);
+    Ok(url)
+}
+
+struct Checker<'a> {
+    args: &'a Args,
+    preflight: &'a PreflightArgs,
+    clients: &'a Clients,
+    pubkey: Option<PublicKeyBox>,
+    state: State,
+    cache: Option<Cache>,
+}
+
+impl Checker<'_> {
+    /// Find the transparency signature of a package, without downloading the package
+    async fn find_proof(&self, pubkey: &PublicKeyBox, upgrade: &Upgrade) -> Result<Proof> {
+        if let Some(cache) = &self.cache {
+            if let Some(proof) = cache.load_proof_sha256(pubkey, &upgrade.sha256)? {
+                info!(
+                    "Using cached transparency signature for {:?}",
+                    upgrade.filename
+                );
+                return Ok(proof);
+            }
+        }
+
+        if !self.args.skip_proof_archive {
+            let proof = proofarchive::lookup_sha256(
+                &self.state,
+                pubkey,
+                &upgrade.filename,
+                &upgrade.sha256,
+            )?;
+            if let Some(proof) = proof {
+                info!(
+                    "Found transparency signature for {:?} in proof archive",
+                    upgrade.filename
+                );
+                return Ok(proof);
+            }
+        }
+
+        if self.args.offline {
+            bail!("Package is not in any proof archive and the transparency log can't be queried offline");
+        }
+        if !self.args.skip_proof_archive && !self.args.allow_individual_proofs {
+            bail!(
+                "Package is not in any proof archive, refusing to request its proof individually"
+            );
+        }
+
+        let url = match (&self.args.transparency_url, &self.preflight.mirror) {
+            (Some(url), _) => url.clone(),
+            (None, Some(mirror)) => mirror_url(mirror, &upgrade.repo, &upgrade.filename)?,
+            (None, None) => bail!(
+                "Package is not in any proof archive and neither --transparency-url nor --mirror is set"
+            ),
+        };
+        let url = xfer::proof_url(&url, &self.args.transparency_url, &upgrade.filename)?;
+        let proof = proof::fetch_and_verify_sha256(
+            &self.clients.proofs,
+            pubkey,
+            &url,
+            &upgrade.sha256,
+            &self.clients.log_proxy,
+        )
+        .await?;
+
+        if let Some(cache) = &self.cache {
+            if let Err(err) = cache.store_proof(&upgrade.sha256, &proof) {
+                warn!("Failed to cache transparency signature: {:#}", err);
+            }
+        }
+        Ok(proof)
+    }
+
+    async fn verify(&self, report: &mut Report) -> Result<()> {
+        let upgrade = &report.upgrade;
+
+        if let Some(pubkey) = &self.pubkey {
+            let proof = self
+                .find_proof(pubkey, upgrade)
+                .await
+                .context("Failed to check transparency log")?;
+            let trusted_comment = proof.trusted_comment.clone();
+            report.proof = Some(proof);
+            if trusted_comment != report.upgrade.filename {
+                bail!(
+                    "Transparency signature was issued for {:?}, but the sync database lists {:?}",
+                    trusted_comment,
+                    report.upgrade.filename
+                );
+            }
+        }
+
+        let client = (!self.args.offline).then_some(&self.clients.rebuilders);
+        let mut decision = Decision::default();
+        for rebuilder in &self.args.rebuilders {
+            let started = Instant::now();
+            let query = RebuildQuery {
+                pkgname: &report.upgrade.name,
+                pkgver: &report.upgrade.version,
+                buildinfo_sha256: None,
+                pkg_sha256: &report.upgrade.sha256,
+            };
+            let status =
+                match reproducible::rebuild_status(client, self.cache.as_ref(), rebuilder, &query)
+                    .await
+                {
+                    Ok((status, _)) => status,
+                    Err(err) => {
+                        warn!(
+                            "Failed to query rebuilder {:?}: {:#}",
+                            rebuilder.as_str(),
+                            err
+                        );
+                        RebuildStatus::Error(format!("{:#}", err))
+                    }
+                };
+            decision.rebuilders.push(RebuilderResult {
+                rebuilder: rebuilder.clone(),
+                status,
+                duration_ms: started.elapsed().as_millis() as u64,
+            });
+        }
+
+        let result = xfer::enforce_rebuild_policy(self.args, &mut decision);
+        report.rebuilders = decision.rebuilders;
+        result
+    }
+
+    async fn check(&self, upgrade: Upgrade) -> Report {
+        let mut report = Report {
+            upgrade,
+            proof: None,
+            rebuilders: Vec::new(),
+            error: None,
+        };
+        if let Err(err) = self.verify(&mut report).await {
+            warn!(
+                "Package {:?} failed verification: {:#}",
+                report.upgrade.name, err
+            );
+            report.error = Some(format!("{:#}", err));
+        }
+        report
+    }
+}
+
+/// Verify the given upgrades concurrently, the reports are in the same order
+pub async fn check(
+    args: &Args,
+    preflight: &PreflightArgs,
+    clients: &Clients,
+    upgrades: Vec<Upgrade>,
+) -> Result<Summary> {
+    let checker = Checker {
+        args,
+        preflight,
+        clients,
+        pubkey: xfer::load_pubkey(args)?,
+        state: State::new(&args.state_dir),
+        cache: args.cache(),
+    };
+
+    let packages = stream::iter(upgrades)
+        .map(|upgrade| checker.check(upgrade))
+        .buffered(preflight.jobs.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    let go = packages.iter().all(|r| r.error.is_none());
+    Ok(Summary {
+        schema_version: decision::SCHEMA_VERSION,
+        go,
+        packages,
+    })
+}
+
+fn print_text(summary: &Summary) {
+    for report in &summary.packages {
+        let upgrade = &report.upgrade;
+        let line = format!(
+            "{}/{} {} -> {}",
+            upgrade.repo, upgrade.name, upgrade.installed, upgrade.version
+        );
+        match &report.error {
+            Some(err) => println!("FAIL {}: {}", line, err),
+            None => println!("ok   {}", line),
+        }
+    }
+
+    let total = summary.packages.len();
+    if summary.go {
+        println!("GO: {} pending upgrades passed verification", total);
+    } else {
+        println!(
+            "NO-GO: {} of {} pending upgrades failed verification",
+            summary.failed(),
+            total
+        );
+    }
+}
+
+/// Verify all pending upgrades and print a summary, fails if any of them doesn't pass verification
+pub async fn run(args: &Args, preflight: &PreflightArgs, clients: &Clients) -> Result<()> {
+    let limits = args.limits();
+    let installed = load_installed(&preflight.local_db_dir)?;
+    let dbs = syncdb::load(&args.sync_db_dir, &limits).context("Failed to load sync databases")?;
+    let repos = if preflight.repos.is_empty() {
+        syncdb::configured_repos(&preflight.pacman_conf).with_context(|| {
+            anyhow!(
+                "Failed to read repositories from {:?}, use --repo to list them",
+                preflight.pacman_conf
+            )
+        })?
+    } else {
+        preflight.repos.clone()
+    };
+    let dbs = select_repos(dbs, &repos)?;
+
+    let upgrades = pending_upgrades(&installed, &dbs);
+    info!("Found {} pending upgrades", upgrades.len());
+
+    let summary = check(args, preflight, clients, upgrades).await?;
+    match args.output_format {
+        OutputFormat::Text => print_text(&summary),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+    }
+
+    if !summary.go {
+        bail!(
+            "{} of {} pending upgrades failed verification",
+            summary.failed(),
+            summary.packages.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Command;
+    use crate::config::Config;
+    use crate::routing::Routes;
+    use crate::testutil::{self, sign};
+    use clap::Parser;
+    use pacman_bintrans_common::http::RetryPolicy;
+    use pacman_bintrans_common::proofarchive::ProofArchive;
+    use pacman_bintrans_common::syncdb::Pkg;
+    use sha2::{Digest, Sha256};
+
+    fn local(name: &str, version: &str) -> LocalPkg {
+        LocalPkg {
+            name: name.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    fn pkg(name: &str, version: &str) -> Pkg {
+        Pkg {
+            filename: format!("{}-{}-x86_64.pkg.tar.zst", name, version),
+            name: name.to_string(),
+            version: version.to_string(),
+            sha256sum: hex::encode(Sha256::digest(name.as_bytes())),
+            arch: "x86_64".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn db(repo: &str, pkgs: Vec<Pkg>) -> (String, SyncDb) {
+        (
+            repo.to_string(),
+            SyncDb {
+                pkgs,
+                errors: Vec::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_pending_upgrades() {
+        let installed = vec![
+            local("linux", "6.1.1-1"),
+            local("pacman", "6.0.2-1"),
+            local("rebuilderd", "0.18.1-1"),
+            local("custom", "1.0-1"),
+            local("vim", "1:9.0-1"),
+        ];
+        let dbs = vec![
+            db(
+                "core",
+                vec![pkg("linux", "6.1.2-1"), pkg("pacman", "6.0.2-1")],
+            ),
+            db(
+                "extra",
+                vec![
+                    pkg("rebuilderd", "0.18.1-2"),
+                    pkg("linux", "6.2.0-1"),
+                    pkg("vim", "9.1-1"),
+                ],
+            ),
+        ];
+
+        let upgrades = pending_upgrades(&installed, &dbs);
+        let upgrades = upgrades
+            .iter()
+            .map(|u| (u.repo.as_str(), u.name.as_str(), u.version.as_str()))
+            .collect::<Vec<_>>();
+        // linux is taken from core, because core comes first
+        assert_eq!(
+            upgrades,
+            vec![
+                ("core", "linux", "6.1.2-1"),
+                ("extra", "rebuilderd", "0.18.1-2")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_repos() {
+        let dbs = vec![
+            db("core", vec![]),
+            db("extra", vec![]),
+            db("multilib", vec![]),
+        ];
+        let selected = select_repos(dbs, &["extra".to_string(), "core".to_string()]).unwrap();
+        let names = selected.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["extra", "core"]);
+
+        let dbs = vec![db("core", vec![])];
+        assert!(select_repos(dbs, &["testing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_mirror_url() {
+        let url = mirror_url(
+            "https://geo.mirror.pkgbuild.com/$repo/os/$arch",
+            "core",
+            "linux-6.1.2-1-x86_64.pkg.tar.zst",
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            format!(
+                "https://geo.mirror.pkgbuild.com/core/os/{}/linux-6.1.2-1-x86_64.pkg.tar.zst",
+                std::env::consts::ARCH
+            )
+        );
+    }
+
+    fn upgrade(name: &str) -> Upgrade {
+        let pkg = pkg(name, "1.0-1");
+        Upgrade {
+            repo: "core".to_string(),
+            name: pkg.name,
+            installed: "0.9-1".to_string(),
+            version: pkg.version,
+            arch: pkg.arch,
+            filename: pkg.filename,
+            sha256: pkg.sha256sum,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_with_proof_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = testutil::keypair();
+        let pubkey = keypair.pk.to_base64();
+
+        let good = upgrade("linux");
+        let renamed = upgrade("pacman");
+        let missing = upgrade("vim");
+        let mut archive = ProofArchive::new("core.db", &"ab".repeat(32));
+        archive.insert(
+            &good.filename,
+            &good.sha256,
+            &sign(&keypair.sk, &good.sha256, &good.filename),
+        );
+        // signed for a different filename than the sync database lists
+        archive.insert(
+            &renamed.filename,
+            &renamed.sha256,
+            &sign(
+                &keypair.sk,
+                &renamed.sha256,
+                "evil-1.0-1-x86_64.pkg.tar.zst",
+            ),
+        );
+        let bytes = archive.to_vec().unwrap();
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        let sig = sign(
+            &keypair.sk,
+            &sha256,
+            &ProofArchive::name(&archive.db, &archive.db_sha256),
+        );
+        State::new(dir.path())
+            .store_proof_archive("core.db", &bytes, sig.as_bytes(), None)
+            .unwrap();
+
+        let args = Args::try_parse_from([
+            "pacman-bintrans",
+            "--pubkey",
+            &pubkey,
+            "--state-dir",
+            dir.path().to_str().unwrap(),
+            "preflight",
+        ])
+        .unwrap();
+        let preflight = match &args.command {
+            Some(Command::Preflight(preflight)) => preflight,
+            _ => panic!("Expected preflight subcommand"),
+        };
+        let clients = Clients::new(
+            Routes::default(),
+            &Config::default(),
+            RetryPolicy::default(),
+        )
+        .unwrap();
+
+        let summary = check(&args, preflight, &clients, vec![good.clone()])
+            .await
+            .unwrap();
+        assert!(summary.go);
+        assert_eq!(summary.packages[0].error, None);
+        assert_eq!(
+            summary.packages[0].proof.as_ref().unwrap().trusted_comment,
+            good.filename
+        );
+
+        let summary = check(&args, preflight, &clients, vec![good, renamed, missing])
+            .await
+            .unwrap();
+        assert!(!summary.go);
+        assert_eq!(summary.failed(), 2);
+        assert_eq!(summary.packages[0].error, None);
+        assert!(summary.packages[1]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("Transparency signature was issued for"));
+        // the proof isn't requested individually without --allow-individual-proofs
+        assert!(summary.packages[2]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("refusing to request its proof individually"));
+    }
+}
@@ -1,6 +1,7 @@
 use minisign::{PublicKeyBox, SignatureBox};
 use pacman_bintrans_common::errors::*;
 use pacman_bintrans_common::http::{Client, Proxy};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io::Cursor;
@@ -11,15 +12,50 @@ use tokio::process::Command;
 use url::Url;
 
 const REKOR_BIN: &str = "rekor-cli";
-const PROOF_SIZE_LIMIT: usize = 1024; // 1K
+pub const PROOF_SIZE_LIMIT: u64 = 1024; // 1K
+
+/// A verified transparency signature
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Proof {
+    /// The minisign public key the signature was verified with
+    pub pubkey: String,
+    pub trusted_comment: String,
+    /// The index of the signature in the transparency log, if rekor-cli reported it
+    pub log_index: Option<u64>,
+    /// The location of the log entry, if rekor-cli reported it
+    pub log_entry: Option<String>,
+    /// The proof archive the signature was taken from, the archive itself is in the transparency log
+    pub archive: Option<String>,
+    /// The signature, as published in the `.t` file
+    #[serde(skip)]
+    pub signature: String,
+}
+
+/// The json output of `rekor-cli upload --format json`
+#[derive(Debug, Default, Deserialize)]
+struct RekorEntry {
+    #[serde(rename = "Index")]
+    index: Option<u64>,
+    #[serde(rename = "Location")]
+    location: Option<String>,
+}
+
+impl RekorEntry {
+    fn parse(stdout: &[u8]) -> RekorEntry {
+        serde_json::from_slice(stdout).unwrap_or_else(|err| {
+            warn!("Failed to parse rekor-cli output: {:#}", err);
+            RekorEntry::default()
+        })
+    }
+}
 
 async fn rekor_verify(
     pubkey: &PublicKeyBox,
     artifact: &[u8],
     signature: &[u8],
     proxy: &Option<Proxy>,
-) -> Result<()> {
-    rekor_exec(
+) -> Result<RekorEntry> {
+    let stdout = rekor_exec(
         pubkey,
         artifact,
         signature,
@@ -27,7 +63,8 @@ async fn rekor_verify(
         "upload",
         &["--format", "json"],
     )
-    .await
+    .await?;
+    Ok(RekorEntry::parse(&stdout))
 }
 
 async fn rekor_upload(
@@ -36,7 +73,8 @@ async fn rekor_upload(
     signature: &[u8],
     proxy: &Option<Proxy>,
 ) -> Result<()> {
-    rekor_exec(pubkey, artifact, signature, proxy, "upload", &[]).await
+    rekor_exec(pubkey, artifact, signature, proxy, "upload", &[]).await?;
+    Ok(())
 }
 
 async fn rekor_exec(
@@ -46,7 +84,7 @@ async fn rekor_exec(
     proxy: &Option<Proxy>,
     action: &str,
     extra_args: &[&str],
-) -> Result<()> {
+) -> Result<Vec<u8>> {
     let pubkey_file = NamedTempFile::new()?;
     let sig_file = NamedTempFile::new()?;
 
@@ -86,6 +124,9 @@ async fn rekor_exec(
         cmd.env("http_proxy", proxy);
         cmd.env("https_proxy", proxy);
     }
+    if let Some(no_proxy) = proxy.as_ref().and_then(|proxy| proxy.no_proxy()) {
+        cmd.env("no_proxy", no_proxy);
+    }
 
     debug!(
         "Executing {:?} {:?}",
@@ -113,45 +154,87 @@ async fn rekor_exec(
     debug!("Child exited with {:?}", exit.status);
 
     if exit.status.success() {
-        Ok(())
+        Ok(exit.stdout)
     } else {
         bail!("Sigstore verify failed");
     }
 }
 
+/// Verify a transparency signature for the artifact with the given sha256, returns the trusted comment
+pub fn verify_signature_sha256(pubkey: &PublicKeyBox, sha256: &str, sig: &[u8]) -> Result<String> {
+    info!("Verifying transparency signature");
+    let data_reader = Cursor::new(sha256);
+    let sig_box = SignatureBox::from_string(&String::from_utf8_lossy(sig))?;
+    let pk = pubkey.clone().into_public_key()?;
+    minisign::verify(&pk, &sig_box, data_reader, true, false, true)?;
+    let trusted_comment = sig_box.trusted_comment()?;
+    Ok(trusted_comment)
+}
+
+fn sha256sum(artifact: &[u8]) -> String {
+    info!("Calculating sha256sum for {} bytes", artifact.len());
+    hex::encode(Sha256::digest(artifact))
+}
+
+/// Verify a transparency signature without querying the transparency log
+///
+/// Returns the sha256 of the artifact and the trusted comment.
+pub fn verify_signature(
+    pubkey: &PublicKeyBox,
+    artifact: &[u8],
+    sig: &[u8],
+) -> Result<(String, String)> {
+    let sha256 = sha256sum(artifact);
+    let trusted_comment = verify_signature_sha256(pubkey, &sha256, sig)?;
+    Ok((sha256, trusted_comment))
+}
+
+/// Verify the transparency signature of an artifact
 pub async fn verify(
     pubkey: &PublicKeyBox,
     artifact: &[u8],
     sig: &[u8],
     proxy: &Option<Proxy>,
-) -> Result<()> {
-    info!("Calculating sha256sum for {} bytes", artifact.len());
-    let mut hasher = Sha256::new();
-    hasher.update(artifact);
-    let sha256 = hex::encode(hasher.finalize());
+) -> Result<Proof> {
+    let sha256 = sha256sum(artifact);
+    verify_sha256(pubkey, &sha256, sig, proxy).await
+}
 
-    info!("Verifying transparency signature");
-    let data_reader = Cursor::new(&sha256);
-    let sig_box = SignatureBox::from_string(&String::from_utf8_lossy(sig))?;
-    let pk = pubkey.clone().into_public_key()?;
-    minisign::verify(&pk, &sig_box, data_reader, true, false, true)?;
+/// Verify the transparency signature of the artifact with the given sha256
+pub async fn verify_sha256(
+    pubkey: &PublicKeyBox,
+    sha256: &str,
+    sig: &[u8],
+    proxy: &Option<Proxy>,
+) -> Result<Proof> {
+    let trusted_comment = verify_signature_sha256(pubkey, sha256, sig)?;
 
     info!("Verifying signature is in transparency log");
-    if let Err(err) = rekor_verify(pubkey, sha256.as_bytes(), sig, proxy).await {
-        warn!(
-            "Verification failed, uploading signature to log next: {:#}",
-            err
-        );
-        rekor_upload(pubkey, sha256.as_bytes(), sig, proxy)
-            .await
-            .context("Failed to upload signature")?;
-        rekor_verify(pubkey, sha256.as_bytes(), sig, proxy)
-            .await
-            .context("Repeated lookup in transparency log failed")?;
-    }
+    let entry = match rekor_verify(pubkey, sha256.as_bytes(), sig, proxy).await {
+        Ok(entry) => entry,
+        Err(err) => {
+            warn!(
+                "Verification failed, uploading signature to log next: {:#}",
+                err
+            );
+            rekor_upload(pubkey, sha256.as_bytes(), sig, proxy)
+                .await
+                .context("Failed to upload signature")?;
+            rekor_verify(pubkey, sha256.as_bytes(), sig, proxy)
+                .await
+                .context("Repeated lookup in transparency log failed")?
+        }
+    };
 
     info!("Success: package verified");
-    Ok(())
+    Ok(Proof {
+        pubkey: pubkey.clone().into_public_key()?.to_base64(),
+        trusted_comment,
+        log_index: entry.index,
+        log_entry: entry.location,
+        archive: None,
+        signature: String::from_utf8_lossy(sig).into_owned(),
+    })
 }
 
 pub async fn fetch_and_verify(
@@ -160,7 +243,19 @@ pub async fn fetch_and_verify(
     url: &Url,
     pkg: &[u8],
     proxy: &Option<Proxy>,
-) -> Result<()> {
+) -> Result<Proof> {
+    let sha256 = sha256sum(pkg);
+    fetch_and_verify_sha256(client, pubkey, url, &sha256, proxy).await
+}
+
+/// Download and verify the transparency proof of an artifact that isn't available yet
+pub async fn fetch_and_verify_sha256(
+    client: &Client,
+    pubkey: &PublicKeyBox,
+    url: &Url,
+    sha256: &str,
+    proxy: &Option<Proxy>,
+) -> Result<Proof> {
     let url = format!("{}.t", url.as_str());
     info!("Trying to download transparency proof from {:?}", url);
     let url = url.parse::<Url>()?;
@@ -170,5 +265,25 @@ pub async fn fetch_and_verify(
         .await?;
     debug!("Downloaded {} bytes", proof.len());
 
-    verify(pubkey, pkg, &proof, proxy).await
+    verify_sha256(pubkey, sha256, &proof, proxy).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rekor_entry() {
+        let entry = RekorEntry::parse(
+            br#"{"AlreadyExists":true,"Location":"/api/v1/log/entries/3ee0dc3d","Index":1146338}"#,
+        );
+        assert_eq!(entry.index, Some(1146338));
+        assert_eq!(
+            entry.location.as_deref(),
+            Some("/api/v1/log/entries/3ee0dc3d")
+        );
+
+        let entry = RekorEntry::parse(b"Created entry at index 1146338");
+        assert_eq!(entry.index, None);
+    }
 }
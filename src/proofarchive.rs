@@ -0,0 +1,365 @@
+use crate::proof::{self, Proof, PROOF_SIZE_LIMIT};
+use crate::state::{State, StoredArchive};
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::http::{Client, Proxy};
+use pacman_bintrans_common::proofarchive::ProofArchive;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use url::Url;
+
+/// The path, inode, mtime and size of an archive and the key it was verified with
+///
+/// Archives are replaced atomically, so a different file always has a different inode or mtime.
+type Fingerprint = (PathBuf, u64, SystemTime, u64, String);
+
+/// A cached proof archive that passed verification
+struct Verified {
+    stored: StoredArchive,
+    archive: ProofArchive,
+}
+
+/// Proof archives that were verified in this process already
+static VERIFIED: Mutex<BTreeMap<Fingerprint, Arc<Verified>>> = Mutex::new(BTreeMap::new());
+
+/// Download the proof archive of a database snapshot and verify it with the transparency log
+///
+/// `url` is the location of the database proof, without the sha256 suffix.
+pub async fn fetch_and_verify(
+    client: &Client,
+    pubkey: &PublicKeyBox,
+    url: &Url,
+    db_filename: &str,
+    db_sha256: &str,
+    max_size: u64,
+    proxy: &Option<Proxy>,
+) -> Result<StoredArchive> {
+    let url = format!("{}.{}.proofs", url.as_str(), db_sha256);
+    info!("Downloading proof archive from {:?}", url);
+    let archive = client.download_to_mem(url.as_str(), Some(max_size)).await?;
+    debug!("Downloaded {} bytes", archive.len());
+    let sig = client
+        .download_to_mem(format!("{}.t", url).as_str(), Some(PROOF_SIZE_LIMIT))
+        .await?;
+
+    let proof = proof::verify(pubkey, &archive, &sig, proxy).await?;
+    let name = ProofArchive::name(db_filename, db_sha256);
+    if proof.trusted_comment != name {
+        bail!(
+            "Transparency signature was issued for {:?}, but the proof archive was requested as {:?}",
+            proof.trusted_comment,
+            name
+        );
+    }
+
+    let parsed = ProofArchive::parse(&archive)?;
+    if parsed.db != db_filename || parsed.db_sha256 != db_sha256 {
+        bail!(
+            "Proof archive is for {:?} ({}), but was requested for {:?} ({})",
+            parsed.db,
+            parsed.db_sha256,
+            db_filename,
+            db_sha256
+        );
+    }
+    info!(
+        "Verified proof archive with {} signatures",
+        parsed.entries.len()
+    );
+
+    Ok(StoredArchive {
+        archive,
+        sig,
+        log_entry: proof.log_entry,
+    })
+}
+
+/// Verify a cached proof archive, this doesn't query the transparency log again
+pub(crate) fn verify_cached(
+    pubkey: &PublicKeyBox,
+    archive: &[u8],
+    sig: &[u8],
+) -> Result<ProofArchive> {
+    let (_, trusted_comment) = proof::verify_signature(pubkey, archive, sig)?;
+    let archive = ProofArchive::parse(archive)?;
+    let name = ProofArchive::name(&archive.db, &archive.db_sha256);
+    if trusted_comment != name {
+        bail!(
+            "Transparency signature was issued for {:?}, but the proof archive is {:?}",
+            trusted_comment,
+            name
+        );
+    }
+    Ok(archive)
+}
+
+/// Find the transparency signature of a package in the cached proof archives
+///
+/// The signature is verified locally, nothing about the package is sent over the network.
+pub fn lookup(
+    state: &State,
+    pubkey: &PublicKeyBox,
+    filename: &str,
+    pkg: &[u8],
+    sha256: &str,
+) -> Result<Option<Proof>> {
+    if hex::encode(Sha256::digest(pkg)) != sha256 {
+        bail!("Package doesn't match sha256 {}", sha256);
+    }
+    lookup_sha256(state, pubkey, filename, sha256)
+}
+
+fn fingerprint(path: &Path, pubkey: &PublicKeyBox) -> Result<Fingerprint> {
+    let md =
+        fs::metadata(path).with_context(|| anyhow!("Failed to access proof archive {:?}", path))?;
+    Ok((
+        path.to_path_buf(),
+        md.ino(),
+        md.modified()?,
+        md.len(),
+        pubkey.to_string(),
+    ))
+}
+
+/// The cached proof archives that pass verification, each archive is only verified once per process
+fn verified_archives(state: &State, pubkey: &PublicKeyBox) -> Result<Vec<Arc<Verified>>> {
+    let mut verified = VERIFIED
+        .lock()
+        .map_err(|_| anyhow!("Proof archive lock is poisoned"))?;
+
+    let paths = state.proof_archive_paths()?;
+    let mut current = Vec::new();
+    let mut archives = Vec::new();
+    for path in &paths {
+        let fingerprint = fingerprint(path, pubkey)?;
+        if let Some(archive) = verified.get(&fingerprint) {
+            archives.push(archive.clone());
+            current.push(fingerprint);
+            continue;
+        }
+
+        let stored = state.load_proof_archive(path)?;
+        match verify_cached(pubkey, &stored.archive, &stored.sig) {
+            Ok(archive) => {
+                let archive = Arc::new(Verified { stored, archive });
+                verified.insert(fingerprint.clone(), archive.clone());
+                archives.push(archive);
+                current.push(fingerprint);
+            }
+            Err(err) => warn!("Ignoring invalid proof archive {:?}: {:#}", path, err),
+        }
+    }
+
+    // forget previous versions of these archives
+    verified
+        .retain(|fingerprint, _| current.contains(fingerprint) || !paths.contains(&fingerprint.0));
+    Ok(archives)
+}
+
+/// Find the cached proof archive that lists this build of a package
+fn find(
+    state: &State,
+    pubkey: &PublicKeyBox,
+    filename: &str,
+    sha256: &str,
+) -> Result<Option<Arc<Verified>>> {
+    for verified in verified_archives(state, pubkey)? {
+        let archive = &verified.archive;
+        let entry = if let Some(entry) = archive.get(filename) {
+            entry
+        } else {
+            continue;
+        };
+        if entry.sha256 != sha256 {
+            warn!(
+                "Proof archive for {:?} lists sha256 {} for {:?}, but the file has {}",
+                archive.db, entry.sha256, filename, sha256
+            );
+            continue;
+        }
+        return Ok(Some(verified));
+    }
+    Ok(None)
+}
+
+/// Find the transparency signature of a package that wasn't downloaded yet in the cached proof archives
+pub fn lookup_sha256(
+    state: &State,
+    pubkey: &PublicKeyBox,
+    filename: &str,
+    sha256: &str,
+) -> Result<Option<Proof>> {
+    let verified = if let Some(verified) = find(state, pubkey, filename, sha256)? {
+        verified
+    } else {
+        return Ok(None);
+    };
+    let archive = &verified.archive;
+    let signature = &archive.entries[filename].signature;
+
+    let trusted_comment = proof::verify_signature_sha256(pubkey, sha256, signature.as_bytes())
+        .with_context(|| anyhow!("Invalid signature in proof archive for {:?}", archive.db))?;
+    Ok(Some(Proof {
+        pubkey: pubkey.clone().into_public_key()?.to_base64(),
+        trusted_comment,
+        log_index: None,
+        log_entry: None,
+        archive: Some(ProofArchive::name(&archive.db, &archive.db_sha256)),
+        signature: signature.clone(),
+    }))
+}
+
+/// Find the cached proof archive a signature was taken from, to include it in a proof bundle
+pub fn lookup_archive(
+    state: &State,
+    pubkey: &PublicKeyBox,
+    filename: &str,
+    sha256: &str,
+    signature: &str,
+) -> Result<Option<StoredArchive>> {
+    match find(state, pubkey, filename, sha256)? {
+        Some(verified) if verified.archive.entries[filename].signature == signature => {
+            Ok(Some(verified.stored.clone()))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, sign};
+    use minisign::{KeyPair, SecretKey};
+
+    const PKG: &[u8] = b"rebuilderd-0.18.1-1-x86_64.pkg.tar.zst content";
+    const FILENAME: &str = "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst";
+
+    fn setup() -> (tempfile::TempDir, State, KeyPair, ProofArchive) {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(dir.path());
+        let keypair = testutil::keypair();
+        let mut archive = ProofArchive::new("core.db", &"ab".repeat(32));
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        let sig = sign(&keypair.sk, &sha256, FILENAME);
+        archive.insert(FILENAME, &sha256, &sig);
+        (dir, state, keypair, archive)
+    }
+
+    fn store(state: &State, sk: &SecretKey, archive: &ProofArchive) {
+        let bytes = archive.to_vec().unwrap();
+        let name = ProofArchive::name(&archive.db, &archive.db_sha256);
+        let sig = sign(sk, &hex::encode(Sha256::digest(&bytes)), &name);
+        state
+            .store_proof_archive(&archive.db, &bytes, sig.as_bytes(), None)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_lookup() {
+        let (_dir, state, keypair, archive) = setup();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        assert_eq!(
+            lookup(&state, &pubkey, FILENAME, PKG, &sha256).unwrap(),
+            None
+        );
+
+        store(&state, &keypair.sk, &archive);
+        let proof = lookup(&state, &pubkey, FILENAME, PKG, &sha256)
+            .unwrap()
+            .unwrap();
+        assert_eq!(proof.trusted_comment, FILENAME);
+        assert_eq!(
+            proof.archive,
+            Some(ProofArchive::name("core.db", &"ab".repeat(32)))
+        );
+
+        // a different build of the package isn't covered by the archive
+        let other = hex::encode(Sha256::digest(b"something else"));
+        assert_eq!(
+            lookup(&state, &pubkey, FILENAME, b"something else", &other).unwrap(),
+            None
+        );
+        assert_eq!(
+            lookup(
+                &state,
+                &pubkey,
+                "pacman-6.1.0-1-x86_64.pkg.tar.zst",
+                PKG,
+                &sha256
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup_ignores_untrusted_archives() {
+        let (dir, state, keypair, archive) = setup();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+
+        // signed by a different key
+        let other = testutil::keypair();
+        store(&state, &other.sk, &archive);
+        let pubkey = keypair.pk.to_box().unwrap();
+        assert_eq!(
+            lookup(&state, &pubkey, FILENAME, PKG, &sha256).unwrap(),
+            None
+        );
+
+        // modified after it was signed
+        store(&state, &keypair.sk, &archive);
+        let path = dir.path().join("proofs").join("core.db");
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.insert(0, b' ');
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            lookup(&state, &pubkey, FILENAME, PKG, &sha256).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup_verifies_archive_once() {
+        let (dir, state, keypair, archive) = setup();
+        let pubkey = keypair.pk.to_box().unwrap();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        store(&state, &keypair.sk, &archive);
+        assert!(lookup(&state, &pubkey, FILENAME, PKG, &sha256)
+            .unwrap()
+            .is_some());
+
+        // the signature isn't read again while the archive is unchanged
+        fs::write(dir.path().join("proofs").join("core.db.t"), "garbage").unwrap();
+        assert!(lookup(&state, &pubkey, FILENAME, PKG, &sha256)
+            .unwrap()
+            .is_some());
+
+        // a new archive is verified again
+        let other = testutil::keypair();
+        store(&state, &other.sk, &archive);
+        assert_eq!(
+            lookup(&state, &pubkey, FILENAME, PKG, &sha256).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup_rejects_invalid_entry() {
+        let (_dir, state, keypair, mut archive) = setup();
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        // the entry was signed for a different file
+        let other_sha256 = hex::encode(Sha256::digest(b"something else"));
+        let sig = sign(&keypair.sk, &other_sha256, FILENAME);
+        archive.insert(FILENAME, &sha256, &sig);
+        store(&state, &keypair.sk, &archive);
+
+        let pubkey = keypair.pk.to_box().unwrap();
+        assert!(lookup(&state, &pubkey, FILENAME, PKG, &sha256).is_err());
+    }
+}
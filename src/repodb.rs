@@ -0,0 +1,246 @@
+use crate::proof::{self, Proof};
+use crate::state::{DbState, State};
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::http::{Client, Proxy};
+use pacman_bintrans_common::snapshot::DbSnapshot;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Returns true for repository databases, eg. `core.db` or `core.files`
+pub fn is_repo_db(url: &Url) -> bool {
+    let path = url.path();
+    path.ends_with(".db") || path.ends_with(".files")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StaleAction {
+    Warn,
+    Reject,
+}
+
+/// How to deal with databases that are outdated or older than the ones accepted before
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbPolicy {
+    /// Maximum age of a database snapshot in seconds, 0 disables the check
+    pub max_age: u64,
+    pub stale_action: StaleAction,
+    /// Accept snapshots that are older than the newest one accepted before
+    pub allow_downgrade: bool,
+}
+
+impl Default for DbPolicy {
+    fn default() -> DbPolicy {
+        DbPolicy {
+            max_age: DEFAULT_MAX_DB_AGE,
+            stale_action: StaleAction::Warn,
+            allow_downgrade: false,
+        }
+    }
+}
+
+/// One week, the signer is expected to process a repository at least once a day
+pub const DEFAULT_MAX_DB_AGE: u64 = 7 * 24 * 60 * 60;
+
+/// Ensure a database snapshot isn't older than the one we've accepted before
+pub fn check_rollback(
+    previous: Option<&DbState>,
+    current: &DbState,
+    policy: &DbPolicy,
+) -> Result<()> {
+    let previous = if let Some(previous) = previous {
+        previous
+    } else {
+        return Ok(());
+    };
+
+    // unchanged databases are signed again periodically, an older signature is not a rollback
+    if current.sha256 == previous.sha256 {
+        return Ok(());
+    }
+
+    let err = if current.timestamp < previous.timestamp {
+        anyhow!(
+            "Database snapshot is older than the one accepted before: {} < {}",
+            current.timestamp,
+            previous.timestamp
+        )
+    } else if current.timestamp == previous.timestamp && current.sha256 != previous.sha256 {
+        anyhow!(
+            "Database snapshot has the same timestamp as the one accepted before, but a different sha256: {}",
+            current.timestamp
+        )
+    } else {
+        return Ok(());
+    };
+
+    if policy.allow_downgrade {
+        warn!("Allowing database downgrade: {:#}", err);
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Detect mirrors that keep serving an old (but authentic) database
+pub fn check_age(current: &DbState, now: u64, policy: &DbPolicy) -> Result<()> {
+    if policy.max_age == 0 {
+        return Ok(());
+    }
+
+    let age = now.saturating_sub(current.timestamp);
+    if age <= policy.max_age {
+        return Ok(());
+    }
+
+    let err = anyhow!(
+        "Database snapshot is outdated, it was signed {} seconds ago (maximum: {})",
+        age,
+        policy.max_age
+    );
+    match policy.stale_action {
+        StaleAction::Warn => {
+            warn!("{:#}", err);
+            Ok(())
+        }
+        StaleAction::Reject => Err(err),
+    }
+}
+
+/// Verify a downloaded database with the transparency log, returns the proof and the snapshot it attests
+pub async fn verify(
+    client: &Client,
+    pubkey: &PublicKeyBox,
+    url: &Url,
+    filename: &str,
+    db: &[u8],
+    proxy: &Option<Proxy>,
+) -> Result<(Proof, DbState)> {
+    let sha256 = hex::encode(Sha256::digest(db));
+
+    let url = format!("{}.{}", url.as_str(), sha256)
+        .parse::<Url>()
+        .context("Failed to build proof url")?;
+    let proof = proof::fetch_and_verify(client, pubkey, &url, db, proxy).await?;
+    let snapshot =
+        DbSnapshot::parse(&proof.trusted_comment).context("Failed to parse database snapshot")?;
+    debug!("Verified database snapshot: {:?}", snapshot);
+
+    if snapshot.filename != filename {
+        bail!(
+            "Transparency signature was issued for {:?}, but the database was requested as {:?}",
+            snapshot.filename,
+            filename
+        );
+    }
+
+    let state = DbState {
+        timestamp: snapshot.timestamp,
+        sha256,
+    };
+    Ok((proof, state))
+}
+
+/// Check a verified snapshot against the local state and remember it if it's accepted
+pub fn accept(state: &State, filename: &str, current: &DbState, policy: &DbPolicy) -> Result<()> {
+    let previous = state.load_db(filename)?;
+    check_rollback(previous.as_ref(), current, policy)?;
+    // if we've seen a newer signature for the same database, it's known to be current since then
+    let current = match &previous {
+        Some(previous)
+            if previous.sha256 == current.sha256 && previous.timestamp > current.timestamp =>
+        {
+            previous
+        }
+        _ => current,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    check_age(current, now, policy)?;
+
+    if previous.as_ref() != Some(current) {
+        info!(
+            "Storing new database state for {:?}: {:?}",
+            filename, current
+        );
+        state.store_db(filename, current)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(timestamp: u64, sha256: &str) -> DbState {
+        DbState {
+            timestamp,
+            sha256: sha256.repeat(32),
+        }
+    }
+
+    #[test]
+    fn test_is_repo_db() {
+        let url = "https://geo.mirror.pkgbuild.com/core/os/x86_64/core.db"
+            .parse()
+            .unwrap();
+        assert!(is_repo_db(&url));
+        let url = "https://geo.mirror.pkgbuild.com/core/os/x86_64/core.db.sig"
+            .parse()
+            .unwrap();
+        assert!(!is_repo_db(&url));
+        let url = "https://geo.mirror.pkgbuild.com/core/os/x86_64/core.files"
+            .parse()
+            .unwrap();
+        assert!(is_repo_db(&url));
+        let url = "https://geo.mirror.pkgbuild.com/core/os/x86_64/core.files.sig"
+            .parse()
+            .unwrap();
+        assert!(!is_repo_db(&url));
+    }
+
+    #[test]
+    fn test_check_rollback() {
+        let policy = DbPolicy::default();
+        let current = state(1000, "ab");
+        assert!(check_rollback(None, &current, &policy).is_ok());
+        assert!(check_rollback(Some(&state(999, "cd")), &current, &policy).is_ok());
+        assert!(check_rollback(Some(&state(1000, "ab")), &current, &policy).is_ok());
+        assert!(check_rollback(Some(&state(1001, "ab")), &current, &policy).is_ok());
+        assert!(check_rollback(Some(&state(1000, "cd")), &current, &policy).is_err());
+        assert!(check_rollback(Some(&state(1001, "cd")), &current, &policy).is_err());
+    }
+
+    #[test]
+    fn test_check_rollback_allow_downgrade() {
+        let policy = DbPolicy {
+            allow_downgrade: true,
+            ..Default::default()
+        };
+        let current = state(1000, "ab");
+        assert!(check_rollback(Some(&state(1001, "cd")), &current, &policy).is_ok());
+    }
+
+    #[test]
+    fn test_check_age() {
+        let current = state(1000, "ab");
+        let mut policy = DbPolicy {
+            max_age: 100,
+            stale_action: StaleAction::Reject,
+            allow_downgrade: false,
+        };
+        assert!(check_age(&current, 1000, &policy).is_ok());
+        assert!(check_age(&current, 1100, &policy).is_ok());
+        assert!(check_age(&current, 1101, &policy).is_err());
+        // clocks that are behind are not our concern
+        assert!(check_age(&current, 900, &policy).is_ok());
+
+        policy.stale_action = StaleAction::Warn;
+        assert!(check_age(&current, 1101, &policy).is_ok());
+
+        policy.stale_action = StaleAction::Reject;
+        policy.max_age = 0;
+        assert!(check_age(&current, 1_000_000, &policy).is_ok());
+    }
+}
@@ -1,13 +1,24 @@
-use pacman_bintrans_common::decompress;
+use crate::cache::Cache;
+use crate::decision::{BuildinfoBinding, Decision, RebuildStatus, RebuilderResult};
+use pacman_bintrans_common::buildinfo::BuildInfo;
 use pacman_bintrans_common::errors::*;
 use pacman_bintrans_common::http::Client;
+use pacman_bintrans_common::limits::Limits;
+use pacman_bintrans_common::pkginfo::PkgInfo;
+use pacman_bintrans_common::ui::{self, StatusIndicator};
+use pacman_bintrans_common::version::Version;
 use rebuilderd_common::{PkgRelease, Status};
-use std::io::{self, Read, Write};
-use std::path::Path;
-use tar::{Archive, EntryType};
-use tokio::time::{timeout, Duration};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use url::Url;
 
+const ATTESTATION_SIZE_LIMIT: u64 = 1024 * 1024; // 1M
+const RELEASES_SIZE_LIMIT: u64 = 4 * 1024 * 1024; // 4M
+/// Timeout for a single attempt of a rebuilder request, failed attempts are retried
+// TODO: make timeout configurable
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn build_query_url(rebuilder: &Url, name: &str) -> Result<Url> {
     let mut url = rebuilder.clone();
 
@@ -23,20 +34,110 @@ fn build_query_url(rebuilder: &Url, name: &str) -> Result<Url> {
     Ok(url)
 }
 
-/// Returns true for sucecssful rebuilds,  false for everything else
-async fn query_rebuilder(
+fn build_attestation_url(rebuilder: &Url, build_id: i32) -> Result<Url> {
+    let mut url = rebuilder.clone();
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Failed to get path segments for url"))?
+        .pop_if_empty()
+        .extend(&["api", "v0", "builds", &build_id.to_string(), "attestation"]);
+
+    Ok(url)
+}
+
+fn build_log_url(rebuilder: &Url, build_id: i32) -> Result<Url> {
+    let mut url = rebuilder.clone();
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Failed to get path segments for url"))?
+        .pop_if_empty()
+        .extend(&["api", "v0", "builds", &build_id.to_string(), "log"]);
+
+    Ok(url)
+}
+
+/// Collect the sha256 digests of the materials and products of an in-toto link
+fn attestation_digests(json: &[u8]) -> Result<(Vec<String>, Vec<String>)> {
+    let link = serde_json::from_slice::<serde_json::Value>(json)
+        .context("Failed to deserialize attestation")?;
+
+    let digests = |key: &str| {
+        link["signed"][key]
+            .as_object()
+            .map(|artifacts| {
+                artifacts
+                    .values()
+                    .filter_map(|hashes| hashes["sha256"].as_str())
+                    .map(|digest| digest.to_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    };
+
+    Ok((digests("materials"), digests("products")))
+}
+
+/// Check if an attestation was about our package. Since the .BUILDINFO is part
+/// of the package, rebuilding from the package or producing an identical package
+/// both bind the result to our .BUILDINFO.
+///
+/// If the .BUILDINFO isn't known (the package wasn't downloaded yet) only a
+/// different product is considered a mismatch.
+pub(crate) fn buildinfo_binding(
+    attestation: &[u8],
+    buildinfo_sha256: Option<&str>,
+    pkg_sha256: &str,
+) -> Result<BuildinfoBinding> {
+    let (materials, products) = attestation_digests(attestation)?;
+
+    if products.iter().any(|d| d == pkg_sha256)
+        || materials
+            .iter()
+            .any(|d| Some(d.as_str()) == buildinfo_sha256 || d == pkg_sha256)
+    {
+        Ok(BuildinfoBinding::Matches)
+    } else if products.is_empty() && (materials.is_empty() || buildinfo_sha256.is_none()) {
+        Ok(BuildinfoBinding::Unknown)
+    } else {
+        let mut reported = materials;
+        reported.extend(products);
+        Ok(BuildinfoBinding::Mismatch { reported })
+    }
+}
+
+/// The package a rebuilder is asked about
+pub struct RebuildQuery<'a> {
+    pub pkgname: &'a str,
+    pub pkgver: &'a str,
+    /// Only known if the package was downloaded already
+    pub buildinfo_sha256: Option<&'a str>,
+    pub pkg_sha256: &'a str,
+}
+
+async fn fetch_buildinfo_binding(
     client: &Client,
     rebuilder: &Url,
-    name: &str,
-    version: &str,
-) -> Result<bool> {
+    build_id: i32,
+    query: &RebuildQuery<'_>,
+) -> Result<BuildinfoBinding> {
+    let url = build_attestation_url(rebuilder, build_id)?;
+    info!("Fetching attestation: {:?}", url.as_str());
+
+    let attestation = client
+        .download_to_mem(url.as_str(), Some(ATTESTATION_SIZE_LIMIT))
+        .await?;
+    buildinfo_binding(&attestation, query.buildinfo_sha256, query.pkg_sha256)
+}
+
+/// List the releases of a package the rebuilder knows about
+async fn fetch_releases(client: &Client, rebuilder: &Url, name: &str) -> Result<Vec<PkgRelease>> {
     let url = build_query_url(rebuilder, name)?;
 
     info!("Querying rebuilder: {:?}", url.as_str());
 
-    // TODO: make timeout configurable
-    let json = client.download_to_mem(url.as_str(), None);
-    let json = timeout(Duration::from_secs(5), json).await??;
+    let json = client
+        .download_to_mem(url.as_str(), Some(RELEASES_SIZE_LIMIT))
+        .await?;
     let pkgs = serde_json::from_slice::<Vec<PkgRelease>>(&json)
         .context("Failed to deserialize response")?;
 
@@ -46,131 +147,209 @@ async fn query_rebuilder(
         pkgs
     );
 
-    for pkg in pkgs {
-        if pkg.name != name {
-            continue;
-        }
-
-        if pkg.version != version {
-            continue;
-        }
+    Ok(pkgs)
+}
 
-        debug!(
-            "Found matching package (status: {:?}): {:?}",
-            pkg.status, pkg
-        );
+/// A build of a package by a rebuilder, with links to its log and attestation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Build {
+    pub rebuilder: Url,
+    pub status: Status,
+    pub build_id: Option<i32>,
+    pub log: Option<Url>,
+    pub attestation: Option<Url>,
+}
 
-        if pkg.status != Status::Good {
+/// The builds of this exact package version, regardless of their status
+fn list_builds(
+    rebuilder: &Url,
+    query: &RebuildQuery<'_>,
+    pkgs: Vec<PkgRelease>,
+) -> Result<Vec<Build>> {
+    let pkgver = Version::parse(query.pkgver);
+    let mut builds = Vec::new();
+    for pkg in pkgs {
+        // a build without pkgrel isn't this package
+        if pkg.name != query.pkgname || Version::parse(&pkg.version) != pkgver {
             continue;
         }
 
-        return Ok(true);
+        let (log, attestation) = match pkg.build_id {
+            Some(build_id) => (
+                Some(build_log_url(rebuilder, build_id)?),
+                if pkg.has_attestation {
+                    Some(build_attestation_url(rebuilder, build_id)?)
+                } else {
+                    None
+                },
+            ),
+            None => (None, None),
+        };
+        builds.push(Build {
+            rebuilder: rebuilder.clone(),
+            status: pkg.status,
+            build_id: pkg.build_id,
+            log,
+            attestation,
+        });
     }
-
-    Ok(false)
-}
-
-#[derive(Debug, PartialEq)]
-struct PkgInfo {
-    name: String,
-    version: String,
+    Ok(builds)
 }
 
-fn extract_dot_pkginfo_from_archive(bytes: &[u8]) -> Result<String> {
-    let compression = decompress::detect_compression(bytes);
-    let tar =
-        decompress::stream(compression, bytes).context("Failed to open compressed package")?;
+/// Query a rebuilder for the package, returns its verdict and the builds of this version
+async fn query_rebuilder(
+    client: &Client,
+    rebuilder: &Url,
+    query: &RebuildQuery<'_>,
+) -> Result<(RebuildStatus, Vec<Build>)> {
+    let pkgs = fetch_releases(client, rebuilder, query.pkgname).await?;
+    let builds = list_builds(rebuilder, query, pkgs)?;
 
-    let mut archive = Archive::new(tar);
+    for build in &builds {
+        debug!(
+            "Found matching build (status: {:?}): {:?}",
+            build.status, build
+        );
 
-    for entry in archive.entries()? {
-        let mut entry = entry?;
-        if entry.header().entry_type() != EntryType::Regular {
+        if build.status != Status::Good {
             continue;
         }
 
-        match entry.header().path() {
-            Ok(p) if p == Path::new(".PKGINFO") => (),
-            _ => continue,
-        }
-
-        let mut file = String::new();
-        entry
-            .read_to_string(&mut file)
-            .context("Failed to read .PKGINFO from archive")?;
+        let binding = match build.build_id {
+            Some(build_id) if build.attestation.is_some() => {
+                match fetch_buildinfo_binding(client, rebuilder, build_id, query).await {
+                    Ok(binding) => binding,
+                    Err(err) => {
+                        warn!(
+                            "Failed to fetch attestation from rebuilder {:?}: {:#}",
+                            rebuilder.as_str(),
+                            err
+                        );
+                        BuildinfoBinding::Unknown
+                    }
+                }
+            }
+            _ => BuildinfoBinding::Unknown,
+        };
 
-        return Ok(file);
+        return Ok((RebuildStatus::Reproduced(binding), builds));
     }
 
-    bail!("Package does not contain .PKGINFO")
+    Ok((RebuildStatus::NotReproduced, builds))
 }
 
-fn parse_pkg_info(pkg: &[u8]) -> Result<PkgInfo> {
-    let mut pkgname = None;
-    let mut pkgver = None;
+/// Query a rebuilder about a package, or use its cached verdict
+///
+/// Without a client only cached negative verdicts are available. The builds of
+/// the package are only listed if the rebuilder was queried.
+pub async fn rebuild_status(
+    client: Option<&Client>,
+    cache: Option<&Cache>,
+    rebuilder: &Url,
+    query: &RebuildQuery<'_>,
+) -> Result<(RebuildStatus, Vec<Build>)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cached = if let Some(cache) = cache {
+        cache.load_verdict(query.pkg_sha256, rebuilder, now)?
+    } else {
+        None
+    };
 
-    info!("Extracting .PKGINFO from package...");
-    let content = extract_dot_pkginfo_from_archive(pkg)?;
-    for line in content.split('\n') {
-        if let Some(value) = line.strip_prefix("pkgname = ") {
-            pkgname = Some(value.to_string());
-        }
-        if let Some(value) = line.strip_prefix("pkgver = ") {
-            pkgver = Some(value.to_string());
+    if let Some(status) = cached {
+        info!("Using cached verdict of rebuilder {:?}", rebuilder.as_str());
+        Ok((status, Vec::new()))
+    } else if let Some(client) = client {
+        let (status, builds) = query_rebuilder(client, rebuilder, query).await?;
+        if let Some(cache) = cache {
+            if let Err(err) = cache.store_verdict(query.pkg_sha256, rebuilder, &status, now) {
+                warn!("Failed to cache rebuilder verdict: {:#}", err);
+            }
         }
+        Ok((status, builds))
+    } else {
+        bail!("No cached verdict and rebuilders can't be queried offline");
     }
-
-    let pkginfo = PkgInfo {
-        name: pkgname.context("Missing pkgname field in .PKGINFO")?,
-        version: pkgver.context("Missing pkgver field in .PKGINFO")?,
-    };
-    debug!("Parsed pkginfo: {:?}", pkginfo);
-    Ok(pkginfo)
 }
 
+/// Query every rebuilder, or use its cached verdict
+///
+/// The builds the rebuilders listed for this package are added to `builds`, if given.
+#[allow(clippy::too_many_arguments)]
 pub async fn check_rebuilds(
-    client: &Client,
+    client: Option<&Client>,
+    cache: Option<&Cache>,
     pkg: &[u8],
+    pkginfo: &PkgInfo,
     rebuilders: &[Url],
-    log: &Option<&str>,
-) -> Result<usize> {
-    if log.is_none() {
-        println!("\x1b[1m[\x1b[34m%\x1b[0;1m]\x1b[0m Inspecting .PKGINFO in package...");
-    }
-
-    let pkginfo = parse_pkg_info(pkg).context("Failed to parse infos from package")?;
-    if log.is_none() {
-        print!("\x1b[1A\x1b[2K");
-    }
+    limits: &Limits,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+    mut builds: Option<&mut Vec<Build>>,
+) -> Result<()> {
+    ui.progress("Inspecting .BUILDINFO in package...");
+
+    let buildinfo =
+        BuildInfo::extract_from_pkg(pkg, limits).context("Failed to read .BUILDINFO")?;
+    let buildinfo_sha256 = hex::encode(Sha256::digest(&buildinfo));
+    let buildinfo = BuildInfo::from_bytes(&buildinfo).context("Failed to parse .BUILDINFO")?;
+    buildinfo.ensure_matches(pkginfo)?;
+    ui.clear();
+
+    decision.buildinfo_sha256 = Some(buildinfo_sha256.clone());
+    decision.pkgbuild_sha256sum = Some(buildinfo.pkgbuild_sha256sum);
+    let pkg_sha256 = decision
+        .sha256
+        .clone()
+        .ok_or_else(|| anyhow!("Package sha256 is unknown"))?;
 
-    let mut confirms = 0;
     for rebuilder in rebuilders {
-        if log.is_none() {
-            println!(
-                "\x1b[2K\r\x1b[1m[\x1b[34m%\x1b[0;1m]\x1b[0m Checking rebuilder {:?}...",
-                rebuilder.as_str()
-            );
-        }
+        ui.progress(&format!("Checking rebuilder {:?}...", rebuilder.as_str()));
+
+        let started = Instant::now();
+        let query = RebuildQuery {
+            pkgname: &pkginfo.pkgname,
+            pkgver: &pkginfo.pkgver,
+            buildinfo_sha256: Some(&buildinfo_sha256),
+            pkg_sha256: &pkg_sha256,
+        };
+        let result =
+            rebuild_status(client, cache, rebuilder, &query)
+                .await
+                .map(|(status, listed)| {
+                    if let Some(builds) = builds.as_deref_mut() {
+                        builds.extend(listed);
+                    }
+                    status
+                });
+
+        let status = match result {
+            Ok(RebuildStatus::Reproduced(BuildinfoBinding::Mismatch { reported })) => {
+                let msg = format!(
+                    "Rebuilder attested a different build: {:?}",
+                    rebuilder.as_str()
+                );
+
+                warn!("{} (reported digests: {:?})", msg, reported);
+
+                ui.finish(ui::Status::Failure, &msg, Some("MISMATCH"));
 
-        match query_rebuilder(client, rebuilder, &pkginfo.name, &pkginfo.version).await {
-            Ok(true) => {
+                RebuildStatus::Reproduced(BuildinfoBinding::Mismatch { reported })
+            }
+            Ok(RebuildStatus::Reproduced(binding)) => {
                 let msg = format!(
                     "Package was reproduced by rebuilder: {:?}",
                     rebuilder.as_str()
                 );
 
-                info!("{}", msg);
+                info!("{} (buildinfo: {:?})", msg, binding);
 
-                if log.is_none() {
-                    println!("\x1b[1A\x1b[2K\r\x1b[1m[\x1b[32m+\x1b[0;1m]\x1b[0m {:95} \x1b[32mREPRODUCIBLE\x1b[0m", msg);
-                }
+                ui.finish(ui::Status::Success, &msg, Some("REPRODUCIBLE"));
 
-                confirms += 1;
+                RebuildStatus::Reproduced(binding)
             }
-            Ok(false) => {
-                if log.is_none() {
-                    print!("\x1b[1A\x1b[2K");
-                }
+            Ok(status) => {
+                ui.clear();
+                status
             }
             Err(err) => {
                 warn!(
@@ -178,15 +357,23 @@ pub async fn check_rebuilds(
                     rebuilder.as_str(),
                     err
                 );
-                if log.is_none() {
-                    println!("\x1b[1A\x1b[2K\r\x1b[1m[\x1b[31m-\x1b[0;1m]\x1b[0m Failed to query rebuilder {:?}: {:#}", rebuilder.as_str(), err);
-                }
+                ui.failure(&format!(
+                    "Failed to query rebuilder {:?}: {:#}",
+                    rebuilder.as_str(),
+                    err
+                ));
+                RebuildStatus::Error(format!("{:#}", err))
             }
-        }
+        };
+
+        decision.rebuilders.push(RebuilderResult {
+            rebuilder: rebuilder.clone(),
+            status,
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
     }
-    io::stdout().flush().ok();
 
-    Ok(confirms)
+    Ok(())
 }
 
 #[cfg(test)]
@@ -234,15 +421,98 @@ mod tests {
     }
 
     #[test]
-    fn test_parse_pkg_get_name_version() {
-        let bytes = include_bytes!("../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
-        let pkginfo = parse_pkg_info(bytes).unwrap();
+    fn test_build_attestation_url() {
+        let rebuilder = "https://wolfpit.net/rebuild/".parse().unwrap();
+        let url = build_attestation_url(&rebuilder, 1337).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://wolfpit.net/rebuild/api/v0/builds/1337/attestation"
+        );
+    }
+
+    #[test]
+    fn test_build_log_url() {
+        let rebuilder = "https://wolfpit.net/rebuild".parse().unwrap();
+        let url = build_log_url(&rebuilder, 1337).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://wolfpit.net/rebuild/api/v0/builds/1337/log"
+        );
+    }
+
+    const BUILDINFO_SHA256: &str =
+        "0c2e8b7a2b1b9d36e6b4e8cbbe4b3f7a2f1d2cd9bb6e6e0ab7d79c7c6e0f9d11";
+    const PKG_SHA256: &str = "8f5c0d3b4a0e7c3e9d6f1a2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f";
+
+    fn link(materials: &str, products: &str) -> Vec<u8> {
+        format!(
+            r#"{{"signatures":[],"signed":{{"_type":"link","name":"rebuild","materials":{{{}}},"products":{{{}}}}}}}"#,
+            materials, products
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_attestation_matches_buildinfo() {
+        let attestation = link(
+            &format!(r#"".BUILDINFO":{{"sha256":"{}"}}"#, BUILDINFO_SHA256),
+            "",
+        );
+        let binding = buildinfo_binding(&attestation, Some(BUILDINFO_SHA256), PKG_SHA256).unwrap();
+        assert_eq!(binding, BuildinfoBinding::Matches);
+    }
+
+    #[test]
+    fn test_attestation_matches_product() {
+        let attestation = link(
+            "",
+            &format!(
+                r#""rebuilderd-0.18.1-1-x86_64.pkg.tar.zst":{{"sha256":"{}"}}"#,
+                PKG_SHA256.to_uppercase()
+            ),
+        );
+        let binding = buildinfo_binding(&attestation, Some(BUILDINFO_SHA256), PKG_SHA256).unwrap();
+        assert_eq!(binding, BuildinfoBinding::Matches);
+    }
+
+    #[test]
+    fn test_attestation_without_digests() {
+        let attestation = link("", "");
+        let binding = buildinfo_binding(&attestation, Some(BUILDINFO_SHA256), PKG_SHA256).unwrap();
+        assert_eq!(binding, BuildinfoBinding::Unknown);
+    }
+
+    #[test]
+    fn test_attestation_mismatch() {
+        let other = "ab".repeat(32);
+        let attestation = link(&format!(r#"".BUILDINFO":{{"sha256":"{}"}}"#, other), "");
+        let binding = buildinfo_binding(&attestation, Some(BUILDINFO_SHA256), PKG_SHA256).unwrap();
         assert_eq!(
-            pkginfo,
-            PkgInfo {
-                name: "rebuilderd".to_string(),
-                version: "0.18.1-1".to_string(),
+            binding,
+            BuildinfoBinding::Mismatch {
+                reported: vec![other]
             }
         );
     }
+
+    #[test]
+    fn test_attestation_without_buildinfo() {
+        // the package wasn't downloaded yet, only a product can bind the rebuild to it
+        let attestation = link(
+            &format!(r#"".BUILDINFO":{{"sha256":"{}"}}"#, BUILDINFO_SHA256),
+            "",
+        );
+        let binding = buildinfo_binding(&attestation, None, PKG_SHA256).unwrap();
+        assert_eq!(binding, BuildinfoBinding::Unknown);
+
+        let attestation = link(
+            "",
+            &format!(
+                r#""rebuilderd-0.18.1-1-x86_64.pkg.tar.zst":{{"sha256":"{}"}}"#,
+                PKG_SHA256
+            ),
+        );
+        let binding = buildinfo_binding(&attestation, None, PKG_SHA256).unwrap();
+        assert_eq!(binding, BuildinfoBinding::Matches);
+    }
 }
@@ -0,0 +1,304 @@
+use pacman_bintrans_common::errors::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The newest database snapshot that was accepted for a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbState {
+    pub timestamp: u64,
+    pub sha256: String,
+}
+
+impl DbState {
+    fn parse(content: &str) -> Result<DbState> {
+        let (timestamp, sha256) = content
+            .trim_end()
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Expected `<timestamp> <sha256>`: {:?}", content))?;
+        let timestamp = timestamp
+            .parse()
+            .with_context(|| anyhow!("Invalid timestamp: {:?}", timestamp))?;
+        Ok(DbState {
+            timestamp,
+            sha256: sha256.to_string(),
+        })
+    }
+}
+
+/// A cached proof archive, it needs to be verified again when it's loaded
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoredArchive {
+    pub archive: Vec<u8>,
+    pub sig: Vec<u8>,
+    /// The location of the transparency log entry of the archive, if rekor-cli reported it
+    pub log_entry: Option<String>,
+}
+
+/// Local state that is kept between invocations to detect rollbacks
+pub struct State {
+    dir: PathBuf,
+}
+
+impl State {
+    pub fn new(dir: &Path) -> State {
+        State {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn db_path(&self, filename: &str) -> Result<PathBuf> {
+        if filename.is_empty() || filename.contains('/') || filename.starts_with('.') {
+            bail!("Invalid database filename: {:?}", filename);
+        }
+        Ok(self.dir.join("dbs").join(filename))
+    }
+
+    fn pkg_path(&self, pkgname: &str, arch: &str) -> Result<PathBuf> {
+        for name in &[pkgname, arch] {
+            if name.is_empty() || name.contains('/') || name.starts_with('.') {
+                bail!("Invalid package name or architecture: {:?}", name);
+            }
+        }
+        Ok(self.dir.join("pkgs").join(arch).join(pkgname))
+    }
+
+    pub fn load_db(&self, filename: &str) -> Result<Option<DbState>> {
+        let path = self.db_path(filename)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read database state from {:?}", path))?;
+        let state = DbState::parse(&content)
+            .with_context(|| anyhow!("Failed to parse database state from {:?}", path))?;
+        Ok(Some(state))
+    }
+
+    pub fn store_db(&self, filename: &str, state: &DbState) -> Result<()> {
+        let path = self.db_path(filename)?;
+        write_atomic(
+            &path,
+            format!("{} {}\n", state.timestamp, state.sha256).as_bytes(),
+        )
+        .with_context(|| anyhow!("Failed to write database state to {:?}", path))
+    }
+
+    fn proof_archive_path(&self, db_filename: &str) -> Result<PathBuf> {
+        if db_filename.is_empty() || db_filename.contains('/') || db_filename.starts_with('.') {
+            bail!("Invalid database filename: {:?}", db_filename);
+        }
+        Ok(self.dir.join("proofs").join(db_filename))
+    }
+
+    /// Keep the proof archive of the newest database snapshot, with its signature in `<path>.t`
+    /// and the location of its log entry in `<path>.entry`
+    pub fn store_proof_archive(
+        &self,
+        db_filename: &str,
+        archive: &[u8],
+        sig: &[u8],
+        log_entry: Option<&str>,
+    ) -> Result<()> {
+        let path = self.proof_archive_path(db_filename)?;
+        let sig_path = with_suffix(&path, ".t");
+        write_atomic(&sig_path, sig).with_context(|| {
+            anyhow!("Failed to write proof archive signature to {:?}", sig_path)
+        })?;
+        let entry_path = with_suffix(&path, ".entry");
+        if let Some(log_entry) = log_entry {
+            write_atomic(&entry_path, log_entry.as_bytes()).with_context(|| {
+                anyhow!(
+                    "Failed to write proof archive log entry to {:?}",
+                    entry_path
+                )
+            })?;
+        } else if entry_path.exists() {
+            fs::remove_file(&entry_path).with_context(|| {
+                anyhow!("Failed to remove proof archive log entry {:?}", entry_path)
+            })?;
+        }
+        write_atomic(&path, archive)
+            .with_context(|| anyhow!("Failed to write proof archive to {:?}", path))
+    }
+
+    /// The paths of all cached proof archives, without their signatures
+    pub fn proof_archive_paths(&self) -> Result<Vec<PathBuf>> {
+        let dir = self.dir.join("proofs");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&dir)
+            .with_context(|| anyhow!("Failed to list proof archives in {:?}", dir))?
+        {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("t") | Some("entry") | Some("tmp") => continue,
+                _ => paths.push(path),
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Read a cached proof archive with its signature, it needs to be verified again
+    pub fn load_proof_archive(&self, path: &Path) -> Result<StoredArchive> {
+        let sig_path = with_suffix(path, ".t");
+        let entry_path = with_suffix(path, ".entry");
+        let archive = fs::read(path)
+            .with_context(|| anyhow!("Failed to read proof archive from {:?}", path))?;
+        let sig = fs::read(&sig_path).with_context(|| {
+            anyhow!("Failed to read proof archive signature from {:?}", sig_path)
+        })?;
+        let log_entry = if entry_path.exists() {
+            let log_entry = fs::read_to_string(&entry_path).with_context(|| {
+                anyhow!(
+                    "Failed to read proof archive log entry from {:?}",
+                    entry_path
+                )
+            })?;
+            Some(log_entry)
+        } else {
+            None
+        };
+        Ok(StoredArchive {
+            archive,
+            sig,
+            log_entry,
+        })
+    }
+
+    /// All cached proof archives with their signatures, they need to be verified again
+    pub fn load_proof_archives(&self) -> Result<Vec<StoredArchive>> {
+        self.proof_archive_paths()?
+            .iter()
+            .map(|path| self.load_proof_archive(path))
+            .collect()
+    }
+
+    /// The newest version of a package that was accepted before
+    pub fn load_pkg_version(&self, pkgname: &str, arch: &str) -> Result<Option<String>> {
+        let path = self.pkg_path(pkgname, arch)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| anyhow!("Failed to read package state from {:?}", path))?;
+        Ok(Some(content.trim_end().to_string()))
+    }
+
+    pub fn store_pkg_version(&self, pkgname: &str, arch: &str, version: &str) -> Result<()> {
+        let path = self.pkg_path(pkgname, arch)?;
+        write_atomic(&path, format!("{}\n", version).as_bytes())
+            .with_context(|| anyhow!("Failed to write package state to {:?}", path))
+    }
+}
+
+/// Append a suffix to the file name, e.g. `.t` for the signature next to a proof archive
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Write to a temporary file first so an interrupted write can't corrupt the state
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Path has no parent directory"))?;
+    fs::create_dir_all(parent)?;
+    let tmp = with_suffix(path, ".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_db_state_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(dir.path());
+        assert_eq!(state.load_db("core.db").unwrap(), None);
+
+        let db = DbState {
+            timestamp: 1639741192,
+            sha256: "ab".repeat(32),
+        };
+        state.store_db("core.db", &db).unwrap();
+        assert_eq!(state.load_db("core.db").unwrap(), Some(db));
+        assert_eq!(state.load_db("extra.db").unwrap(), None);
+    }
+
+    #[test]
+    fn test_pkg_version_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(dir.path());
+        assert_eq!(
+            state.load_pkg_version("rebuilderd", "x86_64").unwrap(),
+            None
+        );
+
+        state
+            .store_pkg_version("rebuilderd", "x86_64", "0.18.1-1")
+            .unwrap();
+        assert_eq!(
+            state.load_pkg_version("rebuilderd", "x86_64").unwrap(),
+            Some("0.18.1-1".to_string())
+        );
+        assert_eq!(
+            state.load_pkg_version("rebuilderd", "aarch64").unwrap(),
+            None
+        );
+        assert!(state.load_pkg_version("..", "x86_64").is_err());
+    }
+
+    #[test]
+    fn test_proof_archive_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(dir.path());
+        assert!(state.load_proof_archives().unwrap().is_empty());
+
+        state
+            .store_proof_archive("core.db", b"archive v1", b"sig v1", Some("/entry/v1"))
+            .unwrap();
+        state
+            .store_proof_archive("core.db", b"archive v2", b"sig v2", None)
+            .unwrap();
+        state
+            .store_proof_archive("extra.db", b"archive", b"sig", Some("/entry"))
+            .unwrap();
+
+        let mut archives = state.load_proof_archives().unwrap();
+        archives.sort();
+        assert_eq!(
+            archives,
+            vec![
+                StoredArchive {
+                    archive: b"archive".to_vec(),
+                    sig: b"sig".to_vec(),
+                    log_entry: Some("/entry".to_string()),
+                },
+                // the log entry of the previous snapshot doesn't stick around
+                StoredArchive {
+                    archive: b"archive v2".to_vec(),
+                    sig: b"sig v2".to_vec(),
+                    log_entry: None,
+                },
+            ]
+        );
+        assert!(state
+            .store_proof_archive("../core.db", b"", b"", None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_db_state_invalid_filename() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = State::new(dir.path());
+        assert!(state.load_db("../core.db").is_err());
+        assert!(state.load_db(".core.db").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::decision::SyncDbEntry;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::limits::Limits;
+use pacman_bintrans_common::syncdb::{self, SyncDb};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The `*.db` files that pacman synced, by repository name and sorted by name
+fn db_paths(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| anyhow!("Failed to list {:?}", dir))? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("db") {
+            continue;
+        }
+        if let Some(repo) = path.file_stem().and_then(|stem| stem.to_str()) {
+            paths.push((repo.to_string(), path));
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn read_db(path: &Path, limits: &Limits) -> Result<Vec<u8>> {
+    let md = fs::metadata(path)?;
+    limits.check_download_size(md.len())?;
+    fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))
+}
+
+/// Load all `*.db` files that pacman synced, by repository name and sorted by name
+///
+/// Databases that fail to parse are skipped with a warning.
+pub fn load(dir: &Path, limits: &Limits) -> Result<Vec<(String, SyncDb)>> {
+    let mut dbs = Vec::new();
+    for (repo, path) in db_paths(dir)? {
+        debug!("Loading sync database {:?}", path);
+        let bytes = read_db(&path, limits)?;
+        let db = match syncdb::parse(&bytes, limits) {
+            Ok(db) => db,
+            Err(err) => {
+                warn!("Failed to parse sync database {:?}: {:#}", path, err);
+                continue;
+            }
+        };
+
+        for err in &db.errors {
+            debug!(
+                "Skipping invalid entry {:?} in sync database {:?}: {:#}",
+                err.entry, path, err.error
+            );
+        }
+
+        dbs.push((repo, db));
+    }
+
+    Ok(dbs)
+}
+
+/// Search all `*.db` files that pacman synced for the given package filename
+///
+/// Databases that fail to parse are skipped with a warning.
+pub fn lookup(dir: &Path, filename: &str, limits: &Limits) -> Result<Vec<SyncDbEntry>> {
+    let mut found = Vec::new();
+    for (repo, path) in db_paths(dir)? {
+        debug!("Searching sync database {:?}", path);
+        let bytes = read_db(&path, limits)?;
+        match syncdb::find_sha256(&bytes, filename, limits) {
+            Ok(Some(sha256)) => found.push(SyncDbEntry {
+                repo,
+                sha256: sha256.to_lowercase(),
+            }),
+            Ok(None) => (),
+            Err(err) => warn!("Failed to parse sync database {:?}: {:#}", path, err),
+        }
+    }
+    Ok(found)
+}
+
+/// Includes are followed this deep, to stop include loops
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Read the repositories from pacman.conf in the order pacman uses them
+///
+/// `Include` directives are followed, glob patterns aren't supported.
+pub fn configured_repos(path: &Path) -> Result<Vec<String>> {
+    let mut repos = Vec::new();
+    read_repos(path, &mut repos, 0)?;
+    Ok(repos)
+}
+
+fn read_repos(path: &Path, repos: &mut Vec<String>, depth: usize) -> Result<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        bail!("Too many nested includes in {:?}", path);
+    }
+
+    let content = fs::read_to_string(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            if section != "options" && !repos.iter().any(|r| r == section) {
+                repos.push(section.to_string());
+            }
+        } else if let Some((key, value)) = line.split_once('=') {
+            if key.trim() != "Include" {
+                continue;
+            }
+            let include = value.trim();
+            if include.contains(['*', '?', '[']) {
+                bail!(
+                    "Glob patterns in {:?} are not supported: {:?}",
+                    path,
+                    include
+                );
+            }
+            read_repos(Path::new(include), repos, depth + 1)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_db(path: &Path, filename: &str, sha256: &str) {
+        let desc = format!(
+            "%FILENAME%\n{}\n\n%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%SHA256SUM%\n{}\n\n%ARCH%\nany\n\n",
+            filename, sha256
+        );
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(desc.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "foo-1.0-1/desc", desc.as_bytes())
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        w.write_all(&tar).unwrap();
+        fs::write(path, w.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_sync_dbs() {
+        let dir = tempfile::tempdir().unwrap();
+        let sha256 = "ab".repeat(32);
+        write_db(
+            &dir.path().join("core.db"),
+            "foo-1.0-1-any.pkg.tar.zst",
+            &sha256,
+        );
+        write_db(
+            &dir.path().join("extra.db"),
+            "bar-1.0-1-any.pkg.tar.zst",
+            &sha256,
+        );
+        fs::write(dir.path().join("garbage.db"), b"this is not a database").unwrap();
+        fs::write(dir.path().join("core.files"), b"ignored").unwrap();
+
+        let found = lookup(dir.path(), "foo-1.0-1-any.pkg.tar.zst", &Limits::default()).unwrap();
+        assert_eq!(
+            found,
+            vec![SyncDbEntry {
+                repo: "core".to_string(),
+                sha256,
+            }]
+        );
+
+        let found = lookup(dir.path(), "baz-1.0-1-any.pkg.tar.zst", &Limits::default()).unwrap();
+        assert_eq!(found, vec![]);
+    }
+
+    #[test]
+    fn test_configured_repos() {
+        let dir = tempfile::tempdir().unwrap();
+        let mirrorlist = dir.path().join("mirrorlist");
+        fs::write(
+            &mirrorlist,
+            "## Worldwide\nServer = https://geo.mirror.pkgbuild.com/$repo/os/$arch\n",
+        )
+        .unwrap();
+        let custom = dir.path().join("custom.conf");
+        fs::write(&custom, "[custom]\nServer = file:///srv/repo\n").unwrap();
+
+        let conf = dir.path().join("pacman.conf");
+        fs::write(
+            &conf,
+            format!(
+                "[options]\nHoldPkg = pacman glibc\n\n#[core-testing]\n#Include = /etc/pacman.d/mirrorlist\n\n[extra]\nInclude = {}\n\n[core]\nInclude = {}\nInclude = {}\n",
+                mirrorlist.display(),
+                mirrorlist.display(),
+                custom.display(),
+            ),
+        )
+        .unwrap();
+
+        let repos = configured_repos(&conf).unwrap();
+        assert_eq!(repos, vec!["extra", "core", "custom"]);
+    }
+
+    #[test]
+    fn test_configured_repos_rejects_globs() {
+        let dir = tempfile::tempdir().unwrap();
+        let conf = dir.path().join("pacman.conf");
+        fs::write(&conf, "[options]\nInclude = /etc/pacman.d/*.conf\n").unwrap();
+        assert!(configured_repos(&conf).is_err());
+    }
+}
@@ -0,0 +1,16 @@
+//! Fixtures that are shared by the tests of multiple modules
+
+use minisign::{KeyPair, SecretKey};
+use std::io::Cursor;
+
+/// A new keypair to sign test data with
+pub fn keypair() -> KeyPair {
+    KeyPair::generate_unencrypted_keypair().unwrap()
+}
+
+/// Sign a hex encoded sha256 like pacman-bintrans-sign does, the comment is trusted
+pub fn sign(sk: &SecretKey, sha256: &str, comment: &str) -> String {
+    minisign::sign(None, sk, Cursor::new(sha256), Some(comment), None)
+        .unwrap()
+        .to_string()
+}
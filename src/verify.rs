@@ -0,0 +1,452 @@
+use crate::args::{Args, OutputFormat, VerifyArgs};
+use crate::bundle::{self, Bundle};
+use crate::decision::{Decision, Kind, Verdict};
+use crate::proof::{self, Proof};
+use crate::routing::Clients;
+use crate::xfer;
+use minisign::PublicKeyBox;
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::pkginfo::PkgInfo;
+use pacman_bintrans_common::ui::StatusIndicator;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Exit code if a file couldn't be read or the verification couldn't be set up
+pub const EXIT_ERROR: i32 = 1;
+/// Exit code if at least one package was rejected
+pub const EXIT_REJECTED: i32 = 3;
+
+/// The transparency proof to use for a file, if one is available on disk
+fn proof_path(file: &Path, explicit: Option<&Path>) -> Option<PathBuf> {
+    if let Some(explicit) = explicit {
+        return Some(explicit.to_path_buf());
+    }
+    let path = bundle::path(file);
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Verify the transparency signature of a package, from a file or from --transparency-url
+///
+/// A proof bundle is verified without network access if --checkpoint is set.
+async fn find_proof(
+    args: &Args,
+    clients: &Clients,
+    pubkey: &PublicKeyBox,
+    file_name: &str,
+    pkg: &[u8],
+    path: Option<&Path>,
+) -> Result<Proof> {
+    if let Some(path) = path {
+        info!("Using transparency proof from {:?}", path);
+        let md = fs::metadata(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+        args.limits().check_download_size(md.len())?;
+        let bytes = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+
+        if bytes.starts_with(b"{") {
+            let bundle = Bundle::parse(&bytes)
+                .with_context(|| anyhow!("Invalid proof bundle {:?}", path))?;
+            if let Some(checkpoint) = &args.checkpoint {
+                return bundle.verify(pubkey, pkg, checkpoint);
+            }
+            proof::verify(pubkey, pkg, bundle.signature.as_bytes(), &clients.log_proxy).await
+        } else if args.offline {
+            bail!(
+                "{:?} is not a proof bundle and can't be verified offline",
+                path
+            );
+        } else {
+            proof::verify(pubkey, pkg, &bytes, &clients.log_proxy).await
+        }
+    } else if let (Some(transparency_url), false) = (&args.transparency_url, args.offline) {
+        let url = xfer::proof_url(transparency_url, &args.transparency_url, file_name)?;
+        proof::fetch_and_verify(&clients.proofs, pubkey, &url, pkg, &clients.log_proxy).await
+    } else {
+        bail!("No transparency proof found, expected `<file>.t`, --proof or --transparency-url");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn check(
+    args: &Args,
+    clients: &Clients,
+    pubkey: Option<&PublicKeyBox>,
+    file_name: &str,
+    pkg: &[u8],
+    proof: Option<&Path>,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+) -> Result<()> {
+    let sha256 = hex::encode(Sha256::digest(pkg));
+    decision.size = Some(pkg.len());
+    decision.sha256 = Some(sha256.clone());
+
+    xfer::check_sync_db(args, file_name, &sha256, decision)?;
+
+    let pkginfo =
+        PkgInfo::from_pkg(pkg, &args.limits()).context("Failed to parse infos from package")?;
+    decision.pkgname = Some(pkginfo.pkgname.clone());
+    decision.pkgver = Some(pkginfo.pkgver.clone());
+
+    if let Some(pubkey) = pubkey {
+        ui.progress("Checking transparency log...");
+        let result = find_proof(args, clients, pubkey, file_name, pkg, proof).await;
+        xfer::accept_proof(decision, result, file_name)?;
+        ui.success("Package is present in transparency log");
+    }
+
+    xfer::check_mtree(args, pkg, ui, decision)?;
+
+    if !args.rebuilders.is_empty() || args.required_rebuild_confirms > 0 {
+        let cache = args.cache();
+        xfer::check_rebuild_policy(
+            args,
+            clients,
+            cache.as_ref(),
+            pkg,
+            &pkginfo,
+            ui,
+            decision,
+            None,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Verify a single package file, fails if the file can't be read
+pub async fn verify_file(
+    args: &Args,
+    clients: &Clients,
+    pubkey: Option<&PublicKeyBox>,
+    path: &Path,
+    proof: Option<&Path>,
+    ui: &mut dyn StatusIndicator,
+) -> Result<Decision> {
+    let md = fs::metadata(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+    args.limits().check_download_size(md.len())?;
+    let pkg = fs::read(path).with_context(|| anyhow!("Failed to read {:?}", path))?;
+
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Couldn't detect filename for path: {:?}", path))?;
+    let url = Url::from_file_path(env::current_dir()?.join(path))
+        .map_err(|_| anyhow!("Failed to convert path to url: {:?}", path))?;
+
+    let mut decision = Decision::new(&url, Kind::Package);
+    let proof = proof_path(path, proof);
+    let result = check(
+        args,
+        clients,
+        pubkey,
+        file_name,
+        &pkg,
+        proof.as_deref(),
+        ui,
+        &mut decision,
+    )
+    .await;
+    if let Err(err) = &result {
+        ui.failure(&format!("Rejected {:?}: {:#}", path, err));
+    }
+    decision.conclude(&result);
+    Ok(decision)
+}
+
+/// Verify all files and print the results, returns the exit code
+pub async fn run(
+    args: &Args,
+    verify: &VerifyArgs,
+    clients: &Clients,
+    ui: &mut dyn StatusIndicator,
+) -> Result<i32> {
+    if verify.proof.is_some() && verify.files.len() > 1 {
+        bail!("--proof can only be used to verify a single file");
+    }
+
+    let pubkey = xfer::load_pubkey(args)?;
+    if pubkey.is_none() && args.rebuilders.is_empty() && args.required_rebuild_confirms == 0 {
+        bail!("Nothing to verify, use --pubkey or --rebuilder");
+    }
+
+    let mut code = 0;
+    for path in &verify.files {
+        let decision = match verify_file(
+            args,
+            clients,
+            pubkey.as_ref(),
+            path,
+            verify.proof.as_deref(),
+            ui,
+        )
+        .await
+        {
+            Ok(decision) => decision,
+            Err(err) => {
+                ui.failure(&format!("{:#}", err));
+                if args.output_format == OutputFormat::Text {
+                    println!("ERROR {}: {:#}", path.display(), err);
+                }
+                if code == 0 {
+                    code = EXIT_ERROR;
+                }
+                continue;
+            }
+        };
+
+        if decision.verdict != Verdict::Accepted {
+            code = EXIT_REJECTED;
+        }
+        match args.output_format {
+            OutputFormat::Text => match &decision.error {
+                Some(err) => println!("FAIL  {}: {}", path.display(), err),
+                None => println!("ok    {}", path.display()),
+            },
+            OutputFormat::Json => println!("{}", serde_json::to_string(&decision)?),
+        }
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Command;
+    use crate::bundle::{ConsistencyProof, InclusionProof};
+    use crate::config::Config;
+    use crate::routing::Routes;
+    use base64::prelude::*;
+    use clap::Parser;
+    use minisign::KeyPair;
+    use pacman_bintrans_common::http::RetryPolicy;
+    use pacman_bintrans_common::merkle;
+    use pacman_bintrans_common::ui;
+    use std::io::Cursor;
+
+    const PKG: &[u8] = include_bytes!("../test_data/rebuilderd-0.18.1-1-x86_64.pkg.tar.zst");
+    const FILENAME: &str = "rebuilderd-0.18.1-1-x86_64.pkg.tar.zst";
+
+    /// A bundle for a log that only contains the signature of the package
+    fn bundle(keypair: &KeyPair) -> (Bundle, String) {
+        let sha256 = hex::encode(Sha256::digest(PKG));
+        let sig = minisign::sign(
+            None,
+            &keypair.sk,
+            Cursor::new(&sha256),
+            Some(FILENAME),
+            None,
+        )
+        .unwrap()
+        .to_string();
+
+        let body = serde_json::json!({
+            "kind": "rekord",
+            "spec": {
+                "data": {
+                    "hash": {
+                        "algorithm": "sha256",
+                        "value": hex::encode(Sha256::digest(sha256.as_bytes())),
+                    },
+                },
+                "signature": {
+                    "content": BASE64_STANDARD.encode(&sig),
+                },
+            },
+        })
+        .to_string();
+        let root = hex::encode(merkle::leaf_hash(body.as_bytes()));
+
+        let bundle = Bundle {
+            version: 1,
+            signature: sig,
+            log_index: Some(0),
+            log_entry: None,
+            archive: None,
+            body: Some(BASE64_STANDARD.encode(&body)),
+            inclusion_proof: Some(InclusionProof {
+                log_index: 0,
+                tree_size: 1,
+                root_hash: root.clone(),
+                hashes: Vec::new(),
+            }),
+            consistency_proof: Some(ConsistencyProof {
+                tree_size: 1,
+                root_hash: root.clone(),
+                hashes: Vec::new(),
+            }),
+            archived: None,
+        };
+        (bundle, format!("1:{}", root))
+    }
+
+    fn parse(args: &[&str]) -> (Args, VerifyArgs) {
+        let mut args = Args::try_parse_from(args).unwrap();
+        match args.command.take() {
+            Some(Command::Verify(verify)) => (args, verify),
+            _ => panic!("Expected verify subcommand"),
+        }
+    }
+
+    fn clients() -> Clients {
+        Clients::new(
+            Routes::default(),
+            &Config::default(),
+            RetryPolicy::default(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::generate_unencrypted_keypair().unwrap();
+        let (bundle, checkpoint) = bundle(&keypair);
+        let pkg = dir.path().join(FILENAME);
+        fs::write(&pkg, PKG).unwrap();
+        bundle.store(&bundle::path(&pkg)).unwrap();
+        let renamed = dir.path().join("rebuilderd-0.18.1-2-x86_64.pkg.tar.zst");
+        fs::write(&renamed, PKG).unwrap();
+
+        let pubkey = keypair.pk.to_base64();
+        let state_dir = dir.path().to_str().unwrap();
+        let sync_db_dir = dir.path().join("sync");
+        let verify_args = |files: &[&Path]| {
+            let mut args = vec![
+                "pacman-bintrans",
+                "--pubkey",
+                &pubkey,
+                "--offline",
+                "--checkpoint",
+                &checkpoint,
+                "--state-dir",
+                state_dir,
+                "--sync-db-dir",
+                sync_db_dir.to_str().unwrap(),
+                "verify",
+            ];
+            args.extend(files.iter().map(|path| path.to_str().unwrap()));
+            parse(&args)
+        };
+        let clients = clients();
+        let mut ui = ui::new(ui::Mode::None, false);
+
+        let (args, verify) = verify_args(&[&pkg]);
+        let decision = verify_file(
+            &args,
+            &clients,
+            xfer::load_pubkey(&args).unwrap().as_ref(),
+            &pkg,
+            None,
+            ui.as_mut(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(decision.verdict, Verdict::Accepted);
+        assert_eq!(decision.pkgname.as_deref(), Some("rebuilderd"));
+        assert_eq!(decision.proof.unwrap().trusted_comment, FILENAME);
+        assert_eq!(run(&args, &verify, &clients, ui.as_mut()).await.unwrap(), 0);
+
+        // there's no bundle next to it and the log can't be queried offline
+        let (args, verify) = verify_args(&[&pkg, &renamed]);
+        assert_eq!(
+            run(&args, &verify, &clients, ui.as_mut()).await.unwrap(),
+            EXIT_REJECTED
+        );
+
+        let missing = dir.path().join("missing.pkg.tar.zst");
+        let (args, verify) = verify_args(&[&pkg, &missing]);
+        assert_eq!(
+            run(&args, &verify, &clients, ui.as_mut()).await.unwrap(),
+            EXIT_ERROR
+        );
+
+        // the signature was issued for a different filename
+        bundle.store(&bundle::path(&renamed)).unwrap();
+        let (args, _) = verify_args(&[&renamed]);
+        let decision = verify_file(
+            &args,
+            &clients,
+            xfer::load_pubkey(&args).unwrap().as_ref(),
+            &renamed,
+            None,
+            ui.as_mut(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(decision.verdict, Verdict::Rejected);
+        assert!(decision
+            .policy
+            .iter()
+            .any(|check| check.name == "proof_filename" && !check.passed));
+    }
+
+    #[tokio::test]
+    async fn test_verify_explicit_proof() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::generate_unencrypted_keypair().unwrap();
+        let (bundle, checkpoint) = bundle(&keypair);
+        let pkg = dir.path().join(FILENAME);
+        fs::write(&pkg, PKG).unwrap();
+        let proof = dir.path().join("proof.json");
+        bundle.store(&proof).unwrap();
+
+        let pubkey = keypair.pk.to_base64();
+        let (args, verify) = parse(&[
+            "pacman-bintrans",
+            "--pubkey",
+            &pubkey,
+            "--offline",
+            "--checkpoint",
+            &checkpoint,
+            "--skip-sync-db-check",
+            "verify",
+            "--proof",
+            proof.to_str().unwrap(),
+            pkg.to_str().unwrap(),
+        ]);
+        let clients = clients();
+        let mut ui = ui::new(ui::Mode::None, false);
+        assert_eq!(run(&args, &verify, &clients, ui.as_mut()).await.unwrap(), 0);
+
+        // signed by somebody else
+        let other = KeyPair::generate_unencrypted_keypair().unwrap().pk;
+        let (args, verify) = parse(&[
+            "pacman-bintrans",
+            "--pubkey",
+            &other.to_base64(),
+            "--offline",
+            "--checkpoint",
+            &checkpoint,
+            "--skip-sync-db-check",
+            "verify",
+            "--proof",
+            proof.to_str().unwrap(),
+            pkg.to_str().unwrap(),
+        ]);
+        assert_eq!(
+            run(&args, &verify, &clients, ui.as_mut()).await.unwrap(),
+            EXIT_REJECTED
+        );
+
+        let (args, verify) = parse(&[
+            "pacman-bintrans",
+            "--pubkey",
+            &pubkey,
+            "verify",
+            "--proof",
+            proof.to_str().unwrap(),
+            pkg.to_str().unwrap(),
+            pkg.to_str().unwrap(),
+        ]);
+        assert!(run(&args, &verify, &clients, ui.as_mut()).await.is_err());
+    }
+}
@@ -0,0 +1,609 @@
+use crate::args::Args;
+use crate::bundle::{self, Bundle};
+use crate::cache::Cache;
+use crate::decision::{BuildinfoBinding, Decision, Kind, RebuildStatus};
+use crate::downgrade;
+use crate::proof::{self, Proof};
+use crate::proofarchive;
+use crate::repodb;
+use crate::reproducible::{self, Build};
+use crate::routing::Clients;
+use crate::state::State;
+use crate::syncdb;
+use minisign::{PublicKey, PublicKeyBox};
+use pacman_bintrans_common::errors::*;
+use pacman_bintrans_common::mtree;
+use pacman_bintrans_common::pkginfo::PkgInfo;
+use pacman_bintrans_common::ui::StatusIndicator;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use url::Url;
+
+fn needs_transparency_proof(url: &str) -> bool {
+    let parts = url.split('.').collect::<Vec<_>>();
+
+    let mut iter = parts.iter().rev();
+
+    // strip .tar.zstd
+    if let Some(x) = iter.next() {
+        // if the extension is .tar there is possibly no compression
+        if *x != "tar" && iter.next() != Some(&"tar") {
+            return false;
+        }
+    }
+
+    iter.next() == Some(&"pkg")
+}
+
+pub fn filename_from_url(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?;
+    let filename = segments.next_back()?;
+    Some(filename.to_string())
+}
+
+/// The url the transparency proof of a file is located at, without the `.t` suffix
+pub fn proof_url(url: &Url, transparency_url: &Option<Url>, file_name: &str) -> Result<Url> {
+    if let Some(transparency_url) = transparency_url {
+        let mut url = transparency_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Failed to get path segments for url"))?
+            .pop_if_empty()
+            .push(file_name);
+        Ok(url)
+    } else {
+        Ok(url.clone())
+    }
+}
+
+/// Cross-check the sha256 of a package with the sync databases, if they exist
+pub fn check_sync_db(
+    args: &Args,
+    file_name: &str,
+    sha256: &str,
+    decision: &mut Decision,
+) -> Result<()> {
+    let limits = args.limits();
+
+    if args.skip_sync_db_check {
+        debug!("Skipping sync database check");
+    } else if args.sync_db_dir.exists() {
+        let entries = syncdb::lookup(&args.sync_db_dir, file_name, &limits)
+            .context("Failed to search sync databases")?;
+
+        if entries.is_empty() {
+            warn!("Package {:?} is not listed in any sync database", file_name);
+        }
+
+        for entry in &entries {
+            if entry.sha256 != sha256 {
+                let msg = format!(
+                    "Sync database {:?} lists sha256 {} for {:?}, but the file has {}",
+                    entry.repo, entry.sha256, file_name, sha256
+                );
+                warn!("{}", msg);
+                decision.inconsistencies.push(msg);
+            }
+        }
+        decision.syncdb = Some(entries);
+
+        let passed = decision.inconsistencies.is_empty();
+        decision.check("sync_db", passed, None);
+        if !passed {
+            bail!("Package doesn't match the sync database");
+        }
+    } else {
+        debug!(
+            "Sync database directory {:?} doesn't exist, skipping check",
+            args.sync_db_dir
+        );
+    }
+    Ok(())
+}
+
+/// Record the transparency signature of a package, it's expected to be issued for its filename
+pub fn accept_proof(decision: &mut Decision, result: Result<Proof>, file_name: &str) -> Result<()> {
+    let proof = decision
+        .evaluate("transparency_log", result)
+        .context("Failed to check transparency log")?;
+
+    let passed = proof.trusted_comment == file_name;
+    decision.check("proof_filename", passed, None);
+    if !passed {
+        let msg = format!(
+            "Transparency signature was issued for {:?}, but the file was requested as {:?}",
+            proof.trusted_comment, file_name
+        );
+        decision.proof = Some(proof);
+        decision.inconsistencies.push(msg.clone());
+        bail!("{}", msg);
+    }
+    decision.proof = Some(proof);
+    Ok(())
+}
+
+/// Verify the package contents against the embedded .MTREE, if enabled
+pub fn check_mtree(
+    args: &Args,
+    pkg: &[u8],
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+) -> Result<()> {
+    let limits = args.limits();
+
+    if args.verify_mtree {
+        ui.progress("Verifying package contents against .MTREE...");
+
+        let mismatches =
+            mtree::verify_pkg(pkg, &limits).context("Failed to verify package contents")?;
+        for mismatch in &mismatches {
+            warn!("Package content doesn't match .MTREE: {}", mismatch);
+        }
+        let num_mismatches = mismatches.len();
+        decision.mtree_mismatches = Some(mismatches);
+
+        decision.check("mtree", num_mismatches == 0, None);
+        if num_mismatches > 0 {
+            bail!(
+                "Package contents don't match .MTREE: found {} mismatches",
+                num_mismatches
+            );
+        }
+
+        ui.success("Package contents match .MTREE");
+    }
+    Ok(())
+}
+
+/// Query the rebuilders and enforce the required number of rebuild confirms
+///
+/// The builds the rebuilders listed for this package are added to `builds`, if given.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_rebuild_policy(
+    args: &Args,
+    clients: &Clients,
+    cache: Option<&Cache>,
+    pkg: &[u8],
+    pkginfo: &PkgInfo,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+    builds: Option<&mut Vec<Build>>,
+) -> Result<()> {
+    let limits = args.limits();
+
+    reproducible::check_rebuilds(
+        (!args.offline).then_some(&clients.rebuilders),
+        cache,
+        pkg,
+        pkginfo,
+        &args.rebuilders,
+        &limits,
+        ui,
+        decision,
+        builds,
+    )
+    .await
+    .context("Failed to check rebuilds")?;
+
+    enforce_rebuild_policy(args, decision)
+}
+
+/// Reject packages with a mismatching rebuild and enforce the required number of rebuild confirms
+pub fn enforce_rebuild_policy(args: &Args, decision: &mut Decision) -> Result<()> {
+    let mismatch = decision
+        .rebuilders
+        .iter()
+        .find(|r| {
+            matches!(
+                r.status,
+                RebuildStatus::Reproduced(BuildinfoBinding::Mismatch { .. })
+            )
+        })
+        .map(|r| r.rebuilder.clone());
+    decision.check(
+        "buildinfo_binding",
+        mismatch.is_none(),
+        mismatch.as_ref().map(|r| r.to_string()),
+    );
+    if let Some(rebuilder) = mismatch {
+        bail!(
+            "Rebuilder {:?} attested a build that doesn't match this package",
+            rebuilder.as_str()
+        );
+    }
+
+    let rebuild_confirms = decision.rebuild_confirms(args.require_buildinfo_match);
+    let passed = rebuild_confirms >= args.required_rebuild_confirms;
+    decision.check(
+        "rebuild_confirms",
+        passed,
+        Some(format!(
+            "got {}, required {}",
+            rebuild_confirms, args.required_rebuild_confirms
+        )),
+    );
+    if !passed {
+        bail!(
+            "Not enough rebuild confirms: got {}, expected {}",
+            rebuild_confirms,
+            args.required_rebuild_confirms
+        );
+    }
+    Ok(())
+}
+
+/// Download a package and write it to the output path if it's accepted
+async fn verify_pkg(
+    args: &Args,
+    url: &Url,
+    output: &Path,
+    clients: &Clients,
+    pubkey: Option<&PublicKeyBox>,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+) -> Result<()> {
+    let limits = args.limits();
+
+    info!(
+        "Transparency proof is required for {:?}, downloading into memory",
+        url.as_str()
+    );
+    let pkg = if output.exists() {
+        info!("Target path already exists, reading from disk instead of downloading");
+        let md = fs::metadata(output).context("Failed to read existing file")?;
+        limits.check_download_size(md.len())?;
+        fs::read(output).context("Failed to read existing file")?
+    } else if args.offline {
+        bail!(
+            "Package {:?} doesn't exist and can't be downloaded in offline mode",
+            output
+        );
+    } else {
+        let pkg = clients
+            .packages
+            .download_to_mem(url.as_str(), Some(limits.max_download_size))
+            .await?;
+        debug!("Downloaded {} bytes", pkg.len());
+        pkg
+    };
+
+    ui.success(&format!("Downloaded {:?}", url.as_str()));
+
+    let sha256 = hex::encode(Sha256::digest(&pkg));
+    decision.size = Some(pkg.len());
+    decision.sha256 = Some(sha256.clone());
+    let file_name = filename_from_url(url)
+        .ok_or_else(|| anyhow!("Couldn't detect filename for url: {:?}", url.as_str()))?;
+
+    check_sync_db(args, &file_name, &sha256, decision)?;
+
+    ui.progress("Inspecting .PKGINFO in package...");
+
+    let pkginfo = PkgInfo::from_pkg(&pkg, &limits).context("Failed to parse infos from package")?;
+    decision.pkgname = Some(pkginfo.pkgname.clone());
+    decision.pkgver = Some(pkginfo.pkgver.clone());
+
+    let state = State::new(&args.state_dir);
+    let cache = args.cache();
+    let previous_version = state.load_pkg_version(&pkginfo.pkgname, &pkginfo.arch)?;
+    let result = downgrade::check(
+        previous_version.as_deref(),
+        &pkginfo.pkgver,
+        args.allow_pkg_downgrade,
+    );
+    decision
+        .evaluate("downgrade", result)
+        .context("Refusing to accept package")?;
+
+    ui.clear();
+
+    let proof_url = proof_url(url, &args.transparency_url, &file_name)?;
+
+    if let Some(pubkey) = pubkey {
+        ui.progress("Checking transparency log...");
+
+        let result = if let (true, Some(checkpoint)) = (args.offline, &args.checkpoint) {
+            let path = bundle::path(output);
+            info!("Verifying transparency proof from {:?}", path);
+            Bundle::load(&path).and_then(|bundle| bundle.verify(pubkey, &pkg, checkpoint))
+        } else {
+            let cached = match &cache {
+                Some(cache) => cache.load_proof(pubkey, &pkg, &sha256),
+                None => Ok(None),
+            };
+            let archived = match cached {
+                Ok(Some(proof)) => {
+                    info!("Using cached transparency signature");
+                    Ok(Some(proof))
+                }
+                Ok(None) if args.skip_proof_archive => Ok(None),
+                Ok(None) => {
+                    let proof = proofarchive::lookup(&state, pubkey, &file_name, &pkg, &sha256);
+                    if let Ok(Some(_)) = &proof {
+                        info!("Found transparency signature in proof archive");
+                    }
+                    proof
+                }
+                Err(err) => Err(err),
+            };
+            match archived {
+                Ok(Some(proof)) => Ok(proof),
+                Ok(None) if !args.skip_proof_archive && !args.allow_individual_proofs => {
+                    Err(anyhow!(
+                        "Package {:?} is not in any proof archive, refusing to request its proof individually",
+                        file_name
+                    ))
+                }
+                Ok(None) => {
+                    if !args.skip_proof_archive {
+                        warn!(
+                            "Package {:?} is not in any proof archive, requesting its proof individually",
+                            file_name
+                        );
+                    }
+                    proof::fetch_and_verify(
+                        &clients.proofs,
+                        pubkey,
+                        &proof_url,
+                        &pkg,
+                        &clients.log_proxy,
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            }
+        };
+        if let (Some(cache), Ok(proof)) = (&cache, &result) {
+            if let Err(err) = cache.store_proof(&sha256, proof) {
+                warn!("Failed to cache transparency signature: {:#}", err);
+            }
+        }
+        accept_proof(decision, result, &file_name)?;
+
+        ui.success("Package is present in transparency log");
+    }
+
+    check_mtree(args, &pkg, ui, decision)?;
+
+    if !args.rebuilders.is_empty() || args.required_rebuild_confirms > 0 {
+        check_rebuild_policy(
+            args,
+            clients,
+            cache.as_ref(),
+            &pkg,
+            &pkginfo,
+            ui,
+            decision,
+            None,
+        )
+        .await?;
+    } else if pubkey.is_none() {
+        warn!("There is no configured authentication method, pacman-bintrans is used as a simple downloader!");
+    }
+
+    if downgrade::is_newer(previous_version.as_deref(), &pkginfo.pkgver) {
+        info!(
+            "Storing new version for {:?} ({}): {:?}",
+            pkginfo.pkgname, pkginfo.arch, pkginfo.pkgver
+        );
+        state.store_pkg_version(&pkginfo.pkgname, &pkginfo.arch, &pkginfo.pkgver)?;
+    }
+
+    info!("Writing pkg to {:?}", output);
+    fs::write(output, &pkg).context("Failed to write database file after verification")?;
+    debug!("Wrote {} bytes", pkg.len());
+
+    if let (Some(proof), false, false) = (&decision.proof, args.offline, args.skip_proof_bundle) {
+        let path = bundle::path(output);
+        let mut bundle = Bundle::new(proof);
+        let archive = match (proof.archive.is_some(), pubkey) {
+            (true, Some(pubkey)) => {
+                proofarchive::lookup_archive(&state, pubkey, &file_name, &sha256, &proof.signature)
+                    .unwrap_or_else(|err| {
+                        warn!("Failed to load proof archive: {:#}", err);
+                        None
+                    })
+            }
+            _ => None,
+        };
+        let result = if let Some(archive) = &archive {
+            bundle
+                .fetch_archive_evidence(&clients.log, archive, args.checkpoint.as_ref())
+                .await
+        } else if proof.log_entry.is_some() {
+            bundle
+                .fetch_evidence(&clients.log, &sha256, args.checkpoint.as_ref())
+                .await
+        } else {
+            Err(anyhow!("Location of the transparency log entry is unknown"))
+        };
+        if let Err(err) = result {
+            warn!(
+                "Failed to download transparency log entry, the proof bundle can't be verified offline: {:#}",
+                err
+            );
+        }
+        info!("Writing proof bundle to {:?}", path);
+        if let Err(err) = bundle.store(&path) {
+            warn!("Failed to save proof bundle: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Download a repository database and write it to the output path if it's accepted
+async fn verify_db(
+    args: &Args,
+    url: &Url,
+    output: &Path,
+    clients: &Clients,
+    pubkey: &PublicKeyBox,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Decision,
+) -> Result<()> {
+    let limits = args.limits();
+
+    info!(
+        "Transparency proof is required for {:?}, downloading into memory",
+        url.as_str()
+    );
+    let db = clients
+        .packages
+        .download_to_mem(url.as_str(), Some(limits.max_download_size))
+        .await?;
+    debug!("Downloaded {} bytes", db.len());
+
+    ui.success(&format!("Downloaded {:?}", url.as_str()));
+
+    decision.size = Some(db.len());
+    decision.sha256 = Some(hex::encode(Sha256::digest(&db)));
+    let file_name = filename_from_url(url)
+        .ok_or_else(|| anyhow!("Couldn't detect filename for url: {:?}", url.as_str()))?;
+    let proof_url = proof_url(url, &args.transparency_url, &file_name)?;
+    let state = State::new(&args.state_dir);
+
+    ui.progress("Checking transparency log...");
+
+    let result = repodb::verify(
+        &clients.proofs,
+        pubkey,
+        &proof_url,
+        &file_name,
+        &db,
+        &clients.log_proxy,
+    )
+    .await;
+    let (proof, snapshot) = decision
+        .evaluate("transparency_log", result)
+        .context("Failed to verify database")?;
+    decision.proof = Some(proof);
+    let result = repodb::accept(&state, &file_name, &snapshot, &args.db_policy());
+    decision
+        .evaluate("db_snapshot", result)
+        .context("Refusing to accept database")?;
+
+    ui.success(&format!(
+        "Database snapshot from {} is present in transparency log",
+        snapshot.timestamp
+    ));
+
+    // proof archives are only published for the .db, the .files lists the same packages
+    if !args.skip_proof_archive && file_name.ends_with(".db") {
+        ui.progress("Downloading proof archive...");
+        let result = proofarchive::fetch_and_verify(
+            &clients.proofs,
+            pubkey,
+            &proof_url,
+            &file_name,
+            &snapshot.sha256,
+            limits.max_download_size,
+            &clients.log_proxy,
+        )
+        .await
+        .and_then(|stored| {
+            state.store_proof_archive(
+                &file_name,
+                &stored.archive,
+                &stored.sig,
+                stored.log_entry.as_deref(),
+            )
+        });
+        match result {
+            Ok(()) => ui.success("Proof archive is present in transparency log"),
+            Err(err) => {
+                ui.clear();
+                warn!("Failed to update proof archive: {:#}", err);
+            }
+        }
+    }
+
+    info!("Writing database to {:?}", output);
+    fs::write(output, &db).context("Failed to write database file after verification")?;
+    debug!("Wrote {} bytes", db.len());
+
+    Ok(())
+}
+
+pub fn load_pubkey(args: &Args) -> Result<Option<PublicKeyBox>> {
+    if let Some(pubkey) = &args.pubkey {
+        let pubkey = PublicKey::from_base64(pubkey)
+            .context("Failed to load transparency public key")?
+            .to_box()?;
+        Ok(Some(pubkey))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Conclude the decision and keep it for the caller
+fn conclude(
+    decision: &mut Option<Decision>,
+    mut concluded: Decision,
+    result: Result<()>,
+) -> Result<()> {
+    concluded.conclude(&result);
+    debug!("Decision: {:?}", concluded);
+    *decision = Some(concluded);
+    result
+}
+
+/// Handle a single XferCommand invocation, the file is written to the output path if it's accepted
+///
+/// The decision about packages and databases is stored in `decision`, also if they were rejected.
+pub async fn run(
+    args: &Args,
+    clients: &Clients,
+    ui: &mut dyn StatusIndicator,
+    decision: &mut Option<Decision>,
+) -> Result<()> {
+    let (url, output) = args.transfer()?;
+    let pubkey = load_pubkey(args)?;
+    let limits = args.limits();
+
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow!("Failed to convert file:// url to path"))?;
+        info!("Copying from file {:?} to {:?}", path, output);
+        let n = fs::copy(path, output).context("Failed to copy from file://")?;
+        debug!("Copied {} bytes", n);
+        return Ok(());
+    }
+
+    if args.offline && !needs_transparency_proof(url.as_str()) {
+        bail!(
+            "Only packages can be verified in offline mode: {:?}",
+            url.as_str()
+        );
+    }
+
+    if needs_transparency_proof(url.as_str()) {
+        let mut concluded = Decision::new(url, Kind::Package);
+        let result = verify_pkg(
+            args,
+            url,
+            output,
+            clients,
+            pubkey.as_ref(),
+            ui,
+            &mut concluded,
+        )
+        .await;
+        conclude(decision, concluded, result)
+    } else if let (true, Some(pubkey)) = (repodb::is_repo_db(url), &pubkey) {
+        let mut concluded = Decision::new(url, Kind::Database);
+        let result = verify_db(args, url, output, clients, pubkey, ui, &mut concluded).await;
+        conclude(decision, concluded, result)
+    } else {
+        info!("Downloading {:?} to {:?}", url.as_str(), output);
+        let n = clients
+            .packages
+            .download_to_file(url.as_str(), output, Some(limits.max_download_size))
+            .await?;
+        debug!("Downloaded {} bytes", n);
+
+        ui.success(&format!("Downloaded {:?}", url.as_str()));
+        Ok(())
+    }
+}